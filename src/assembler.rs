@@ -0,0 +1,485 @@
+use std::{collections::HashMap, fs, path::Path};
+
+use crate::{
+    constant::SIGNATURE,
+    cpu::encode_register,
+    logger::Logger,
+    opcode::{InstructionInfo, OperandKind, Operation},
+    verbose_println, ExecutionError,
+};
+
+/*
+
+syntax
+
+    # comment (`;` also starts a comment)
+    label:  ldi r1, $1          registers are named as in the debugger (`r3b1`, `pc`)
+            jifz r3b1, $x00aa   `$` decimal, `$x` hex, `$1.5` float, `$!label` label address
+            pushi $!str
+    str:    .string "hi\n"      raw bytes, escapes: \n \t \r \0 \\ \" \xNN
+            .bytes $1, $x02     one byte per operand
+            .dword $!str        little endian u64 per operand
+            .equ strl, $3       named constant usable as `$!strl`, not emitted as a label
+            .break              adds the current address to the breakpoint block
+
+execution starts at `_start` if defined, otherwise at 0
+
+*/
+
+enum Constant {
+    Value(u64),
+    Label(String),
+}
+
+/// a label reference to patch once every label is known
+struct Fixup {
+    offset: usize,
+    label: String,
+    line: usize,
+}
+
+#[derive(Default)]
+pub struct Assembler {
+    image: Vec<u8>,
+    labels: Vec<(u64, String)>,
+    addresses: HashMap<String, u64>,
+    constants: HashMap<String, u64>,
+    break_points: Vec<u64>,
+    fixups: Vec<Fixup>,
+    /// 1 based source line being assembled
+    line: usize,
+    /// path of the source file written to the line table
+    path: String,
+    /// address and source line of every instruction
+    lines: Vec<(u64, u64)>,
+}
+
+/// assembles `source` and writes the NISVC-EF binary to `output`, next to `source` by default
+pub fn run(source: &str, output: Option<String>, log: &Logger) -> Result<(), ExecutionError> {
+    let text = fs::read_to_string(source)
+        .map_err(|e| ExecutionError::new(format!("failed to read {source}: {e}")))?;
+    let output = output.unwrap_or(
+        Path::new(source)
+            .with_extension("nef")
+            .to_string_lossy()
+            .to_string(),
+    );
+    // absolute so debuggers can match it against the files an editor has open
+    let path = fs::canonicalize(source)
+        .map(|p| p.to_string_lossy().to_string())
+        .unwrap_or(source.to_string());
+    let binary =
+        Assembler::assemble_named(&text, &path).map_err(|e| e.prepend(format!("{source}:")))?;
+    fs::write(&output, binary)
+        .map_err(|e| ExecutionError::new(format!("failed to write {output}: {e}")))?;
+    verbose_println!(log, "assembled {source} -> {output}");
+    Ok(())
+}
+
+impl Assembler {
+    /// assembles source text into a NISVC-EF binary
+    pub fn assemble(source: &str) -> Result<Vec<u8>, ExecutionError> {
+        Self::assemble_named(source, "")
+    }
+
+    /// like [`Assembler::assemble`], `path` names the source file in the line table
+    pub fn assemble_named(source: &str, path: &str) -> Result<Vec<u8>, ExecutionError> {
+        let mut assembler = Self {
+            path: path.to_string(),
+            ..Self::default()
+        };
+        for (n, line) in source.lines().enumerate() {
+            assembler.line = n + 1;
+            assembler
+                .assemble_line(line)
+                .map_err(|e| e.prepend(format!("{}: ", n + 1)))?;
+        }
+        assembler.link()?;
+        Ok(assembler.serialize())
+    }
+
+    fn assemble_line(&mut self, line: &str) -> Result<(), ExecutionError> {
+        let mut line = strip_comment(line).trim();
+        if let Some((label, rest)) = line.split_once(':') {
+            if is_identifier(label.trim()) {
+                self.define_label(label.trim())?;
+                line = rest.trim();
+            }
+        }
+        if line.is_empty() {
+            return Ok(());
+        }
+        let (mnemonic, operands) = match line.split_once(char::is_whitespace) {
+            Some((mnemonic, operands)) => (mnemonic, operands.trim()),
+            None => (line, ""),
+        };
+        let mnemonic = mnemonic.to_lowercase();
+        if mnemonic == ".string" {
+            let bytes = parse_string(operands)?;
+            self.image.extend(bytes);
+            return Ok(());
+        }
+        let operands: Vec<&str> = if operands.is_empty() {
+            Vec::new()
+        } else {
+            operands.split(',').map(|o| o.trim()).collect()
+        };
+        match mnemonic.as_str() {
+            ".bytes" => {
+                for operand in &operands {
+                    match parse_constant(operand)? {
+                        Constant::Value(v) => self.image.push(u8::try_from(v).map_err(|_| {
+                            ExecutionError::new(format!("`{operand}` does not fit in a byte"))
+                        })?),
+                        Constant::Label(_) => {
+                            return Err(ExecutionError::new(format!(
+                                "label reference `{operand}` does not fit in a byte"
+                            )))
+                        }
+                    }
+                }
+            }
+            ".dword" => {
+                for operand in &operands {
+                    match parse_constant(operand)? {
+                        Constant::Value(v) => self.emit(&v.to_le_bytes(), Vec::new()),
+                        Constant::Label(label) => self.emit(&0u64.to_le_bytes(), vec![(0, label)]),
+                    }
+                }
+            }
+            ".equ" => {
+                expect_operands(&mnemonic, &operands, 2)?;
+                let name = operands[0];
+                if !is_identifier(name) {
+                    return Err(ExecutionError::new(format!(
+                        "invalid constant name `{name}`"
+                    )));
+                }
+                let value = match parse_constant(operands[1])? {
+                    Constant::Value(v) => v,
+                    Constant::Label(label) => self.lookup(&label).ok_or(ExecutionError::new(
+                        format!("`{label}` must be defined before it is used in `.equ`"),
+                    ))?,
+                };
+                if self.constants.insert(name.to_string(), value).is_some() {
+                    return Err(ExecutionError::new(format!("constant `{name}` redefined")));
+                }
+            }
+            ".break" => {
+                expect_operands(&mnemonic, &operands, 0)?;
+                self.break_points.push(self.image.len() as u64);
+            }
+            _ => {
+                let (operation, labels) = parse_instruction(&mnemonic, &operands)?;
+                self.lines.push((self.image.len() as u64, self.line as u64));
+                self.emit(&operation.encode(), labels);
+            }
+        }
+        Ok(())
+    }
+
+    /// appends bytes, `labels` are offsets into `bytes` patched with label addresses during linking
+    fn emit(&mut self, bytes: &[u8], labels: Vec<(usize, String)>) {
+        for (offset, label) in labels {
+            self.fixups.push(Fixup {
+                offset: self.image.len() + offset,
+                label,
+                line: self.line,
+            });
+        }
+        self.image.extend_from_slice(bytes);
+    }
+
+    fn define_label(&mut self, label: &str) -> Result<(), ExecutionError> {
+        let addr = self.image.len() as u64;
+        if self.addresses.insert(label.to_string(), addr).is_some() {
+            return Err(ExecutionError::new(format!("label `{label}` redefined")));
+        }
+        self.labels.push((addr, label.to_string()));
+        Ok(())
+    }
+
+    fn lookup(&self, name: &str) -> Option<u64> {
+        self.addresses
+            .get(name)
+            .or(self.constants.get(name))
+            .copied()
+    }
+
+    /// patches every label reference now that all labels are defined
+    fn link(&mut self) -> Result<(), ExecutionError> {
+        for fixup in &self.fixups {
+            let addr = self
+                .lookup(&fixup.label)
+                .ok_or(ExecutionError::new(format!(
+                    "{}: undefined label `{}`",
+                    fixup.line, fixup.label
+                )))?;
+            self.image[fixup.offset..fixup.offset + 8].copy_from_slice(&addr.to_le_bytes());
+        }
+        Ok(())
+    }
+
+    /// lays the program out as `NISVCEF::load` reads it
+    fn serialize(&self) -> Vec<u8> {
+        let entry_point = self.addresses.get("_start").copied().unwrap_or(0);
+        let mut binary = SIGNATURE.to_vec();
+        binary.extend(entry_point.to_le_bytes());
+        binary.extend((self.image.len() as u64).to_le_bytes());
+        binary.extend(&self.image);
+        binary.extend((self.break_points.len() as u64 * 8).to_le_bytes());
+        for bp in &self.break_points {
+            binary.extend(bp.to_le_bytes());
+        }
+        let mut labels = Vec::new();
+        for (addr, label) in &self.labels {
+            labels.extend(addr.to_le_bytes());
+            labels.extend((label.len() as u64).to_le_bytes());
+            labels.extend(label.as_bytes());
+        }
+        binary.extend((labels.len() as u64).to_le_bytes());
+        binary.extend(labels);
+        let mut lines = (self.path.len() as u64).to_le_bytes().to_vec();
+        lines.extend(self.path.as_bytes());
+        for (addr, line) in &self.lines {
+            lines.extend(addr.to_le_bytes());
+            lines.extend(line.to_le_bytes());
+        }
+        binary.extend((lines.len() as u64).to_le_bytes());
+        binary.extend(lines);
+        binary
+    }
+}
+
+/// returns the operation and any label references as offsets into its encoding
+fn parse_instruction(
+    mnemonic: &str,
+    operands: &[&str],
+) -> Result<(Operation, Vec<(usize, String)>), ExecutionError> {
+    let info = InstructionInfo::find(mnemonic).ok_or(ExecutionError::new(format!(
+        "unknown instruction `{mnemonic}`"
+    )))?;
+    expect_operands(mnemonic, operands, info.operands.len())?;
+    let mut values = Vec::with_capacity(operands.len());
+    let mut labels = Vec::new();
+    // skip the opcode byte
+    let mut offset = 1;
+    for (kind, operand) in info.operands.iter().zip(operands) {
+        let value = match kind {
+            OperandKind::Reg | OperandKind::FReg => parse_register(operand)? as u64,
+            OperandKind::Const | OperandKind::Addr => match parse_constant(operand)? {
+                Constant::Value(v) => v,
+                Constant::Label(label) => {
+                    labels.push((offset, label));
+                    0
+                }
+            },
+        };
+        values.push(value);
+        offset += kind.size();
+    }
+    let operation = Operation::from_operands(info, &values).ok_or(ExecutionError::new(format!(
+        "`{mnemonic}` could not be built from its operands"
+    )))?;
+    Ok((operation, labels))
+}
+
+fn expect_operands(mnemonic: &str, operands: &[&str], count: usize) -> Result<(), ExecutionError> {
+    if operands.len() != count {
+        return Err(ExecutionError::new(format!(
+            "`{mnemonic}` expects {count} operands but found {}",
+            operands.len()
+        )));
+    }
+    Ok(())
+}
+
+fn parse_register(name: &str) -> Result<u8, ExecutionError> {
+    encode_register(name).ok_or(ExecutionError::new(format!("unknown register `{name}`")))
+}
+
+/// parses `$123`, `$-1`, `$x00aa`, `$1.5` or `$!label`
+fn parse_constant(s: &str) -> Result<Constant, ExecutionError> {
+    let invalid = |e: String| ExecutionError::new(format!("invalid constant `{s}`: {e}"));
+    let value = s.strip_prefix('$').ok_or(ExecutionError::new(format!(
+        "expected a `$` constant, found `{s}`"
+    )))?;
+    if let Some(label) = value.strip_prefix('!') {
+        if !is_identifier(label) {
+            return Err(invalid("not a label".to_string()));
+        }
+        return Ok(Constant::Label(label.to_string()));
+    }
+    let value = if let Some(hex) = value.strip_prefix('x') {
+        u64::from_str_radix(hex, 16).map_err(|e| invalid(e.to_string()))?
+    } else if value.contains('.') {
+        value
+            .parse::<f64>()
+            .map_err(|e| invalid(e.to_string()))?
+            .to_bits()
+    } else if value.starts_with('-') {
+        value.parse::<i64>().map_err(|e| invalid(e.to_string()))? as u64
+    } else {
+        value.parse::<u64>().map_err(|e| invalid(e.to_string()))?
+    };
+    Ok(Constant::Value(value))
+}
+
+fn parse_string(s: &str) -> Result<Vec<u8>, ExecutionError> {
+    let inner = s
+        .strip_prefix('"')
+        .and_then(|s| s.strip_suffix('"'))
+        .ok_or(ExecutionError::new(format!(
+            "expected a quoted string, found `{s}`"
+        )))?;
+    let mut bytes = Vec::with_capacity(inner.len());
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            let mut buf = [0; 4];
+            bytes.extend(c.encode_utf8(&mut buf).as_bytes());
+            continue;
+        }
+        let escaped = match chars.next() {
+            Some('n') => b'\n',
+            Some('t') => b'\t',
+            Some('r') => b'\r',
+            Some('0') => 0,
+            Some('\\') => b'\\',
+            Some('"') => b'"',
+            Some('x') => {
+                let hex: String = chars.by_ref().take(2).collect();
+                u8::from_str_radix(&hex, 16)
+                    .map_err(|e| ExecutionError::new(format!("invalid escape `\\x{hex}`: {e}")))?
+            }
+            other => {
+                return Err(ExecutionError::new(format!(
+                    "invalid escape `\\{}`",
+                    other.map(String::from).unwrap_or_default()
+                )))
+            }
+        };
+        bytes.push(escaped);
+    }
+    Ok(bytes)
+}
+
+/// strips `#` and `;` comments outside of string literals
+fn strip_comment(line: &str) -> &str {
+    let mut quoted = false;
+    let mut escaped = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if quoted => escaped = true,
+            '"' => quoted = !quoted,
+            '#' | ';' if !quoted => return &line[..i],
+            _ => (),
+        }
+    }
+    line
+}
+
+fn is_identifier(s: &str) -> bool {
+    !s.is_empty()
+        && !s.starts_with(|c: char| c.is_ascii_digit())
+        && s.chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::loader::NISVCEF;
+
+    fn assemble(source: &str) -> NISVCEF {
+        let binary = Assembler::assemble(source).unwrap_or_else(|e| panic!("{}", e.error));
+        NISVCEF::load(binary).unwrap_or_else(|e| panic!("{}", e.error))
+    }
+
+    fn error(source: &str) -> String {
+        Assembler::assemble(source).unwrap_err().error
+    }
+
+    #[test]
+    fn serialized_binary_loads() {
+        let nef = assemble(
+            "
+            data:   .bytes $1, $xff
+            _start: .break
+                    jmp $!data
+            ",
+        );
+        assert_eq!(nef.entry_point, 2);
+        assert_eq!(nef.break_points, [2]);
+        assert_eq!(nef.address_of("data"), Some(0));
+        assert_eq!(nef.label_at(2), Some("_start"));
+        assert_eq!(&nef.image[..2], [1, 0xff]);
+        assert_eq!(nef.image.len(), 2 + 9);
+    }
+
+    #[test]
+    fn line_table_maps_instructions_to_source_lines() {
+        let binary = Assembler::assemble_named(
+            "_start: nop\n\n        .bytes $1\n        jmp $!_start # back\n",
+            "/src/loop.s",
+        )
+        .unwrap_or_else(|e| panic!("{}", e.error));
+        let nef = NISVCEF::load(binary).unwrap_or_else(|e| panic!("{}", e.error));
+        let symbols = &nef.debug_symbols;
+        assert_eq!(symbols.source(), Some("/src/loop.s"));
+        assert_eq!(symbols.line_of(0), Some(1));
+        assert_eq!(symbols.line_of(2), Some(4));
+        // breakpoints on lines without code move to the next instruction
+        assert_eq!(symbols.address_of_line(2), Some((2, 4)));
+        assert_eq!(symbols.address_of_line(5), None);
+    }
+
+    #[test]
+    fn forward_references_are_patched() {
+        let nef = assemble(
+            "
+                    jmp $!end
+                    .dword $!end, $x1122
+            end:    haltexe
+            ",
+        );
+        let end = nef.address_of("end").unwrap();
+        assert_eq!(end, 9 + 16);
+        assert_eq!(nef.image[1..9], end.to_le_bytes());
+        assert_eq!(nef.image[9..17], end.to_le_bytes());
+        assert_eq!(nef.image[17..25], 0x1122u64.to_le_bytes());
+    }
+
+    #[test]
+    fn equ_names_a_constant_without_a_label() {
+        let nef = assemble(
+            "
+            .equ len, $x10
+            .equ also, $!len
+            pushi $!also
+            ",
+        );
+        assert_eq!(nef.address_of("len"), None);
+        assert_eq!(nef.image[1..9], 0x10u64.to_le_bytes());
+        assert!(error(".equ n, $!later").contains("must be defined before"));
+        assert_eq!(error(".equ n, $1\n.equ n, $2"), "2: constant `n` redefined");
+    }
+
+    #[test]
+    fn isa_examples_assemble() {
+        let nef = assemble("sub r3b1,r2,r1\njifz r3b1,$x00aa\njifnz r3b1,$x00aa\ncall $x00aa");
+        assert_eq!(nef.image[6..14], 0xaau64.to_le_bytes());
+    }
+
+    #[test]
+    fn errors_name_the_line() {
+        assert_eq!(error("nop\nfoo r1"), "2: unknown instruction `foo`");
+        assert_eq!(error("jmp $!nowhere"), "1: undefined label `nowhere`");
+        assert_eq!(error("a: nop\na: nop"), "2: label `a` redefined");
+        assert_eq!(error("ldi r1"), "1: `ldi` expects 2 operands but found 1");
+        assert_eq!(error(".bytes $x100"), "1: `$x100` does not fit in a byte");
+        assert_eq!(error(".bytes $-1"), "1: `$-1` does not fit in a byte");
+        assert!(error("ldi r1, 5").starts_with("1: expected a `$` constant"));
+    }
+}
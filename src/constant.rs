@@ -1,6 +1,9 @@
 pub const END_EXEC: u8 = 0xff;
 pub const HALT_EXE: u8 = 0xfe;
 pub const UNINITIALIZED_MEMORY: u8 = 0xfd;
+pub const BREAKPOINT: u8 = 0xfc;
+/// opcode, register and u64 constant
+pub const MAX_INSTRUCTION_LENGTH: u64 = 10;
 pub const UNINITIALIZED_REGISTER: u64 = 0xfdfdfdfdfdfdfdfd;
 pub const NAME: &str = "nisvc-system";
 pub const PROGRAM_COUNTER: u8 = 1;
@@ -9,6 +12,12 @@ pub const FRAME_POINTER: u8 = 3;
 pub const SIGNATURE: &[u8] = b"NISVC-EF";
 pub const STACK_SIZE: u64 = 1000;
 pub const DEFAULT_CLOCK_SPEED: u64 = 1000; // steps per second
+pub const DEFAULT_HEAP_SIZE: u64 = 1_000_000;
+pub const DEFAULT_STACK_SIZE: u64 = 1_0000;
+
+/// program defined interrupt codes device irqs are delivered as
+pub const IRQ_TIMER: u8 = 0x31;
+pub const IRQ_INPUT: u8 = 0x32;
 
 pub const MEM_STATIC: u8 = 0;
 pub const MEM_HEAP: u8 = 1;
@@ -1,21 +1,20 @@
-use std::{
-    fs::File,
-    io::Read,
-    mem::transmute,
-    ops::{Shl, Shr},
-};
+use std::{fs::File, io::Read, mem::transmute};
 
 pub type RegHandle = u8;
 
 use crossterm::style::Stylize;
 
 use crate::{
-    constant::{FRAME_POINTER, PROGRAM_COUNTER, STACK_POINTER, UNINITIALIZED_REGISTER},
-    loader::NISVCEF,
+    constant::{
+        FRAME_POINTER, MAX_INSTRUCTION_LENGTH, PROGRAM_COUNTER, STACK_POINTER,
+        UNINITIALIZED_REGISTER,
+    },
+    loader::{DebugSymbols, NISVCEF},
     log_disassembly,
+    logger::Logger,
     memory::{bytes_to_u64, Memory},
-    opcode::Operation,
-    very_verbose_println, very_very_verbose_println, ExecutionError, GLOBAL_PROGRAM_COUNTER,
+    opcode::{ByteSource, ImageCursor, OperandKind, Operation},
+    verbose_println, very_verbose_println, very_very_verbose_println, ErrorKind, ExecutionError,
 };
 
 #[derive(Clone)]
@@ -99,10 +98,53 @@ fn decode_register(code: RegHandle) -> (u8, RegWindow) {
         12 => (base, RegWindow::Q4),
         13 => (base, RegWindow::L),
         14 => (base, RegWindow::H),
-        15 => (base, RegWindow::F), // potential to reroute to other registers
-        16 => (base, RegWindow::F), // potential to reroute to other registers
-        _ => unreachable!(),
+        _ => (base, RegWindow::F), // potential to reroute to other registers
+    }
+}
+
+const REGISTER_NAMES: [&str; 16] = [
+    "null", "pc", "sp", "fp", "r1", "r2", "r3", "r4", "r5", "r6", "r7", "r8", "r9", "r10", "r11",
+    "r12",
+];
+const WINDOW_SUFFIXES: [&str; 15] = [
+    "", "b1", "b2", "b3", "b4", "b5", "b6", "b7", "b8", "q1", "q2", "q3", "q4", "l", "h",
+];
+
+/// names a register handle without reading it, such as `r3b1` or `pc`
+pub fn register_name(handle: RegHandle) -> String {
+    let idx = (handle & 0x0f) as usize;
+    let sub = ((handle & 0xf0) >> 4) as usize;
+    if idx < 4 {
+        REGISTER_NAMES[idx].to_string()
+    } else {
+        format!(
+            "{}{}",
+            REGISTER_NAMES[idx],
+            WINDOW_SUFFIXES.get(sub).unwrap_or(&"")
+        )
+    }
+}
+
+/// encodes a register name such as `r1`, `r3b1` or `pc` into its register handle
+pub fn encode_register(name: &str) -> Option<RegHandle> {
+    let name = name.trim().to_lowercase();
+    // longest base name first so `r10` is not matched as `r1` with suffix `0`
+    let (idx, base) = REGISTER_NAMES
+        .iter()
+        .enumerate()
+        .filter(|(_, base)| name.starts_with(*base))
+        .max_by_key(|(_, base)| base.len())?;
+    let suffix = &name[base.len()..];
+    if idx < 4 && !suffix.is_empty() {
+        // special purpose registers are not windowed
+        return None;
     }
+    let sub = if suffix == "f" {
+        0
+    } else {
+        WINDOW_SUFFIXES.iter().position(|s| *s == suffix)?
+    };
+    Some(((sub as u8) << 4) | idx as u8)
 }
 
 union RegisterUnion {
@@ -256,6 +298,19 @@ impl CPURegisters {
         format!("{}{}", name.red(), format!("(0x{:0>2})", val).dark_blue())
     }
 
+    /// prints every full width register
+    pub fn print_all(&mut self) -> String {
+        let mut lines = Vec::with_capacity(self.registers.len() / 4);
+        for row in (0..self.registers.len() as RegHandle).step_by(4) {
+            let line = (row..row + 4)
+                .map(|handle| self.print(handle))
+                .collect::<Vec<String>>()
+                .join(" ");
+            lines.push(line);
+        }
+        lines.join("\n")
+    }
+
     pub fn get_bytelength(&self, register_handle: RegHandle) -> u64 {
         let (_, window) = decode_register(register_handle);
         match window {
@@ -283,249 +338,117 @@ pub struct CPU {
     pub memory: Memory,
     // pub vm_host_bridge: VMHostBridge,
     pub pending_interrupt: u8,
+    /// set when a breakpoint instruction was executed
+    pub pending_breakpoint: bool,
+    /// clock cycles elapsed, one per instruction or idle cycle while waiting for an irq
+    pub cycles: u64,
+    /// guest interrupt handlers entered and not yet left with `iret`
+    pub interrupt_depth: usize,
+    /// frame pointers of the frames set up by `call` that are still on the stack, the saved
+    /// chain alone cannot tell outermost code from a first call made on an empty stack
+    frames: Vec<u64>,
+    pub log: Logger,
 }
 
 impl CPU {
-    pub fn new(heap: u64, stack: u64) -> Self {
+    pub fn new(heap: u64, stack: u64, log: Logger) -> Self {
         Self {
             registers: CPURegisters::new(),
-            memory: Memory::new(heap, stack),
+            memory: Memory::new(heap, stack, log.clone()),
+            log,
             // vm_host_bridge: VMHostBridge::new(),
             pending_interrupt: 0,
+            pending_breakpoint: false,
+            cycles: 0,
+            interrupt_depth: 0,
+            frames: Vec::new(),
         }
     }
-    pub fn load(&mut self, file_path: &str) -> Result<(), ExecutionError> {
+    /// loads the executable image into memory and returns the rest of the package
+    pub fn load(&mut self, file_path: &str) -> Result<NISVCEF, ExecutionError> {
         let mut file = File::open(file_path)
-            .map_err(|e| ExecutionError::new(format!("cannot open file `{file_path}`: {e}",)))?;
+            .map_err(|e| ExecutionError::io(&e, format!("cannot open file `{file_path}`: {e}",)))?;
         let mut contents: Vec<u8> = Vec::new();
         file.read_to_end(&mut contents)
-            .map_err(|e| ExecutionError::new(format!("cannot read file to memory: {e}")))?;
-        let nisvc_executable_package = NISVCEF::load(contents)?;
-        self.memory.load(nisvc_executable_package.image)?;
+            .map_err(|e| ExecutionError::io(&e, format!("cannot read file to memory: {e}")))?;
+        self.load_executable(contents)
+    }
+    /// loads an executable that is already in host memory
+    pub fn load_executable(&mut self, executable: Vec<u8>) -> Result<NISVCEF, ExecutionError> {
+        let mut nisvc_executable_package = NISVCEF::load(executable)?;
+        verbose_println!(
+            self.log,
+            "entry_point: {:#x} program_img_len: {:#x} breakpoints: {}",
+            nisvc_executable_package.entry_point,
+            nisvc_executable_package.image.len(),
+            nisvc_executable_package.break_points.len()
+        );
+        self.memory
+            .load(std::mem::take(&mut nisvc_executable_package.image))?;
         self.registers
             .write(PROGRAM_COUNTER, nisvc_executable_package.entry_point);
         self.registers.write(STACK_POINTER, self.memory.stack_start);
         self.registers.write(FRAME_POINTER, self.memory.stack_start);
-        Ok(())
+        self.frames.clear();
+        Ok(nisvc_executable_package)
     }
-    /// advances pc and returns consumed byte
-    fn consume_byte(&mut self) -> Result<u8, ExecutionError> {
-        let pc = self.registers.read(PROGRAM_COUNTER);
-        let byte = self.memory.read_byte(pc)?;
-        self.registers.write(PROGRAM_COUNTER, pc + 1);
-        very_verbose_println!("byte at {pc:#x} consumed: {:#x}", byte);
-
-        Ok(byte)
+    /// decodes the instruction at `addr` without executing it, returns it and its length
+    pub fn decode_at(&self, addr: u64) -> Result<(Operation, u64), ExecutionError> {
+        let raw: Vec<u8> = (addr..addr + MAX_INSTRUCTION_LENGTH)
+            .map_while(|addr| self.memory.read_byte(addr).ok())
+            .collect();
+        let mut cursor = ImageCursor {
+            image: &raw,
+            offset: 0,
+        };
+        let operation = Operation::decode(&mut cursor)?;
+        Ok((operation, cursor.offset as u64))
     }
-    /// advances pc and returns consumed address (double word u64)
-    fn consume_constant(&mut self) -> Result<u64, ExecutionError> {
-        let pc = self.registers.read(PROGRAM_COUNTER);
-        let double_word = self.memory.read_address(pc)?;
-        self.registers.write(PROGRAM_COUNTER, pc + 8);
-        very_verbose_println!(
-            "byte at {pc:#x}..{:#x} consumed: {:#x}",
-            pc + 8,
-            double_word
-        );
 
-        Ok(double_word)
+    /// length of the instruction at `addr`, 1 when it does not decode
+    pub fn instruction_len(&self, addr: u64) -> u64 {
+        self.decode_at(addr).map_or(1, |(_, len)| len)
     }
-
     fn fetch_decode(&mut self) -> Result<Operation, ExecutionError> {
-        let opcode = self.consume_byte()?;
-        let operation = match opcode {
-            0x00 => Operation::Nop,
-            0x01 => Operation::Cpy {
-                dest: self.consume_byte()?,
-                src: self.consume_byte()?,
-            },
-            0x02 => Operation::Ldi {
-                dest: self.consume_byte()?,
-                src: self.consume_constant()?,
-            },
-            0x03 => Operation::Load {
-                dest: self.consume_byte()?,
-                n: self.consume_byte()?,
-                src: self.consume_byte()?,
-            },
-            0x04 => Operation::Store {
-                dest: self.consume_byte()?,
-                n: self.consume_byte()?,
-                src: self.consume_byte()?,
-            },
-            0x05 => Operation::Add {
-                dest: self.consume_byte()?,
-                op1: self.consume_byte()?,
-                op2: self.consume_byte()?,
-            },
-            0x06 => Operation::Sub {
-                dest: self.consume_byte()?,
-                op1: self.consume_byte()?,
-                op2: self.consume_byte()?,
-            },
-            0x07 => Operation::Mult {
-                dest: self.consume_byte()?,
-                op1: self.consume_byte()?,
-                op2: self.consume_byte()?,
-            },
-            0x08 => Operation::Div {
-                dest: self.consume_byte()?,
-                op1: self.consume_byte()?,
-                op2: self.consume_byte()?,
-            },
-            0x09 => Operation::Or {
-                dest: self.consume_byte()?,
-                op1: self.consume_byte()?,
-                op2: self.consume_byte()?,
-            },
-            0x0a => Operation::Xor {
-                dest: self.consume_byte()?,
-                op1: self.consume_byte()?,
-                op2: self.consume_byte()?,
-            },
-            0x0b => Operation::And {
-                dest: self.consume_byte()?,
-                op1: self.consume_byte()?,
-                op2: self.consume_byte()?,
-            },
-            0x0c => Operation::Not {
-                dest: self.consume_byte()?,
-                op: self.consume_byte()?,
-            },
-            0x0d => Operation::Shl {
-                dest: self.consume_byte()?,
-                n: self.consume_byte()?,
-                src: self.consume_byte()?,
-            },
-            0x0e => Operation::Shr {
-                dest: self.consume_byte()?,
-                n: self.consume_byte()?,
-                src: self.consume_byte()?,
-            },
-            0x0f => Operation::Rotl {
-                dest: self.consume_byte()?,
-                n: self.consume_byte()?,
-                src: self.consume_byte()?,
-            },
-            0x10 => Operation::Rotr {
-                dest: self.consume_byte()?,
-                n: self.consume_byte()?,
-                src: self.consume_byte()?,
-            },
-            0x11 => Operation::Neg {
-                dest: self.consume_byte()?,
-                op: self.consume_byte()?,
-            },
-            0x12 => Operation::Jmp {
-                addr: self.consume_constant()?,
-            },
-            0x13 => Operation::Jifz {
-                condition_reg: self.consume_byte()?,
-                addr: self.consume_constant()?,
-            },
-            0x14 => Operation::Jifnz {
-                condition_reg: self.consume_byte()?,
-                addr: self.consume_constant()?,
-            },
-            // pr
-            0x16 => Operation::Inc {
-                reg: self.consume_byte()?,
-            },
-            0x17 => Operation::Dec {
-                reg: self.consume_byte()?,
-            },
-            0x18 => Operation::Push {
-                src: self.consume_byte()?,
-            },
-            0x19 => Operation::Pop {
-                dest: self.consume_byte()?,
-            },
-            0x1a => Operation::Call {
-                addr: self.consume_constant()?,
-            },
-            0x1b => Operation::Ret,
-            0x1c => Operation::Itof {
-                destf: self.consume_byte()?,
-                srci: self.consume_byte()?,
-            },
-            0x1d => Operation::Ftoi {
-                desti: self.consume_byte()?,
-                srcf: self.consume_byte()?,
-            },
-            0x1e => Operation::Fadd {
-                dest: self.consume_byte()?,
-                op1: self.consume_byte()?,
-                op2: self.consume_byte()?,
-            },
-            0x1f => Operation::Fsub {
-                dest: self.consume_byte()?,
-                op1: self.consume_byte()?,
-                op2: self.consume_byte()?,
-            },
-            0x20 => Operation::Fmult {
-                dest: self.consume_byte()?,
-                op1: self.consume_byte()?,
-                op2: self.consume_byte()?,
-            },
-            0x21 => Operation::Fdiv {
-                dest: self.consume_byte()?,
-                op1: self.consume_byte()?,
-                op2: self.consume_byte()?,
-            },
-            0x22 => Operation::Fmod {
-                dest: self.consume_byte()?,
-                op1: self.consume_byte()?,
-                op2: self.consume_byte()?,
-            },
-            0x23 => Operation::Mod {
-                dest: self.consume_byte()?,
-                op1: self.consume_byte()?,
-                op2: self.consume_byte()?,
-            },
-            0x24 => Operation::Int {
-                code: self.consume_constant()?,
-            },
-            0x25 => Operation::Pushi {
-                immediate: self.consume_constant()?,
-            },
-            0xfd => {
-                return Err(ExecutionError::new(format!(
-                    "malformed binary: attempted to execute uninitialized memory (opcode 0xfd)"
-                )))
-            }
-            0xff => todo!(),
-            _ => panic!("unrecognized opcode {opcode:#x}"),
-        };
-        Ok(operation)
+        let pc = self.registers.read(PROGRAM_COUNTER);
+        Operation::decode(self).map_err(|e| match e.kind {
+            ErrorKind::IllegalInstruction => {
+                let raw = (pc..pc + MAX_INSTRUCTION_LENGTH)
+                    .map_while(|addr| self.memory.read_byte(addr).ok())
+                    .map(|b| format!("{b:0>2x}"))
+                    .collect::<Vec<String>>()
+                    .join(" ");
+                e.prepend(format!("illegal instruction @ {pc:#x} [{raw}]: "))
+            }
+            _ => e,
+        })
     }
 
-    fn execute(&mut self, operation: Operation) -> Result<(), ExecutionError> {
-        very_verbose_println!("exec {:?}", operation);
+    /// `symbols` render jump targets and `pc`, the address of the instruction
+    fn execute(
+        &mut self,
+        operation: Operation,
+        symbols: &DebugSymbols,
+        pc: u64,
+    ) -> Result<(), ExecutionError> {
+        very_verbose_println!(self.log, "exec {:?}", operation);
         match operation {
-            Operation::Nop => log_disassembly!("nop"),
+            Operation::Nop => (),
             Operation::Cpy { dest, src } => {
                 let value = self.registers.read(src);
                 self.registers.write(dest, value);
                 // println!("you here? ");
-                log_disassembly!(
-                    "cpy {}, {}",
-                    self.registers.print(dest),
-                    self.registers.print(src)
-                );
             }
             Operation::Ldi { dest, src } => {
                 self.registers.write(dest, src);
-                log_disassembly!("ldi {}, ${}", self.registers.print(dest), src);
             }
 
             Operation::Load { dest, n, src } => {
                 let n_val = self.registers.read(n);
-                log_disassembly!(
-                    "load {}, {}, {}",
-                    self.registers.print(dest),
-                    self.registers.print(n),
-                    self.registers.print(src),
-                );
+                if n_val > 8 {
+                    let name = self.registers.print(dest);
+                    return Err(ExecutionError::fault(ErrorKind::IllegalInstruction, format!("Attempted to load {n_val} bytes into {name} which is more than a register holds (8)")));
+                }
                 let bytes = bytes_to_u64(&self.memory.read(self.registers.read(src), n_val)?);
                 self.registers.write(dest, bytes);
             }
@@ -536,15 +459,9 @@ impl CPU {
                 let max = self.registers.get_bytelength(src);
                 if n_val > max {
                     let name = self.registers.print(src);
-                    return Err(ExecutionError::new(format!("Attempted to store {n_val} bytes from {name} to ${addr:#x} which are more bytes than are present in the register ({max}) ")));
+                    return Err(ExecutionError::fault(ErrorKind::IllegalInstruction, format!("Attempted to store {n_val} bytes from {name} to ${addr:#x} which are more bytes than are present in the register ({max}) ")));
                 }
 
-                log_disassembly!(
-                    "store {}, {}, {}",
-                    self.registers.print(dest),
-                    self.registers.print(n),
-                    self.registers.print(src)
-                );
                 self.memory.write(addr, &bytes[0..n_val as usize])?;
             }
             Operation::Add { dest, op1, op2 } => {
@@ -553,13 +470,6 @@ impl CPU {
                     .read(op1)
                     .wrapping_add(self.registers.read(op2));
                 self.registers.write(dest, sum);
-
-                log_disassembly!(
-                    "add {}, {}, {}",
-                    self.registers.print(dest),
-                    self.registers.print(op1),
-                    self.registers.print(op2)
-                );
             }
             Operation::Sub { dest, op1, op2 } => {
                 let diff = self
@@ -567,13 +477,6 @@ impl CPU {
                     .read(op1)
                     .wrapping_sub(self.registers.read(op2));
                 self.registers.write(dest, diff);
-
-                log_disassembly!(
-                    "sub {}, {}, {}",
-                    self.registers.print(dest),
-                    self.registers.print(op1),
-                    self.registers.print(op2)
-                );
             }
             Operation::Mult { dest, op1, op2 } => {
                 let result = self
@@ -581,96 +484,48 @@ impl CPU {
                     .read(op1)
                     .wrapping_mul(self.registers.read(op2));
                 self.registers.write(dest, result);
-
-                log_disassembly!(
-                    "mult {}, {}, {}",
-                    self.registers.print(dest),
-                    self.registers.print(op1),
-                    self.registers.print(op2)
-                );
             }
             Operation::Div { dest, op1, op2 } => {
                 // let op1_val = self.registers.read(op1);
                 let op2_val = self.registers.read(op2);
                 if op2_val == 0 {
-                    return Err(ExecutionError::new(format!(
-                        "division by zero error {op1} / {op2}",
-                    )));
+                    return Err(ExecutionError::fault(
+                        ErrorKind::DivisionByZero,
+                        format!("division by zero error {op1} / {op2}"),
+                    ));
                 }
                 let quotient = self.registers.read(op1).wrapping_div(op2_val);
                 self.registers.write(dest, quotient);
-
-                log_disassembly!(
-                    "div {}, {}, {}",
-                    self.registers.print(dest),
-                    self.registers.print(op1),
-                    self.registers.print(op2)
-                );
             }
             Operation::Or { dest, op1, op2 } => {
                 let result = self.registers.read(op1) | self.registers.read(op2);
                 self.registers.write(dest, result);
-
-                log_disassembly!(
-                    "or {}, {}, {}",
-                    self.registers.print(dest),
-                    self.registers.print(op1),
-                    self.registers.print(op2)
-                );
             }
             Operation::Xor { dest, op1, op2 } => {
                 let result = self.registers.read(op1) ^ self.registers.read(op2);
                 self.registers.write(dest, result);
-
-                log_disassembly!(
-                    "xor {}, {}, {}",
-                    self.registers.print(dest),
-                    self.registers.print(op1),
-                    self.registers.print(op2)
-                );
             }
             Operation::And { dest, op1, op2 } => {
                 let result = self.registers.read(op1) & self.registers.read(op2);
                 self.registers.write(dest, result);
-
-                log_disassembly!(
-                    "and {}, {}, {}",
-                    self.registers.print(dest),
-                    self.registers.print(op1),
-                    self.registers.print(op2)
-                );
             }
             Operation::Not { dest, op } => {
                 let result = !self.registers.read(op);
                 self.registers.write(dest, result);
-
-                log_disassembly!(
-                    "not {}, {}",
-                    self.registers.print(dest),
-                    self.registers.print(op),
-                );
             }
             Operation::Shl { dest, n, src } => {
-                let result = self.registers.read(src).shl(self.registers.read(n));
+                let result = self
+                    .registers
+                    .read(src)
+                    .wrapping_shl(self.registers.read(n) as u32);
                 self.registers.write(dest, result);
-
-                log_disassembly!(
-                    "shl {}, {}, {}",
-                    self.registers.print(dest),
-                    self.registers.print(n),
-                    self.registers.print(src)
-                );
             }
             Operation::Shr { dest, n, src } => {
-                let result = self.registers.read(src).shr(self.registers.read(n));
+                let result = self
+                    .registers
+                    .read(src)
+                    .wrapping_shr(self.registers.read(n) as u32);
                 self.registers.write(dest, result);
-
-                log_disassembly!(
-                    "shr {}, {}, {}",
-                    self.registers.print(dest),
-                    self.registers.print(n),
-                    self.registers.print(src)
-                );
             }
             Operation::Rotl { dest, n, src } => {
                 let result = self
@@ -678,12 +533,6 @@ impl CPU {
                     .read(src)
                     .rotate_left(self.registers.read(n) as u32);
                 self.registers.write(dest, result);
-                log_disassembly!(
-                    "or {}, {}, {}",
-                    self.registers.print(dest),
-                    self.registers.print(n),
-                    self.registers.print(src)
-                );
             }
             Operation::Rotr { dest, n, src } => {
                 let result = self
@@ -691,26 +540,14 @@ impl CPU {
                     .read(src)
                     .rotate_right(self.registers.read(n) as u32);
                 self.registers.write(dest, result);
-                log_disassembly!(
-                    "or {}, {}, {}",
-                    self.registers.print(dest),
-                    self.registers.print(n),
-                    self.registers.print(src)
-                );
             }
             Operation::Neg { dest, op } => {
                 let sign_mask: u64 = 0x80_00_00_00_00_00_00_00;
                 let result = self.registers.read(op) ^ sign_mask;
                 self.registers.write(dest, result);
-                log_disassembly!(
-                    "or {}, {}",
-                    self.registers.print(dest),
-                    self.registers.print(op),
-                );
             }
             Operation::Jmp { addr } => {
                 self.registers.write(PROGRAM_COUNTER, addr);
-                log_disassembly!("jmp ${addr}");
             }
             Operation::Jifz {
                 addr,
@@ -720,7 +557,6 @@ impl CPU {
                 if cond == 0 {
                     self.registers.write(PROGRAM_COUNTER, addr);
                 }
-                log_disassembly!("jifz {} ${addr}", self.registers.print(condition_reg));
             }
             Operation::Jifnz {
                 addr,
@@ -730,182 +566,170 @@ impl CPU {
                 if cond != 0 {
                     self.registers.write(PROGRAM_COUNTER, addr);
                 }
-                log_disassembly!("jifnz {} ${addr}", self.registers.print(condition_reg));
             }
             Operation::Inc { reg } => {
                 let inc = self.registers.read(reg).wrapping_add(1);
                 self.registers.write(reg, inc);
-                log_disassembly!("inc {}", self.registers.print(reg));
             }
             Operation::Dec { reg } => {
                 let inc = self.registers.read(reg).wrapping_sub(1);
                 self.registers.write(reg, inc);
-                log_disassembly!("dec {}", self.registers.print(reg));
             }
 
             Operation::Push { src } => {
                 let value = self.registers.read(src);
 
                 self.push(value)?;
-                log_disassembly!("push {}", self.registers.print(src));
             }
             Operation::Pop { dest } => {
                 let value = self.pop()?;
                 self.registers.write(dest, value);
-                log_disassembly!("pop {}", self.registers.print(dest));
-            }
-
-            Operation::Call { addr } => {
-                log_disassembly!("call ${}", addr);
-                let fp = self.registers.read(FRAME_POINTER);
-                let ra = self.registers.read(PROGRAM_COUNTER);
-                let sp = self.registers.read(STACK_POINTER);
-                self.registers.write(FRAME_POINTER, sp);
-                very_very_verbose_println!(
-                    "-- frame setup -- {})",
-                    self.registers.print(STACK_POINTER)
-                );
-                self.push(fp)?;
-                very_very_verbose_println!(
-                    "| fp {fp:#x} -- {} |",
-                    self.registers.print(STACK_POINTER)
-                );
-                self.push(ra)?;
-                very_very_verbose_println!(
-                    "| ra {ra:#x} -- {} |",
-                    self.registers.print(STACK_POINTER)
-                );
-                self.registers.write(PROGRAM_COUNTER, addr);
             }
-            Operation::Ret => {
-                log_disassembly!("ret");
-                let ra = self.pop()?;
-                let fp = self.pop()?;
-                self.registers.write(FRAME_POINTER, fp);
-                self.registers.write(PROGRAM_COUNTER, ra);
+
+            Operation::Call { addr } => self.call(addr)?,
+            Operation::Ret => self.ret()?,
+            Operation::Iret => {
+                if self.interrupt_depth == 0 {
+                    return Err(ExecutionError::fault(
+                        ErrorKind::IllegalInstruction,
+                        "iret outside of an interrupt handler".to_string(),
+                    ));
+                }
+                self.interrupt_depth -= 1;
+                self.ret()?;
             }
 
             Operation::Itof { destf, srci } => {
                 let f = self.registers.read(srci) as f64;
 
                 self.registers.write(destf, unsafe { transmute(f) });
-
-                log_disassembly!(
-                    "itof {}, {}",
-                    self.registers.print_float(destf),
-                    self.registers.print(srci)
-                )
             }
             Operation::Ftoi { desti, srcf } => {
                 let i = self.registers.read(srcf) as u64;
 
                 self.registers.write(desti, i);
-
-                log_disassembly!(
-                    "itof {}, {}",
-                    self.registers.print(desti),
-                    self.registers.print_float(srcf)
-                )
             }
             Operation::Fadd { dest, op1, op2 } => {
                 let fop1: f64 = unsafe { transmute(self.registers.read(op1)) };
                 let fop2: f64 = unsafe { transmute(self.registers.read(op2)) };
                 let sum = fop1 + fop2;
                 self.registers.write(dest, unsafe { transmute(sum) });
-
-                log_disassembly!(
-                    "fadd {}, {}, {}",
-                    self.registers.print_float(dest),
-                    self.registers.print_float(op1),
-                    self.registers.print_float(op2)
-                );
             }
             Operation::Fsub { dest, op1, op2 } => {
                 let fop1: f64 = unsafe { transmute(self.registers.read(op1)) };
                 let fop2: f64 = unsafe { transmute(self.registers.read(op2)) };
                 let diff = fop1 - fop2;
                 self.registers.write(dest, unsafe { transmute(diff) });
-
-                log_disassembly!(
-                    "fsub {}, {}, {}",
-                    self.registers.print_float(dest),
-                    self.registers.print_float(op1),
-                    self.registers.print_float(op2)
-                );
             }
             Operation::Fmult { dest, op1, op2 } => {
                 let fop1: f64 = unsafe { transmute(self.registers.read(op1)) };
                 let fop2: f64 = unsafe { transmute(self.registers.read(op2)) };
                 let result = fop1 * fop2;
                 self.registers.write(dest, unsafe { transmute(result) });
-
-                log_disassembly!(
-                    "fmult {}, {}, {}",
-                    self.registers.print_float(dest),
-                    self.registers.print_float(op1),
-                    self.registers.print_float(op2)
-                );
             }
             Operation::Fdiv { dest, op1, op2 } => {
                 let fop1: f64 = unsafe { transmute(self.registers.read(op1)) };
                 let fop2: f64 = unsafe { transmute(self.registers.read(op2)) };
                 let sum = fop1 + fop2;
                 self.registers.write(dest, unsafe { transmute(sum) });
-
-                log_disassembly!(
-                    "fdiv {}, {}, {}",
-                    self.registers.print_float(dest),
-                    self.registers.print_float(op1),
-                    self.registers.print_float(op2)
-                );
             }
             Operation::Fmod { dest, op1, op2 } => {
                 let fop1: f64 = unsafe { transmute(self.registers.read(op1)) };
                 let fop2: f64 = unsafe { transmute(self.registers.read(op2)) };
                 let sum = fop1 % fop2;
                 self.registers.write(dest, unsafe { transmute(sum) });
-
-                log_disassembly!(
-                    "fdiv {}, {}, {}",
-                    self.registers.print_float(dest),
-                    self.registers.print_float(op1),
-                    self.registers.print_float(op2)
-                );
             }
             Operation::Mod { dest, op1, op2 } => {
-                let sum = self.registers.read(op1) % self.registers.read(op2);
+                let op2_val = self.registers.read(op2);
+                if op2_val == 0 {
+                    return Err(ExecutionError::fault(
+                        ErrorKind::DivisionByZero,
+                        format!("division by zero error {op1} % {op2}"),
+                    ));
+                }
+                let sum = self.registers.read(op1) % op2_val;
                 self.registers.write(dest, sum);
-                log_disassembly!(
-                    "mod {}, {}, {}",
-                    self.registers.print(dest),
-                    self.registers.print(op1),
-                    self.registers.print(op2)
-                );
             }
 
-            Operation::Breakpoint => todo!(),
-            Operation::HaltExe => todo!("haltexe"),
-
-            Operation::Int { code } => {
-                log_disassembly!("int {:#x}", code);
-                self.pending_interrupt = code as u8
+            Operation::Breakpoint => {
+                self.pending_breakpoint = true;
             }
-            Operation::Pushi { immediate } => {
-                log_disassembly!("pushi {immediate:#x}");
-                self.push(immediate)?
+            Operation::HaltExe => {
+                // same as the kill interrupt
+                self.pending_interrupt = 0x14
             }
+
+            Operation::Int { code } => self.pending_interrupt = code as u8,
+            Operation::Pushi { immediate } => self.push(immediate)?,
         };
+        // pcs without a preceding label fall back to the logger's padded hex
+        log_disassembly!(
+            self.log,
+            @symbols.nearest_label(pc).map(|_| symbols.symbolize(pc)).as_deref(),
+            "{}",
+            self.render(&operation, symbols)
+        );
+        Ok(())
+    }
+
+    /// renders an operation with the current register values, jump targets are symbolized
+    fn render(&mut self, operation: &Operation, symbols: &DebugSymbols) -> String {
+        operation.format(|kind, value| match kind {
+            OperandKind::Reg => self.registers.print(value as RegHandle),
+            OperandKind::FReg => self.registers.print_float(value as RegHandle),
+            OperandKind::Addr => symbols.symbolize(value),
+            OperandKind::Const => format!("${value:#x}"),
+        })
+    }
+
+    pub fn step(&mut self, symbols: &DebugSymbols) -> Result<(), ExecutionError> {
+        let pc = self.registers.read(PROGRAM_COUNTER);
+        self.cycles += 1;
+        self.log.locate(pc, self.cycles);
+        let cycle = self.cycles;
+        let op = self.fetch_decode().map_err(|e| e.locate(pc, cycle))?;
+        self.execute(op, symbols, pc)
+            .map_err(|e| e.locate(pc, cycle))?;
+        self.log
+            .locate(self.registers.read(PROGRAM_COUNTER), self.cycles);
         Ok(())
     }
 
-    pub fn step(&mut self) -> Result<(), ExecutionError> {
-        let op = self.fetch_decode()?;
-        self.execute(op)?;
-        unsafe { GLOBAL_PROGRAM_COUNTER = self.registers.read(PROGRAM_COUNTER) }
+    /// sets up a frame returning to the current pc and jumps to addr
+    pub fn call(&mut self, addr: u64) -> Result<(), ExecutionError> {
+        let fp = self.registers.read(FRAME_POINTER);
+        let ra = self.registers.read(PROGRAM_COUNTER);
+        let sp = self.registers.read(STACK_POINTER);
+        self.registers.write(FRAME_POINTER, sp);
+        self.call_depth();
+        self.frames.push(sp);
+        very_very_verbose_println!(
+            self.log,
+            "-- frame setup -- {})",
+            self.registers.print(STACK_POINTER)
+        );
+        self.push(fp)?;
+        very_very_verbose_println!(
+            self.log,
+            "| fp {fp:#x} -- {} |",
+            self.registers.print(STACK_POINTER)
+        );
+        self.push(ra)?;
+        very_very_verbose_println!(
+            self.log,
+            "| ra {ra:#x} -- {} |",
+            self.registers.print(STACK_POINTER)
+        );
+        self.registers.write(PROGRAM_COUNTER, addr);
         Ok(())
     }
 
-    fn break_point(&mut self) -> Result<(), ExecutionError> {
+    /// leaves a frame set up by `call`
+    pub fn ret(&mut self) -> Result<(), ExecutionError> {
+        let ra = self.pop()?;
+        let fp = self.pop()?;
+        self.registers.write(FRAME_POINTER, fp);
+        self.registers.write(PROGRAM_COUNTER, ra);
         Ok(())
     }
 
@@ -922,6 +746,40 @@ impl CPU {
         self.registers.write(STACK_POINTER, sp_d);
         Ok(value)
     }
+    /// number of frames set up by `call` whose saved fp and return address are still on the
+    /// stack, frames the guest unwound by hand are dropped
+    pub fn call_depth(&mut self) -> usize {
+        let sp = self.registers.read(STACK_POINTER);
+        while self.frames.last().is_some_and(|&fp| fp + 16 > sp) {
+            self.frames.pop();
+        }
+        self.frames.len()
+    }
+    /// walks the saved frame pointer and return address pairs pushed by `call`,
+    /// returns the pc of each frame starting with the current one
+    pub fn backtrace(&mut self) -> Vec<u64> {
+        const MAX_FRAMES: usize = 256;
+        let mut frames = vec![self.registers.read(PROGRAM_COUNTER)];
+        let mut fp = self.registers.read(FRAME_POINTER);
+        let depth = self.call_depth().min(MAX_FRAMES - 1);
+        for _ in 0..depth {
+            let (saved_fp, ra) = match (
+                self.memory.read_address(fp),
+                self.memory.read_address(fp + 8),
+            ) {
+                (Ok(saved_fp), Ok(ra)) => (saved_fp, ra),
+                _ => break,
+            };
+            frames.push(ra);
+            // frames only ever grow upwards, anything else is a corrupt chain
+            if saved_fp >= fp {
+                break;
+            }
+            fp = saved_fp;
+        }
+        frames
+    }
+
     pub fn dump_stack(&mut self) -> Vec<u64> {
         let mut stack_dump = Vec::<u64>::new();
         while self.registers.read(STACK_POINTER) != self.memory.stack_start {
@@ -934,3 +792,29 @@ impl CPU {
         stack_dump
     }
 }
+
+impl ByteSource for CPU {
+    /// advances pc and returns consumed byte
+    fn consume_byte(&mut self) -> Result<u8, ExecutionError> {
+        let pc = self.registers.read(PROGRAM_COUNTER);
+        let byte = self.memory.read_byte(pc)?;
+        self.registers.write(PROGRAM_COUNTER, pc + 1);
+        very_verbose_println!(self.log, "byte at {pc:#x} consumed: {:#x}", byte);
+
+        Ok(byte)
+    }
+    /// advances pc and returns consumed address (double word u64)
+    fn consume_constant(&mut self) -> Result<u64, ExecutionError> {
+        let pc = self.registers.read(PROGRAM_COUNTER);
+        let double_word = self.memory.read_address(pc)?;
+        self.registers.write(PROGRAM_COUNTER, pc + 8);
+        very_verbose_println!(
+            self.log,
+            "byte at {pc:#x}..{:#x} consumed: {:#x}",
+            pc + 8,
+            double_word
+        );
+
+        Ok(double_word)
+    }
+}
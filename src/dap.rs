@@ -0,0 +1,799 @@
+use std::{
+    collections::VecDeque,
+    fs::{self, File},
+    io::{stdin, stdout, BufRead, BufReader, Read, Write},
+    os::fd::{AsFd, FromRawFd},
+    path::Path,
+    sync::{
+        mpsc::{channel, Receiver, TryRecvError},
+        Arc, Mutex,
+    },
+    thread,
+};
+
+use serde_json::{json, Value};
+
+use crate::{
+    constant::{
+        DEFAULT_CLOCK_SPEED, DEFAULT_HEAP_SIZE, DEFAULT_STACK_SIZE, FRAME_POINTER, PROGRAM_COUNTER,
+    },
+    cpu::{encode_register, register_name, CPU},
+    debugger::{DebugAction, Debugger},
+    kernel::Kernel,
+    loader::DebugSymbols,
+    logger::Logger,
+    opcode::Operation,
+    ExecutionError,
+};
+
+const THREAD_ID: u64 = 1;
+const REGISTERS_REFERENCE: u64 = 1;
+
+/// writes framed protocol messages, shared with the output forwarding thread
+struct DapWriter {
+    out: Box<dyn Write + Send>,
+    seq: u64,
+}
+
+impl DapWriter {
+    fn send(&mut self, mut message: Value) {
+        self.seq += 1;
+        message["seq"] = json!(self.seq);
+        let body = message.to_string();
+        let _ = write!(self.out, "Content-Length: {}\r\n\r\n{body}", body.len());
+        let _ = self.out.flush();
+    }
+}
+
+/// Debug Adapter Protocol server over stdio
+pub struct DapServer {
+    writer: Arc<Mutex<DapWriter>>,
+    requests: Receiver<Value>,
+    /// requests received while the guest was running
+    queued: VecDeque<Value>,
+    /// still waiting for `configurationDone`
+    configuring: bool,
+    stop_on_entry: bool,
+    running: bool,
+    last_action: &'static str,
+    source_breakpoints: Vec<u64>,
+    function_breakpoints: Vec<u64>,
+    instruction_breakpoints: Vec<u64>,
+    /// one shot breakpoint at the return address used by `stepOut` and `next` over a call
+    temporary_breakpoint: Option<u64>,
+}
+
+/// runs the adapter: waits for a launch request, then runs the program under the debugger
+pub fn run(log: Logger) -> Result<(), ExecutionError> {
+    let (out, mut output) = redirect_stdout()?;
+    let writer = Arc::new(Mutex::new(DapWriter {
+        out: Box::new(out),
+        seq: 0,
+    }));
+    let output_writer = writer.clone();
+    thread::spawn(move || {
+        let mut buf = [0u8; 4096];
+        while let Ok(n) = output.read(&mut buf) {
+            if n == 0 {
+                break;
+            }
+            output_writer.lock().unwrap().send(json!({
+                "type": "event",
+                "event": "output",
+                "body": {"category": "stdout", "output": String::from_utf8_lossy(&buf[..n])},
+            }));
+        }
+    });
+    serve(BufReader::new(stdin()), writer, log)
+}
+
+/// serves one session of requests read from `input`
+fn serve(
+    mut input: impl BufRead + Send + 'static,
+    writer: Arc<Mutex<DapWriter>>,
+    log: Logger,
+) -> Result<(), ExecutionError> {
+    let (sender, requests) = channel();
+    thread::spawn(move || {
+        while let Some(message) = read_message(&mut input) {
+            if sender.send(message).is_err() {
+                break;
+            }
+        }
+    });
+    let mut server = DapServer {
+        writer: writer.clone(),
+        requests,
+        queued: VecDeque::new(),
+        configuring: true,
+        stop_on_entry: false,
+        running: false,
+        last_action: "entry",
+        source_breakpoints: Vec::new(),
+        function_breakpoints: Vec::new(),
+        instruction_breakpoints: Vec::new(),
+        temporary_breakpoint: None,
+    };
+
+    let launch = loop {
+        let request = server.next_request()?;
+        match command(&request) {
+            "initialize" => server.respond(
+                &request,
+                json!({
+                    "supportsConfigurationDoneRequest": true,
+                    "supportsFunctionBreakpoints": true,
+                    "supportsInstructionBreakpoints": true,
+                    "supportsTerminateRequest": true,
+                }),
+            ),
+            "launch" => break request,
+            "disconnect" => {
+                server.respond(&request, json!({}));
+                return Ok(());
+            }
+            _ => server.respond_error(&request, "program not launched"),
+        }
+    };
+    let arguments = &launch["arguments"];
+    let program = match arguments["program"].as_str() {
+        Some(p) => p.to_string(),
+        None => {
+            server.respond_error(&launch, "launch requires `program`");
+            return Err(ExecutionError::new(
+                "dap: launch without program".to_string(),
+            ));
+        }
+    };
+    let cmdline = arguments["args"]
+        .as_array()
+        .map(|args| {
+            args.iter()
+                .filter_map(|a| a.as_str().map(|a| a.to_string()))
+                .collect()
+        })
+        .unwrap_or_default();
+    server.stop_on_entry = arguments["stopOnEntry"].as_bool().unwrap_or(false);
+    let mut kernel = Kernel::new(
+        cmdline,
+        arguments["heap"].as_u64().unwrap_or(DEFAULT_HEAP_SIZE),
+        arguments["stack"].as_u64().unwrap_or(DEFAULT_STACK_SIZE),
+        arguments["clockspeed"]
+            .as_f64()
+            .unwrap_or(DEFAULT_CLOCK_SPEED as f64) as f32,
+        log,
+    );
+    kernel.set_core_dumps(arguments["coreDumps"].as_bool().unwrap_or(true));
+    if let Err(e) = kernel.load(&program) {
+        server.respond_error(&launch, &format!("failed to load `{program}`: {e}"));
+        return Err(e);
+    }
+    server.respond(&launch, json!({}));
+    server.event("initialized", json!({}));
+    kernel.attach_debugger(Debugger::Dap(server));
+    if let Err(e) = kernel.run() {
+        println!("{e}");
+        writer
+            .lock()
+            .unwrap()
+            .send(json!({"type": "event", "event": "terminated", "body": {}}));
+    }
+    Ok(())
+}
+
+impl DapServer {
+    /// serves requests until the client resumes execution
+    pub fn enter(
+        &mut self,
+        cpu: &mut CPU,
+        breakpoints: &mut Vec<u64>,
+        symbols: &DebugSymbols,
+    ) -> Result<DebugAction, ExecutionError> {
+        if let Some(bp) = self.temporary_breakpoint.take() {
+            breakpoints.retain(|b| *b != bp);
+        }
+        if self.running {
+            self.running = false;
+            self.stopped(self.last_action);
+        }
+        loop {
+            let request = match self.queued.pop_front() {
+                Some(r) => r,
+                None => match self.requests.recv() {
+                    Ok(r) => r,
+                    // nobody is left to resume the guest
+                    Err(_) => return Ok(DebugAction::Quit),
+                },
+            };
+            let arguments = &request["arguments"];
+            match command(&request) {
+                "configurationDone" => {
+                    self.respond(&request, json!({}));
+                    self.configuring = false;
+                    if self.stop_on_entry {
+                        self.stopped("entry");
+                    } else {
+                        return Ok(self.resume("breakpoint", DebugAction::Continue));
+                    }
+                }
+                "threads" => self.respond(
+                    &request,
+                    json!({"threads": [{"id": THREAD_ID, "name": "cpu"}]}),
+                ),
+                "stackTrace" => {
+                    let frames: Vec<Value> = cpu
+                        .backtrace()
+                        .iter()
+                        .enumerate()
+                        .map(|(id, pc)| {
+                            // outer frames hold return addresses, their line is the call's
+                            let line =
+                                symbols.line_of(if id == 0 { *pc } else { pc.saturating_sub(1) });
+                            let mut frame = json!({
+                                "id": id,
+                                "name": symbols.symbolize(*pc),
+                                "line": line.unwrap_or(0),
+                                "column": line.map_or(0, |_| 1),
+                                "instructionPointerReference": format!("{pc:#x}"),
+                            });
+                            if let (Some(path), Some(_)) = (symbols.source(), line) {
+                                frame["source"] = source(path);
+                            }
+                            frame
+                        })
+                        .collect();
+                    let total = frames.len();
+                    self.respond(
+                        &request,
+                        json!({"stackFrames": frames, "totalFrames": total}),
+                    );
+                }
+                "scopes" => self.respond(
+                    &request,
+                    json!({"scopes": [{
+                        "name": "Registers",
+                        "presentationHint": "registers",
+                        "variablesReference": REGISTERS_REFERENCE,
+                        "expensive": false,
+                    }]}),
+                ),
+                "variables" => {
+                    let variables: Vec<Value> =
+                        if arguments["variablesReference"].as_u64() == Some(REGISTERS_REFERENCE) {
+                            (0..16u8)
+                                .map(|reg| {
+                                    let value = cpu.registers.read(reg);
+                                    json!({
+                                        "name": register_name(reg),
+                                        "value": format!("{value:#x}"),
+                                        "variablesReference": 0,
+                                    })
+                                })
+                                .collect()
+                        } else {
+                            Vec::new()
+                        };
+                    self.respond(&request, json!({"variables": variables}));
+                }
+                "evaluate" => {
+                    let expression = arguments["expression"].as_str().unwrap_or("");
+                    match encode_register(expression) {
+                        Some(reg) => {
+                            let value = cpu.registers.read(reg);
+                            self.respond(
+                                &request,
+                                json!({"result": format!("{value:#x}"), "variablesReference": 0}),
+                            );
+                        }
+                        None => self.respond_error(&request, "only registers can be evaluated"),
+                    }
+                }
+                "setBreakpoints" => {
+                    let lines: Vec<u64> = arguments["breakpoints"]
+                        .as_array()
+                        .map(|bps| bps.iter().filter_map(|bp| bp["line"].as_u64()).collect())
+                        .unwrap_or_default();
+                    let path = arguments["source"]["path"].as_str().unwrap_or("");
+                    let is_program_source = symbols.source().is_some_and(|s| same_file(s, path));
+                    if !is_program_source {
+                        let message = match symbols.source() {
+                            Some(_) => "not the program's source file",
+                            None => "no line information, reassemble the program",
+                        };
+                        let unverified: Vec<Value> = lines
+                            .iter()
+                            .map(
+                                |line| json!({"verified": false, "line": line, "message": message}),
+                            )
+                            .collect();
+                        self.respond(&request, json!({"breakpoints": unverified}));
+                        continue;
+                    }
+                    let locations: Vec<Option<(u64, u64)>> = lines
+                        .iter()
+                        .map(|line| symbols.address_of_line(*line))
+                        .collect();
+                    let previous = std::mem::take(&mut self.source_breakpoints);
+                    self.source_breakpoints =
+                        locations.iter().flatten().map(|(addr, _)| *addr).collect();
+                    replace_breakpoints(breakpoints, &previous, &self.source_breakpoints);
+                    let bound: Vec<Value> = locations
+                        .iter()
+                        .map(|location| match location {
+                            Some((addr, line)) => json!({
+                                "verified": true,
+                                "line": line,
+                                "source": source(path),
+                                "instructionReference": format!("{addr:#x}"),
+                            }),
+                            None => json!({"verified": false, "message": "no code at or after this line"}),
+                        })
+                        .collect();
+                    self.respond(&request, json!({"breakpoints": bound}));
+                }
+                "setFunctionBreakpoints" => {
+                    let locations: Vec<Option<u64>> = arguments["breakpoints"]
+                        .as_array()
+                        .map(|bps| {
+                            bps.iter()
+                                .map(|bp| bp["name"].as_str().and_then(|n| symbols.resolve(n)))
+                                .collect()
+                        })
+                        .unwrap_or_default();
+                    let previous = std::mem::take(&mut self.function_breakpoints);
+                    self.function_breakpoints = locations.iter().flatten().copied().collect();
+                    replace_breakpoints(breakpoints, &previous, &self.function_breakpoints);
+                    self.respond(&request, json!({"breakpoints": verified(&locations)}));
+                }
+                "setInstructionBreakpoints" => {
+                    let locations: Vec<Option<u64>> = arguments["breakpoints"]
+                        .as_array()
+                        .map(|bps| {
+                            bps.iter()
+                                .map(|bp| {
+                                    let base = bp["instructionReference"]
+                                        .as_str()
+                                        .and_then(|r| symbols.resolve(r))?;
+                                    let offset = bp["offset"].as_i64().unwrap_or(0);
+                                    Some(base.wrapping_add_signed(offset))
+                                })
+                                .collect()
+                        })
+                        .unwrap_or_default();
+                    let previous = std::mem::take(&mut self.instruction_breakpoints);
+                    self.instruction_breakpoints = locations.iter().flatten().copied().collect();
+                    replace_breakpoints(breakpoints, &previous, &self.instruction_breakpoints);
+                    self.respond(&request, json!({"breakpoints": verified(&locations)}));
+                }
+                "continue" => {
+                    self.respond(&request, json!({"allThreadsContinued": true}));
+                    return Ok(self.resume("breakpoint", DebugAction::Continue));
+                }
+                "next" => {
+                    self.respond(&request, json!({}));
+                    let pc = cpu.registers.read(PROGRAM_COUNTER);
+                    if let Ok((Operation::Call { .. }, len)) = cpu.decode_at(pc) {
+                        // run the whole call and stop once it returns
+                        self.break_once(breakpoints, pc + len);
+                        return Ok(self.resume("step", DebugAction::Continue));
+                    }
+                    return Ok(self.resume("step", DebugAction::Step(1)));
+                }
+                "stepIn" => {
+                    self.respond(&request, json!({}));
+                    return Ok(self.resume("step", DebugAction::Step(1)));
+                }
+                "stepOut" => {
+                    self.respond(&request, json!({}));
+                    if cpu.call_depth() == 0 {
+                        // outermost frame, nothing to return to
+                        return Ok(self.resume("step", DebugAction::Continue));
+                    }
+                    let fp = cpu.registers.read(FRAME_POINTER);
+                    if let Ok(ra) = cpu.memory.read_address(fp + 8) {
+                        self.break_once(breakpoints, ra);
+                    }
+                    return Ok(self.resume("step", DebugAction::Continue));
+                }
+                "pause" => {
+                    self.respond(&request, json!({}));
+                    if !self.configuring {
+                        self.stopped("pause");
+                    }
+                }
+                "disconnect" | "terminate" => {
+                    self.respond(&request, json!({}));
+                    return Ok(DebugAction::Quit);
+                }
+                _ => self.respond_error(&request, "unsupported request"),
+            }
+        }
+    }
+
+    /// drains requests received while running, returns true if the client asked to pause or
+    /// went away, the guest must not keep running without a debugger
+    pub fn interrupt_requested(&mut self) -> bool {
+        loop {
+            match self.requests.try_recv() {
+                Ok(request) => {
+                    if command(&request) == "pause" {
+                        self.respond(&request, json!({}));
+                        self.last_action = "pause";
+                        return true;
+                    }
+                    // anything else is served at the next stop
+                    self.queued.push_back(request);
+                }
+                Err(TryRecvError::Empty) => return false,
+                Err(TryRecvError::Disconnected) => return true,
+            }
+        }
+    }
+
+    pub fn exited(&mut self, status: u64) {
+        self.event("exited", json!({"exitCode": status}));
+        self.event("terminated", json!({}));
+    }
+
+    /// stops at `addr` the next time it is reached unless a breakpoint is already there
+    fn break_once(&mut self, breakpoints: &mut Vec<u64>, addr: u64) {
+        if !breakpoints.contains(&addr) {
+            breakpoints.push(addr);
+            self.temporary_breakpoint = Some(addr);
+        }
+    }
+
+    fn resume(&mut self, reason: &'static str, action: DebugAction) -> DebugAction {
+        self.running = true;
+        self.last_action = reason;
+        action
+    }
+
+    fn stopped(&mut self, reason: &str) {
+        self.event(
+            "stopped",
+            json!({"reason": reason, "threadId": THREAD_ID, "allThreadsStopped": true}),
+        );
+    }
+
+    fn next_request(&mut self) -> Result<Value, ExecutionError> {
+        self.requests
+            .recv()
+            .map_err(|_| ExecutionError::new("dap client disconnected".to_string()))
+    }
+
+    fn respond(&mut self, request: &Value, body: Value) {
+        self.writer.lock().unwrap().send(json!({
+            "type": "response",
+            "request_seq": request["seq"],
+            "command": request["command"],
+            "success": true,
+            "body": body,
+        }));
+    }
+
+    fn respond_error(&mut self, request: &Value, message: &str) {
+        self.writer.lock().unwrap().send(json!({
+            "type": "response",
+            "request_seq": request["seq"],
+            "command": request["command"],
+            "success": false,
+            "message": message,
+        }));
+    }
+
+    fn event(&mut self, event: &str, body: Value) {
+        self.writer
+            .lock()
+            .unwrap()
+            .send(json!({"type": "event", "event": event, "body": body}));
+    }
+}
+
+fn command(request: &Value) -> &str {
+    request["command"].as_str().unwrap_or("")
+}
+
+/// a protocol `Source` for the program's assembly file
+fn source(path: &str) -> Value {
+    let name = Path::new(path)
+        .file_name()
+        .map(|n| n.to_string_lossy().to_string())
+        .unwrap_or(path.to_string());
+    json!({"name": name, "path": path})
+}
+
+/// compares paths the way they resolve on disk when both exist
+fn same_file(a: &str, b: &str) -> bool {
+    match (fs::canonicalize(a), fs::canonicalize(b)) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => a == b,
+    }
+}
+
+fn verified(locations: &[Option<u64>]) -> Vec<Value> {
+    locations
+        .iter()
+        .map(|addr| match addr {
+            Some(addr) => json!({"verified": true, "instructionReference": format!("{addr:#x}")}),
+            None => json!({"verified": false, "message": "unknown label or address"}),
+        })
+        .collect()
+}
+
+fn replace_breakpoints(breakpoints: &mut Vec<u64>, previous: &[u64], new: &[u64]) {
+    breakpoints.retain(|bp| !previous.contains(bp));
+    for bp in new {
+        if !breakpoints.contains(bp) {
+            breakpoints.push(*bp);
+        }
+    }
+}
+
+/// reads one `Content-Length` framed message, returns None on EOF
+fn read_message(reader: &mut impl BufRead) -> Option<Value> {
+    let mut content_length = None;
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header).ok()? == 0 {
+            return None;
+        }
+        let header = header.trim();
+        if header.is_empty() {
+            if content_length.is_some() {
+                break;
+            }
+            continue;
+        }
+        if let Some(len) = header.strip_prefix("Content-Length:") {
+            content_length = len.trim().parse::<usize>().ok();
+        }
+    }
+    let mut body = vec![0; content_length?];
+    reader.read_exact(&mut body).ok()?;
+    serde_json::from_slice(&body).ok()
+}
+
+/// the protocol owns stdout, everything else the vm prints is forwarded as output events.
+/// returns the protocol stream and the read end of the redirected stdout
+fn redirect_stdout() -> Result<(File, File), ExecutionError> {
+    let map_err = |e| ExecutionError::new(format!("failed to redirect stdout: {e}"));
+    let protocol: File = stdout()
+        .as_fd()
+        .try_clone_to_owned()
+        .map_err(map_err)?
+        .into();
+    let mut fds = [0; 2];
+    unsafe {
+        if libc::pipe(fds.as_mut_ptr()) != 0 || libc::dup2(fds[1], libc::STDOUT_FILENO) < 0 {
+            return Err(map_err(std::io::Error::last_os_error()));
+        }
+        libc::close(fds[1]);
+        Ok((protocol, File::from_raw_fd(fds[0])))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::Assembler;
+    use std::io::Cursor;
+
+    /// the protocol output of a session, shared with the server
+    #[derive(Clone, Default)]
+    struct SharedBuf(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuf {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// the requests of a session, then blocks like a client that is still connected until
+    /// `open` is dropped
+    struct HeldOpen {
+        requests: Cursor<Vec<u8>>,
+        open: Receiver<()>,
+    }
+
+    impl Read for HeldOpen {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let n = self.requests.read(buf)?;
+            if n == 0 {
+                let _ = self.open.recv();
+            }
+            Ok(n)
+        }
+    }
+
+    fn frame(message: &Value) -> String {
+        let body = message.to_string();
+        format!("Content-Length: {}\r\n\r\n{body}", body.len())
+    }
+
+    /// runs a session of `requests` and returns every message the server sent, the client
+    /// stays connected until the session ends unless it `hangs_up` after the last request
+    fn session(requests: &[Value], hangs_up: bool) -> Vec<Value> {
+        let input: String = requests
+            .iter()
+            .enumerate()
+            .map(|(seq, request)| {
+                let mut request = request.clone();
+                request["seq"] = json!(seq + 1);
+                request["type"] = json!("request");
+                frame(&request)
+            })
+            .collect();
+        let out = SharedBuf::default();
+        let writer = Arc::new(Mutex::new(DapWriter {
+            out: Box::new(out.clone()),
+            seq: 0,
+        }));
+        let (connected, open) = channel::<()>();
+        let connected = (!hangs_up).then_some(connected);
+        let input = HeldOpen {
+            requests: Cursor::new(input.into_bytes()),
+            open,
+        };
+        serve(BufReader::new(input), writer, Logger::silent()).unwrap();
+        drop(connected);
+        let output = out.0.lock().unwrap().clone();
+        let mut reader = Cursor::new(output);
+        std::iter::from_fn(|| read_message(&mut reader)).collect()
+    }
+
+    fn responses<'a>(messages: &'a [Value], command: &str) -> Vec<&'a Value> {
+        messages
+            .iter()
+            .filter(|m| m["type"] == "response" && m["command"] == command)
+            .collect()
+    }
+
+    /// the source line of each frame
+    fn lines(stack_trace: &Value) -> Vec<u64> {
+        stack_trace["body"]["stackFrames"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|frame| frame["line"].as_u64().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn messages_are_content_length_framed() {
+        let mut input = Cursor::new(
+            b"Content-Type: json\r\nContent-Length: 9\r\n\r\n{\"a\": 1}\n\
+              Content-Length: 2\r\n\r\n[]\
+              Content-Length: 8\r\n\r\n{\"b\""
+                .to_vec(),
+        );
+        assert_eq!(read_message(&mut input), Some(json!({"a": 1})));
+        assert_eq!(read_message(&mut input), Some(json!([])));
+        // a body cut short by the end of the stream
+        assert_eq!(read_message(&mut input), None);
+        assert_eq!(read_message(&mut Cursor::new(Vec::new())), None);
+    }
+
+    #[test]
+    fn session_binds_breakpoints_and_steps() {
+        let base = std::env::temp_dir().join(format!("nisvc-dap-{}", std::process::id()));
+        let source_path = base.with_extension("s");
+        let program_path = base.with_extension("nef");
+        let source = "
+            _start: call $!f
+                    call $!f
+                    ldi r1, $0
+                    push r1
+                    int $x19
+            f:      nop
+                    ret
+        ";
+        std::fs::write(&source_path, source).unwrap();
+        let source_path = source_path.to_str().unwrap();
+        let binary = Assembler::assemble_named(source, source_path)
+            .unwrap_or_else(|e| panic!("{}", e.error));
+        std::fs::write(&program_path, binary).unwrap();
+
+        let messages = session(
+            &[
+                json!({"command": "initialize", "arguments": {}}),
+                json!({"command": "launch", "arguments": {
+                    "program": program_path.to_str().unwrap(),
+                    "stopOnEntry": true,
+                    "coreDumps": false,
+                    "clockspeed": 1e9,
+                }}),
+                json!({"command": "setBreakpoints", "arguments": {
+                    "source": {"path": source_path},
+                    "breakpoints": [{"line": 7}, {"line": 6}, {"line": 9}],
+                }}),
+                json!({"command": "setBreakpoints", "arguments": {
+                    "source": {"path": "/elsewhere.s"},
+                    "breakpoints": [{"line": 1}],
+                }}),
+                json!({"command": "setFunctionBreakpoints", "arguments": {
+                    "breakpoints": [{"name": "f"}, {"name": "nowhere"}],
+                }}),
+                json!({"command": "configurationDone"}),
+                json!({"command": "stackTrace", "arguments": {"threadId": THREAD_ID}}),
+                json!({"command": "stepIn", "arguments": {"threadId": THREAD_ID}}),
+                json!({"command": "stackTrace", "arguments": {"threadId": THREAD_ID}}),
+                json!({"command": "stepOut", "arguments": {"threadId": THREAD_ID}}),
+                json!({"command": "setFunctionBreakpoints", "arguments": {"breakpoints": []}}),
+                json!({"command": "setBreakpoints", "arguments": {
+                    "source": {"path": source_path},
+                    "breakpoints": [{"line": 6}],
+                }}),
+                json!({"command": "next", "arguments": {"threadId": THREAD_ID}}),
+                json!({"command": "stackTrace", "arguments": {"threadId": THREAD_ID}}),
+                json!({"command": "continue", "arguments": {"threadId": THREAD_ID}}),
+                json!({"command": "stackTrace", "arguments": {"threadId": THREAD_ID}}),
+                json!({"command": "continue", "arguments": {"threadId": THREAD_ID}}),
+            ],
+            false,
+        );
+        let _ = std::fs::remove_file(source_path);
+        let _ = std::fs::remove_file(&program_path);
+
+        let set_breakpoints = responses(&messages, "setBreakpoints");
+        let bound = &set_breakpoints[0]["body"]["breakpoints"];
+        assert_eq!(bound[0]["verified"], true);
+        // `f` follows two calls, an ldi, a push and an int
+        assert_eq!(bound[0]["instructionReference"], "0x27");
+        assert_eq!(bound[1]["line"], 6);
+        assert_eq!(bound[2]["verified"], false);
+        let elsewhere = &set_breakpoints[1]["body"]["breakpoints"];
+        assert_eq!(elsewhere[0]["verified"], false);
+
+        let function = &responses(&messages, "setFunctionBreakpoints")[0]["body"]["breakpoints"];
+        assert_eq!(function[0]["verified"], true);
+        assert_eq!(function[1]["verified"], false);
+
+        let stops: Vec<&Value> = messages
+            .iter()
+            .filter(|m| m["event"] == "stopped")
+            .map(|m| &m["body"]["reason"])
+            .collect();
+        assert_eq!(stops, ["entry", "step", "step", "step", "breakpoint"]);
+
+        let traces = responses(&messages, "stackTrace");
+        assert_eq!(lines(traces[0]), [2]);
+        let frame = &traces[0]["body"]["stackFrames"][0];
+        assert_eq!(frame["source"]["path"], source_path);
+        // stepped into `f`, the caller's frame shows the call
+        assert_eq!(lines(traces[1]), [7, 2]);
+        // `next` ran the second call to `f` without stopping inside it
+        assert_eq!(lines(traces[2]), [4]);
+        assert_eq!(lines(traces[3]), [6]);
+
+        let exited = messages.iter().find(|m| m["event"] == "exited").unwrap();
+        assert_eq!(exited["body"]["exitCode"], 0);
+    }
+
+    #[test]
+    fn hanging_up_stops_a_running_guest() {
+        let program_path =
+            std::env::temp_dir().join(format!("nisvc-dap-hangup-{}.nef", std::process::id()));
+        let binary =
+            Assembler::assemble("spin: jmp $!spin").unwrap_or_else(|e| panic!("{}", e.error));
+        std::fs::write(&program_path, binary).unwrap();
+        let messages = session(
+            &[
+                json!({"command": "initialize", "arguments": {}}),
+                json!({"command": "launch", "arguments": {
+                    "program": program_path.to_str().unwrap(),
+                    "coreDumps": false,
+                    "clockspeed": 1e9,
+                }}),
+                json!({"command": "configurationDone"}),
+            ],
+            true,
+        );
+        let _ = std::fs::remove_file(&program_path);
+        assert!(messages.iter().any(|m| m["event"] == "terminated"));
+    }
+}
@@ -1,6 +1,33 @@
-use rustyline::{self, history::FileHistory, DefaultEditor, Editor};
+use std::path::Path;
+
+use crossterm::style::Stylize;
+use rustyline::{self, error::ReadlineError, history::FileHistory, DefaultEditor, Editor};
+
+use crate::{
+    constant::PROGRAM_COUNTER,
+    cpu::{encode_register, CPU},
+    debugger::DebugAction,
+    gpu::GPU,
+    loader::{parse_number, DebugSymbols},
+    ExecutionError,
+};
+
+const HELP: &str = "\
+commands:
+  step [n]            | s    execute n instructions (default 1)
+  continue            | c    resume execution until the next breakpoint
+  break <addr|label>  | b    set a breakpoint at addr or label
+  delete <addr|label> | d    clear the breakpoint at addr or label
+  breakpoints         | bl   list breakpoints
+  registers           | r    print all registers
+  print <reg>..       | p    print registers by name (eg `r1b2`, `pc`)
+  set <reg> <value>          write value into a register
+  examine <addr> [n]  | x    dump n bytes of memory starting at addr (default 16)
+  write <addr> <b>..  | w    write bytes into memory starting at addr
+  frame <path>        | f    write the last drawn frame to a .png or .ppm file
+  help                | h    print this message
+  quit                | q    stop execution";
 
-use crate::ExecutionError;
 pub struct Shell {
     readline: Editor<(), FileHistory>,
 }
@@ -12,14 +39,265 @@ impl Shell {
         Ok(Self { readline })
     }
 
-    pub fn enter() -> Result<(), ExecutionError> {
-        todo!()
+    /// runs the REPL until a command resumes or stops execution
+    pub fn enter(
+        &mut self,
+        cpu: &mut CPU,
+        breakpoints: &mut Vec<u64>,
+        symbols: &DebugSymbols,
+        gpu: Option<&GPU>,
+    ) -> Result<DebugAction, ExecutionError> {
+        let pc = cpu.registers.read(PROGRAM_COUNTER);
+        println!(
+            "{} @ {pc:#x} {}",
+            "stopped".on_blue(),
+            symbols.symbolize(pc)
+        );
+        loop {
+            let cmdline = match self.prompt()? {
+                Some(l) => l,
+                None => return Ok(DebugAction::Quit),
+            };
+            match self.exec_cmdline(&cmdline, cpu, breakpoints, symbols, gpu) {
+                Ok(Some(action)) => return Ok(action),
+                Ok(None) => continue,
+                Err(e) => println!("{e}"),
+            }
+        }
+    }
+
+    /// returns None on EOF
+    fn prompt(&mut self) -> Result<Option<String>, ExecutionError> {
+        match self.readline.readline("(nisvc-dbg) ") {
+            Ok(line) => {
+                let _ = self.readline.add_history_entry(line.as_str());
+                Ok(Some(line))
+            }
+            Err(ReadlineError::Interrupted) => Ok(Some(String::new())),
+            Err(ReadlineError::Eof) => Ok(None),
+            Err(e) => Err(ExecutionError::new(format!("debug shell read error: {e}"))),
+        }
+    }
+
+    fn exec_cmdline(
+        &mut self,
+        cmdline: &str,
+        cpu: &mut CPU,
+        breakpoints: &mut Vec<u64>,
+        symbols: &DebugSymbols,
+        gpu: Option<&GPU>,
+    ) -> Result<Option<DebugAction>, ExecutionError> {
+        let mut words = cmdline.split_whitespace();
+        let cmd = match words.next() {
+            Some(c) => c,
+            None => return Ok(None),
+        };
+        let args: Vec<&str> = words.collect();
+        match cmd {
+            "step" | "s" => {
+                let n = match args.first() {
+                    Some(n) => parse_value(n)? as usize,
+                    None => 1,
+                };
+                return Ok(Some(DebugAction::Step(n)));
+            }
+            "continue" | "c" => return Ok(Some(DebugAction::Continue)),
+            "quit" | "q" => return Ok(Some(DebugAction::Quit)),
+            "break" | "b" => {
+                let addr = parse_location(nth_arg(&args, 0, "address")?, symbols)?;
+                if !breakpoints.contains(&addr) {
+                    breakpoints.push(addr);
+                }
+                println!("breakpoint set @ {addr:#x}");
+            }
+            "delete" | "d" => {
+                let addr = parse_location(nth_arg(&args, 0, "address")?, symbols)?;
+                let len = breakpoints.len();
+                breakpoints.retain(|bp| *bp != addr);
+                if breakpoints.len() == len {
+                    return Err(ExecutionError::new(format!("no breakpoint @ {addr:#x}")));
+                }
+                println!("breakpoint cleared @ {addr:#x}");
+            }
+            "breakpoints" | "bl" => {
+                for (i, bp) in breakpoints.iter().enumerate() {
+                    println!("{i}: {bp:#x}");
+                }
+            }
+            "registers" | "r" => println!("{}", cpu.registers.print_all()),
+            "print" | "p" => {
+                nth_arg(&args, 0, "register")?;
+                for name in &args {
+                    let handle = parse_register(name)?;
+                    println!("{}", cpu.registers.print(handle));
+                }
+            }
+            "set" => {
+                let handle = parse_register(nth_arg(&args, 0, "register")?)?;
+                let value = parse_value(nth_arg(&args, 1, "value")?)?;
+                cpu.registers.write(handle, value);
+                println!("{}", cpu.registers.print(handle));
+            }
+            "examine" | "x" => {
+                let addr = parse_location(nth_arg(&args, 0, "address")?, symbols)?;
+                let n = match args.get(1) {
+                    Some(n) => parse_value(n)?,
+                    None => 16,
+                };
+                let bytes = cpu.memory.read(addr, n)?;
+                for (i, row) in bytes.chunks(16).enumerate() {
+                    let hex = row
+                        .iter()
+                        .map(|b| format!("{b:0>2x}"))
+                        .collect::<Vec<String>>()
+                        .join(" ");
+                    let ascii: String = row
+                        .iter()
+                        .map(|b| {
+                            if b.is_ascii_graphic() || *b == b' ' {
+                                *b as char
+                            } else {
+                                '.'
+                            }
+                        })
+                        .collect();
+                    println!("{:0>8x}: {hex:<48} {ascii}", addr + i as u64 * 16);
+                }
+            }
+            "write" | "w" => {
+                let addr = parse_location(nth_arg(&args, 0, "address")?, symbols)?;
+                nth_arg(&args, 1, "byte")?;
+                let bytes = args[1..]
+                    .iter()
+                    .map(|b| parse_byte(b))
+                    .collect::<Result<Vec<u8>, ExecutionError>>()?;
+                let n = cpu.memory.write(addr, &bytes)?;
+                println!("wrote {n} bytes @ {addr:#x}");
+            }
+            "frame" | "f" => {
+                let path = nth_arg(&args, 0, "path")?;
+                let gpu = gpu.ok_or(ExecutionError::new(
+                    "no frame buffer, the program has not called init_fb".to_string(),
+                ))?;
+                gpu.capture(Path::new(path))?;
+                println!("wrote {path} ({} frames drawn)", gpu.frames_drawn());
+            }
+            "help" | "h" => println!("{HELP}"),
+            _ => {
+                return Err(ExecutionError::new(format!(
+                    "unknown command `{cmd}`, try `help`"
+                )))
+            }
+        }
+        Ok(None)
+    }
+}
+
+fn nth_arg<'a>(args: &[&'a str], n: usize, name: &str) -> Result<&'a str, ExecutionError> {
+    args.get(n)
+        .copied()
+        .ok_or(ExecutionError::new(format!("missing argument <{name}>")))
+}
+
+fn parse_register(name: &str) -> Result<u8, ExecutionError> {
+    encode_register(name).ok_or(ExecutionError::new(format!("unknown register `{name}`")))
+}
+
+/// parses an address or label
+fn parse_location(s: &str, symbols: &DebugSymbols) -> Result<u64, ExecutionError> {
+    symbols.resolve(s).ok_or(ExecutionError::new(format!(
+        "invalid location `{s}`: not an address or known label"
+    )))
+}
+
+fn parse_value(s: &str) -> Result<u64, ExecutionError> {
+    parse_number(s).map_err(|e| ExecutionError::new(format!("invalid value `{s}`: {e}")))
+}
+
+fn parse_byte(s: &str) -> Result<u8, ExecutionError> {
+    u8::try_from(parse_value(s)?)
+        .map_err(|_| ExecutionError::new(format!("invalid byte `{s}`: does not fit in a byte")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{assembler::Assembler, logger::Logger};
+
+    /// a cpu running `main: nop` followed by `loop: nop`, and its symbols
+    fn machine() -> (CPU, DebugSymbols) {
+        let program = Assembler::assemble("main: nop\nloop: nop\n.bytes $0, $0, $0, $0")
+            .unwrap_or_else(|e| panic!("{}", e.error));
+        let mut cpu = CPU::new(0x100, 0x100, Logger::silent());
+        let nef = cpu.load_executable(program).unwrap();
+        (cpu, nef.debug_symbols)
+    }
+
+    fn exec(
+        cmdline: &str,
+        cpu: &mut CPU,
+        breakpoints: &mut Vec<u64>,
+        symbols: &DebugSymbols,
+    ) -> Result<Option<DebugAction>, ExecutionError> {
+        Shell::new()
+            .unwrap()
+            .exec_cmdline(cmdline, cpu, breakpoints, symbols, None)
+    }
+
+    #[test]
+    fn step_continue_and_quit_resume() {
+        let (mut cpu, symbols) = machine();
+        let mut bps = Vec::new();
+        let mut run = |cmdline: &str| exec(cmdline, &mut cpu, &mut bps, &symbols);
+        assert!(matches!(run("s"), Ok(Some(DebugAction::Step(1)))));
+        assert!(matches!(run("step 0x10"), Ok(Some(DebugAction::Step(16)))));
+        assert!(run("step many").is_err());
+        assert!(matches!(run("c"), Ok(Some(DebugAction::Continue))));
+        assert!(matches!(run("quit"), Ok(Some(DebugAction::Quit))));
+        assert!(matches!(run("   "), Ok(None)));
+        assert!(run("jump").is_err());
     }
 
-    fn prompt() -> Result<String, ExecutionError> {
-        todo!()
+    #[test]
+    fn breakpoints_are_set_by_address_or_label_and_deleted() {
+        let (mut cpu, symbols) = machine();
+        let mut bps = Vec::new();
+        exec("b loop", &mut cpu, &mut bps, &symbols).unwrap();
+        exec("break 1", &mut cpu, &mut bps, &symbols).unwrap();
+        exec("b 0x10", &mut cpu, &mut bps, &symbols).unwrap();
+        assert_eq!(bps, [1, 0x10]);
+        assert!(exec("b nowhere", &mut cpu, &mut bps, &symbols).is_err());
+        exec("d loop", &mut cpu, &mut bps, &symbols).unwrap();
+        assert_eq!(bps, [0x10]);
+        assert!(exec("delete loop", &mut cpu, &mut bps, &symbols).is_err());
+        assert!(exec("d", &mut cpu, &mut bps, &symbols).is_err());
     }
-    fn exec_cmdline() -> Result<String, ExecutionError> {
-        todo!()
+
+    #[test]
+    fn set_writes_registers_and_their_windows() {
+        let (mut cpu, symbols) = machine();
+        let mut bps = Vec::new();
+        exec("set r1 10", &mut cpu, &mut bps, &symbols).unwrap();
+        assert_eq!(cpu.registers.read(encode_register("r1").unwrap()), 10);
+        exec("set r2b1 0xab", &mut cpu, &mut bps, &symbols).unwrap();
+        assert_eq!(cpu.registers.read(encode_register("r2b1").unwrap()), 0xab);
+        exec("p r1 r2b1 pc", &mut cpu, &mut bps, &symbols).unwrap();
+        assert!(exec("set r99 1", &mut cpu, &mut bps, &symbols).is_err());
+        assert!(exec("set r1", &mut cpu, &mut bps, &symbols).is_err());
+        assert!(exec("p", &mut cpu, &mut bps, &symbols).is_err());
+    }
+
+    #[test]
+    fn write_rejects_values_above_a_byte() {
+        let (mut cpu, symbols) = machine();
+        let mut bps = Vec::new();
+        exec("w loop 0xff 7", &mut cpu, &mut bps, &symbols).unwrap();
+        assert_eq!(cpu.memory.read(1, 2).unwrap(), [0xff, 7]);
+        assert!(exec("write 2 0x1ff", &mut cpu, &mut bps, &symbols).is_err());
+        assert!(exec("w 2 1 256", &mut cpu, &mut bps, &symbols).is_err());
+        assert_eq!(cpu.memory.read(2, 2).unwrap(), [7, 0]);
+        exec("x main 4", &mut cpu, &mut bps, &symbols).unwrap();
+        assert!(exec("x", &mut cpu, &mut bps, &symbols).is_err());
+        assert!(exec("w 2", &mut cpu, &mut bps, &symbols).is_err());
     }
 }
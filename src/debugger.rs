@@ -0,0 +1,56 @@
+use crate::{
+    cpu::CPU, dap::DapServer, debug_shell::Shell, gdb::GdbStub, gpu::GPU, loader::DebugSymbols,
+    ExecutionError,
+};
+
+/// what the kernel should do once a debugger hands control back
+pub enum DebugAction {
+    /// execute n instructions then re-enter the debugger
+    Step(usize),
+    /// run until a breakpoint is hit
+    Continue,
+    /// stop execution
+    Quit,
+}
+
+/// debugger frontends the kernel can hand control to when execution pauses
+pub enum Debugger {
+    Shell(Box<Shell>),
+    Gdb(GdbStub),
+    Dap(DapServer),
+}
+
+impl Debugger {
+    /// called whenever execution pauses, blocks until the frontend resumes or stops execution
+    pub fn enter(
+        &mut self,
+        cpu: &mut CPU,
+        breakpoints: &mut Vec<u64>,
+        symbols: &DebugSymbols,
+        gpu: Option<&GPU>,
+    ) -> Result<DebugAction, ExecutionError> {
+        match self {
+            Debugger::Shell(shell) => shell.enter(cpu, breakpoints, symbols, gpu),
+            Debugger::Gdb(gdb) => gdb.enter(cpu, breakpoints),
+            Debugger::Dap(dap) => dap.enter(cpu, breakpoints, symbols),
+        }
+    }
+
+    /// polled every cycle while running, returns true if the frontend requested a pause
+    pub fn interrupt_requested(&mut self) -> bool {
+        match self {
+            Debugger::Shell(_) => false,
+            Debugger::Gdb(gdb) => gdb.interrupt_requested(),
+            Debugger::Dap(dap) => dap.interrupt_requested(),
+        }
+    }
+
+    /// called once the guest has stopped running
+    pub fn exited(&mut self, status: u64) {
+        match self {
+            Debugger::Shell(_) => (),
+            Debugger::Gdb(gdb) => gdb.exited(status),
+            Debugger::Dap(dap) => dap.exited(status),
+        }
+    }
+}
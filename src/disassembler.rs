@@ -0,0 +1,167 @@
+use std::{collections::BTreeMap, fs};
+
+use crate::{
+    loader::{DebugSymbols, NISVCEF},
+    opcode::{ImageCursor, Operation},
+    ExecutionError,
+};
+
+/// instruction decoded at an address, or the error that stopped decoding there
+struct Decoded {
+    len: usize,
+    operation: Result<Operation, ExecutionError>,
+}
+
+fn decode_at(image: &[u8], addr: usize) -> Decoded {
+    let mut cursor = ImageCursor {
+        image,
+        offset: addr,
+    };
+    match Operation::decode(&mut cursor) {
+        Ok(operation) => Decoded {
+            len: cursor.offset - addr,
+            operation: Ok(operation),
+        },
+        Err(e) => Decoded {
+            len: 1,
+            operation: Err(e),
+        },
+    }
+}
+
+/// decodes every instruction back to back from the start of the image, resyncing at labels
+fn linear_sweep(image: &[u8], symbols: &DebugSymbols) -> BTreeMap<usize, Decoded> {
+    let mut decoded = BTreeMap::new();
+    let mut addr = 0;
+    while addr < image.len() {
+        let mut instruction = decode_at(image, addr);
+        let last = (addr + instruction.len - 1) as u64;
+        if let Some((label, offset)) = symbols.nearest_label(last) {
+            if last - offset > addr as u64 {
+                // a label inside the instruction means this was data, not code
+                instruction = Decoded {
+                    len: 1,
+                    operation: Err(ExecutionError::new(format!("overlaps label `{label}`"))),
+                };
+            }
+        }
+        let len = instruction.len;
+        decoded.insert(addr, instruction);
+        addr += len;
+    }
+    decoded
+}
+
+/// decodes only code reachable from the entry point by following jump and call targets
+fn recursive_sweep(image: &[u8], entry_point: u64) -> BTreeMap<usize, Decoded> {
+    let mut decoded = BTreeMap::new();
+    let mut pending = vec![entry_point as usize];
+    while let Some(addr) = pending.pop() {
+        if addr >= image.len() || decoded.contains_key(&addr) {
+            continue;
+        }
+        let instruction = decode_at(image, addr);
+        let next = addr + instruction.len;
+        match &instruction.operation {
+            Ok(Operation::Jmp { addr }) => pending.push(*addr as usize),
+            Ok(Operation::Jifz { addr, .. })
+            | Ok(Operation::Jifnz { addr, .. })
+            | Ok(Operation::Call { addr }) => {
+                pending.push(*addr as usize);
+                pending.push(next);
+            }
+            Ok(Operation::Ret) | Ok(Operation::Iret) | Ok(Operation::HaltExe) | Err(_) => (),
+            Ok(_) => pending.push(next),
+        }
+        decoded.insert(addr, instruction);
+    }
+    decoded
+}
+
+/// prints the disassembly of a NEF executable without running it
+pub fn run(path: &str, recursive: bool) -> Result<(), ExecutionError> {
+    let file =
+        fs::read(path).map_err(|e| ExecutionError::new(format!("failed to read {path}: {e}")))?;
+    let nef = NISVCEF::load(file)?;
+    let decoded = if recursive {
+        recursive_sweep(&nef.image, nef.entry_point)
+    } else {
+        linear_sweep(&nef.image, &nef.debug_symbols)
+    };
+    print!("{}", render(&nef.image, &decoded, &nef.debug_symbols));
+    Ok(())
+}
+
+fn render(image: &[u8], decoded: &BTreeMap<usize, Decoded>, symbols: &DebugSymbols) -> String {
+    let mut out = String::new();
+    let mut expected = 0;
+    for (addr, instruction) in decoded {
+        if *addr != expected {
+            out.push_str("        ...\n");
+        }
+        expected = addr + instruction.len;
+        if let Some(label) = symbols.label_at(*addr as u64) {
+            out.push_str(&format!("{label}:\n"));
+        }
+        let raw = image[*addr..expected]
+            .iter()
+            .map(|b| format!("{b:0>2x}"))
+            .collect::<Vec<String>>()
+            .join(" ");
+        let text = match &instruction.operation {
+            Ok(operation) => operation.disassemble(symbols),
+            Err(e) => format!(".bytes $x{:0>2x}  # {}", image[*addr], e.error),
+        };
+        out.push_str(&format!("{addr:0>8x}: {raw:<30} {text}\n"));
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::Assembler;
+
+    const PROGRAM: &str = "
+        _start: call $!f
+                haltexe
+        data:   .bytes $xff, $xfe
+        f:      ret
+    ";
+
+    fn program() -> NISVCEF {
+        let binary = Assembler::assemble(PROGRAM).unwrap_or_else(|e| panic!("{}", e.error));
+        NISVCEF::load(binary).unwrap_or_else(|e| panic!("{}", e.error))
+    }
+
+    #[test]
+    fn linear_sweep_decodes_data_as_bytes() {
+        let nef = program();
+        let decoded = linear_sweep(&nef.image, &nef.debug_symbols);
+        assert_eq!(
+            decoded.keys().copied().collect::<Vec<usize>>(),
+            [0x00, 0x09, 0x0a, 0x0b, 0x0c]
+        );
+        assert!(decoded[&0x0a].operation.is_err());
+        // 0xfe happens to be haltexe
+        assert!(matches!(decoded[&0x0b].operation, Ok(Operation::HaltExe)));
+    }
+
+    #[test]
+    fn recursive_sweep_skips_data() {
+        let nef = program();
+        let decoded = recursive_sweep(&nef.image, nef.entry_point);
+        let expected = [
+            "_start:",
+            "00000000: 1a 0c 00 00 00 00 00 00 00     call $!f",
+            "00000009: fe                             haltexe",
+            "        ...",
+            "f:",
+            "0000000c: 1b                             ret",
+        ];
+        assert_eq!(
+            render(&nef.image, &decoded, &nef.debug_symbols),
+            expected.join("\n") + "\n"
+        );
+    }
+}
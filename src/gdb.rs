@@ -0,0 +1,605 @@
+use std::{
+    collections::VecDeque,
+    io::{ErrorKind, Read, Write},
+    net::{TcpListener, TcpStream},
+    os::unix::net::{UnixListener, UnixStream},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    constant::PROGRAM_COUNTER, cpu::CPU, debugger::DebugAction, kernel_log, logger::Logger,
+    ExecutionError,
+};
+
+const REGISTER_COUNT: u8 = 16;
+const PACKET_SIZE: usize = 0x4000;
+const INTERRUPT: u8 = 0x03;
+/// time between polls of the client for an interrupt while the guest runs
+const INTERRUPT_POLL_INTERVAL: Duration = Duration::from_millis(50);
+/// SIGTRAP, reported for every stop
+const STOP_REPLY: &str = "S05";
+
+/// gdb has no nisvc architecture, so the description declares none and gdb keeps the one it
+/// was built for, its disassembly is meaningless. the register file is the 16 little endian
+/// 64 bit registers below in order, register packets are refused until a client read this
+/// description since one laying them out by its own architecture would misread them
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <feature name="org.nisvc.core">
+    <reg name="null" bitsize="64" type="uint64" regnum="0"/>
+    <reg name="pc" bitsize="64" type="code_ptr"/>
+    <reg name="sp" bitsize="64" type="data_ptr"/>
+    <reg name="fp" bitsize="64" type="data_ptr"/>
+    <reg name="r1" bitsize="64" type="uint64"/>
+    <reg name="r2" bitsize="64" type="uint64"/>
+    <reg name="r3" bitsize="64" type="uint64"/>
+    <reg name="r4" bitsize="64" type="uint64"/>
+    <reg name="r5" bitsize="64" type="uint64"/>
+    <reg name="r6" bitsize="64" type="uint64"/>
+    <reg name="r7" bitsize="64" type="uint64"/>
+    <reg name="r8" bitsize="64" type="uint64"/>
+    <reg name="r9" bitsize="64" type="uint64"/>
+    <reg name="r10" bitsize="64" type="uint64"/>
+    <reg name="r11" bitsize="64" type="uint64"/>
+    <reg name="r12" bitsize="64" type="uint64"/>
+  </feature>
+</target>
+"#;
+
+enum GdbStream {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+impl GdbStream {
+    fn set_nonblocking(&self, nonblocking: bool) -> std::io::Result<()> {
+        match self {
+            GdbStream::Tcp(s) => s.set_nonblocking(nonblocking),
+            GdbStream::Unix(s) => s.set_nonblocking(nonblocking),
+        }
+    }
+}
+
+impl Read for GdbStream {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            GdbStream::Tcp(s) => s.read(buf),
+            GdbStream::Unix(s) => s.read(buf),
+        }
+    }
+}
+
+impl Write for GdbStream {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            GdbStream::Tcp(s) => s.write(buf),
+            GdbStream::Unix(s) => s.write(buf),
+        }
+    }
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            GdbStream::Tcp(s) => s.flush(),
+            GdbStream::Unix(s) => s.flush(),
+        }
+    }
+}
+
+/// GDB remote serial protocol stub
+pub struct GdbStub {
+    stream: GdbStream,
+    /// set once execution was resumed, the next stop must be reported to the client
+    running: bool,
+    /// bytes read while polling for an interrupt, the start of the next packet
+    pending: VecDeque<u8>,
+    last_poll: Instant,
+    /// set once the client read the target description
+    described: bool,
+    /// breakpoints the client inserted, removed again when it detaches
+    inserted: Vec<u64>,
+    /// set once the client detached or went away, the guest then runs on undisturbed
+    detached: bool,
+    log: Logger,
+}
+
+impl GdbStub {
+    /// waits for a client on a tcp port (`1234`, `host:1234`) or a unix socket path
+    pub fn listen(address: &str, log: Logger) -> Result<Self, ExecutionError> {
+        let map_err = |e| ExecutionError::new(format!("gdb stub failed on `{address}`: {e}"));
+        let stream = if let Ok(port) = address.parse::<u16>() {
+            println!("waiting for gdb on 127.0.0.1:{port}");
+            let (stream, _) = TcpListener::bind(("127.0.0.1", port))
+                .and_then(|l| l.accept())
+                .map_err(map_err)?;
+            stream.set_nodelay(true).map_err(map_err)?;
+            GdbStream::Tcp(stream)
+        } else if address.contains(':') {
+            println!("waiting for gdb on {address}");
+            let (stream, _) = TcpListener::bind(address)
+                .and_then(|l| l.accept())
+                .map_err(map_err)?;
+            stream.set_nodelay(true).map_err(map_err)?;
+            GdbStream::Tcp(stream)
+        } else {
+            println!("waiting for gdb on unix socket {address}");
+            let _ = std::fs::remove_file(address);
+            let (stream, _) = UnixListener::bind(address)
+                .and_then(|l| l.accept())
+                .map_err(map_err)?;
+            GdbStream::Unix(stream)
+        };
+        println!("gdb attached");
+        Ok(Self::new(stream, log))
+    }
+
+    fn new(stream: GdbStream, log: Logger) -> Self {
+        Self {
+            stream,
+            running: false,
+            pending: VecDeque::new(),
+            last_poll: Instant::now(),
+            described: false,
+            inserted: Vec::new(),
+            detached: false,
+            log,
+        }
+    }
+
+    /// serves packets until the client resumes execution
+    pub fn enter(
+        &mut self,
+        cpu: &mut CPU,
+        breakpoints: &mut Vec<u64>,
+    ) -> Result<DebugAction, ExecutionError> {
+        if self.detached {
+            return Ok(DebugAction::Continue);
+        }
+        if self.running {
+            self.running = false;
+            self.send_packet(STOP_REPLY)?;
+        }
+        loop {
+            let packet = match self.receive_packet()? {
+                Some(p) => p,
+                // client detached
+                None => {
+                    self.detach(breakpoints);
+                    return Ok(DebugAction::Continue);
+                }
+            };
+            kernel_log!(self.log, "gdb <- {packet}");
+            let reply = match packet.as_bytes().first() {
+                // see TARGET_XML
+                Some(b'g' | b'G' | b'p' | b'P') if !self.described => "E02".to_string(),
+                Some(b'?') => STOP_REPLY.to_string(),
+                Some(b'g') => {
+                    let mut reply = String::new();
+                    for reg in 0..REGISTER_COUNT {
+                        reply += &encode_hex(&cpu.registers.read(reg).to_le_bytes());
+                    }
+                    reply
+                }
+                Some(b'G') => match decode_hex(&packet[1..]) {
+                    Some(bytes) if bytes.len() == REGISTER_COUNT as usize * 8 => {
+                        for (reg, value) in bytes.chunks_exact(8).enumerate() {
+                            cpu.registers.write(reg as u8, le_u64(value));
+                        }
+                        "OK".to_string()
+                    }
+                    _ => "E01".to_string(),
+                },
+                Some(b'p') => match u8::from_str_radix(&packet[1..], 16) {
+                    Ok(reg) if reg < REGISTER_COUNT => {
+                        encode_hex(&cpu.registers.read(reg).to_le_bytes())
+                    }
+                    _ => "E01".to_string(),
+                },
+                Some(b'P') => {
+                    let parsed = packet[1..].split_once('=').and_then(|(reg, value)| {
+                        Some((u8::from_str_radix(reg, 16).ok()?, decode_hex(value)?))
+                    });
+                    match parsed {
+                        Some((reg, value)) if reg < REGISTER_COUNT && value.len() == 8 => {
+                            cpu.registers.write(reg, le_u64(&value));
+                            "OK".to_string()
+                        }
+                        _ => "E01".to_string(),
+                    }
+                }
+                Some(b'm') => match parse_addr_len(&packet[1..]) {
+                    Some((addr, len)) => match cpu.memory.read(addr, len) {
+                        Ok(bytes) => encode_hex(&bytes),
+                        Err(_) => "E14".to_string(),
+                    },
+                    None => "E01".to_string(),
+                },
+                Some(b'M') => {
+                    let parsed = packet[1..].split_once(':').and_then(|(range, data)| {
+                        Some((parse_addr_len(range)?, decode_hex(data)?))
+                    });
+                    match parsed {
+                        Some(((addr, len), data)) if data.len() as u64 == len => {
+                            match cpu.memory.write(addr, &data) {
+                                Ok(_) => "OK".to_string(),
+                                Err(_) => "E14".to_string(),
+                            }
+                        }
+                        _ => "E01".to_string(),
+                    }
+                }
+                Some(b'c') => {
+                    self.resume(cpu, &packet[1..]);
+                    return Ok(DebugAction::Continue);
+                }
+                Some(b's') => {
+                    self.resume(cpu, &packet[1..]);
+                    return Ok(DebugAction::Step(1));
+                }
+                Some(b'Z') | Some(b'z') => {
+                    let insert = packet.starts_with('Z');
+                    let mut fields = packet[1..].split(',');
+                    let kind = fields.next();
+                    let addr = fields.next().and_then(|a| u64::from_str_radix(a, 16).ok());
+                    match (kind, addr) {
+                        (Some("0"), Some(addr)) => {
+                            if insert {
+                                if !breakpoints.contains(&addr) {
+                                    breakpoints.push(addr);
+                                    self.inserted.push(addr);
+                                }
+                            } else {
+                                breakpoints.retain(|bp| *bp != addr);
+                                self.inserted.retain(|bp| *bp != addr);
+                            }
+                            "OK".to_string()
+                        }
+                        // hardware breakpoints and watchpoints are unsupported
+                        _ => String::new(),
+                    }
+                }
+                Some(b'k') => {
+                    self.detach(breakpoints);
+                    return Ok(DebugAction::Quit);
+                }
+                Some(b'D') => {
+                    self.send_packet("OK")?;
+                    self.detach(breakpoints);
+                    return Ok(DebugAction::Continue);
+                }
+                Some(b'H') => "OK".to_string(),
+                Some(b'q') => self.query(&packet),
+                _ => String::new(),
+            };
+            self.send_packet(&reply)?;
+        }
+    }
+
+    /// polls the client for an out of band interrupt (^C) at most every `INTERRUPT_POLL_INTERVAL`
+    pub fn interrupt_requested(&mut self) -> bool {
+        if self.detached || self.last_poll.elapsed() < INTERRUPT_POLL_INTERVAL {
+            return false;
+        }
+        self.last_poll = Instant::now();
+        self.poll_interrupt()
+    }
+
+    /// reads whatever the client sent without blocking, keeping all but interrupts for
+    /// `receive_packet`
+    fn poll_interrupt(&mut self) -> bool {
+        let mut buf = [0u8; 64];
+        if self.stream.set_nonblocking(true).is_err() {
+            return false;
+        }
+        let read = self.stream.read(&mut buf).unwrap_or(0);
+        let _ = self.stream.set_nonblocking(false);
+        let mut interrupted = false;
+        for &byte in &buf[..read] {
+            if byte == INTERRUPT {
+                interrupted = true;
+            } else {
+                self.pending.push_back(byte);
+            }
+        }
+        interrupted
+    }
+
+    /// reports the guest's exit status to the client
+    pub fn exited(&mut self, status: u64) {
+        let _ = self.send_packet(&format!("W{:0>2x}", status as u8));
+    }
+
+    /// removes the client's breakpoints, those set on the command line stay
+    fn detach(&mut self, breakpoints: &mut Vec<u64>) {
+        breakpoints.retain(|bp| !self.inserted.contains(bp));
+        self.inserted.clear();
+        self.detached = true;
+    }
+
+    /// `c`/`s` may carry an address to resume from
+    fn resume(&mut self, cpu: &mut CPU, addr: &str) {
+        if let Ok(addr) = u64::from_str_radix(addr, 16) {
+            cpu.registers.write(PROGRAM_COUNTER, addr);
+        }
+        self.running = true;
+    }
+
+    fn query(&mut self, packet: &str) -> String {
+        if packet.starts_with("qSupported") {
+            format!("PacketSize={PACKET_SIZE:x};qXfer:features:read+")
+        } else if packet == "qAttached" {
+            "1".to_string()
+        } else if packet == "qC" {
+            "QC1".to_string()
+        } else if packet == "qfThreadInfo" {
+            "m1".to_string()
+        } else if packet == "qsThreadInfo" {
+            "l".to_string()
+        } else if let Some(range) = packet.strip_prefix("qXfer:features:read:target.xml:") {
+            let (offset, len) = match parse_addr_len(range) {
+                Some(r) => r,
+                None => return "E01".to_string(),
+            };
+            let offset = (offset as usize).min(TARGET_XML.len());
+            let end = (offset + len as usize).min(TARGET_XML.len());
+            let prefix = if end == TARGET_XML.len() {
+                self.described = true;
+                "l"
+            } else {
+                "m"
+            };
+            format!("{prefix}{}", &TARGET_XML[offset..end])
+        } else {
+            String::new()
+        }
+    }
+
+    /// returns None if the client disconnected
+    fn receive_packet(&mut self) -> Result<Option<String>, ExecutionError> {
+        let mut byte = [0u8; 1];
+        loop {
+            // skip acks and stray interrupts until the start of a packet
+            loop {
+                if !self.read_byte(&mut byte)? {
+                    return Ok(None);
+                }
+                if byte[0] == b'$' {
+                    break;
+                }
+            }
+            let mut data = Vec::new();
+            loop {
+                if !self.read_byte(&mut byte)? {
+                    return Ok(None);
+                }
+                if byte[0] == b'#' {
+                    break;
+                }
+                data.push(byte[0]);
+            }
+            let mut checksum = [0u8; 2];
+            for c in checksum.iter_mut() {
+                if !self.read_byte(&mut byte)? {
+                    return Ok(None);
+                }
+                *c = byte[0];
+            }
+            let expected = u8::from_str_radix(&String::from_utf8_lossy(&checksum), 16).ok();
+            if expected == Some(compute_checksum(&data)) {
+                self.write_raw(b"+")?;
+                return Ok(Some(String::from_utf8_lossy(&data).to_string()));
+            }
+            self.write_raw(b"-")?;
+        }
+    }
+
+    fn send_packet(&mut self, data: &str) -> Result<(), ExecutionError> {
+        kernel_log!(self.log, "gdb -> {data}");
+        let packet = format!("${data}#{:0>2x}", compute_checksum(data.as_bytes()));
+        self.write_raw(packet.as_bytes())
+    }
+
+    fn read_byte(&mut self, byte: &mut [u8; 1]) -> Result<bool, ExecutionError> {
+        if let Some(pending) = self.pending.pop_front() {
+            byte[0] = pending;
+            return Ok(true);
+        }
+        loop {
+            match self.stream.read(byte) {
+                Ok(0) => return Ok(false),
+                Ok(_) => return Ok(true),
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(ExecutionError::new(format!("gdb connection error: {e}"))),
+            }
+        }
+    }
+
+    fn write_raw(&mut self, bytes: &[u8]) -> Result<(), ExecutionError> {
+        self.stream
+            .write_all(bytes)
+            .and_then(|_| self.stream.flush())
+            .map_err(|e| ExecutionError::new(format!("gdb connection error: {e}")))
+    }
+}
+
+fn compute_checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, b| sum.wrapping_add(*b))
+}
+
+fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:0>2x}")).collect()
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+fn le_u64(bytes: &[u8]) -> u64 {
+    let mut buf = [0u8; 8];
+    buf.copy_from_slice(&bytes[..8]);
+    u64::from_le_bytes(buf)
+}
+
+/// parses `addr,len` in hex
+fn parse_addr_len(s: &str) -> Option<(u64, u64)> {
+    let (addr, len) = s.split_once(',')?;
+    Some((
+        u64::from_str_radix(addr, 16).ok()?,
+        u64::from_str_radix(len, 16).ok()?,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    fn frame(packet: &str) -> String {
+        format!("${packet}#{:0>2x}", compute_checksum(packet.as_bytes()))
+    }
+
+    fn send(stream: &mut UnixStream, packet: &str) {
+        stream.write_all(frame(packet).as_bytes()).unwrap();
+    }
+
+    /// reads the next packet, skipping acks, and acknowledges it
+    fn reply(stream: &mut UnixStream) -> String {
+        let mut byte = [0u8; 1];
+        while byte[0] != b'$' {
+            stream.read_exact(&mut byte).unwrap();
+        }
+        let mut data = Vec::new();
+        loop {
+            stream.read_exact(&mut byte).unwrap();
+            if byte[0] == b'#' {
+                break;
+            }
+            data.push(byte[0]);
+        }
+        let mut checksum = [0u8; 2];
+        stream.read_exact(&mut checksum).unwrap();
+        stream.write_all(b"+").unwrap();
+        String::from_utf8(data).unwrap()
+    }
+
+    fn exchange(stream: &mut UnixStream, packet: &str) -> String {
+        send(stream, packet);
+        reply(stream)
+    }
+
+    #[test]
+    fn unix_socket_round_trip() {
+        let path = std::env::temp_dir().join(format!("nisvc-gdb-{}.sock", std::process::id()));
+        let client_path = path.clone();
+        let client = thread::spawn(move || {
+            let mut stream = loop {
+                match UnixStream::connect(&client_path) {
+                    Ok(stream) => break stream,
+                    Err(_) => thread::sleep(Duration::from_millis(10)),
+                }
+            };
+            let s = &mut stream;
+            assert_eq!(exchange(s, "?"), STOP_REPLY);
+            // registers are refused until the target description was read
+            assert_eq!(exchange(s, "g"), "E02");
+            let xml = exchange(s, "qXfer:features:read:target.xml:0,10");
+            assert_eq!(xml, "m<?xml version=\"1");
+            let xml = exchange(
+                s,
+                &format!("qXfer:features:read:target.xml:0,{PACKET_SIZE:x}"),
+            );
+            assert_eq!(xml, format!("l{TARGET_XML}"));
+            let registers: String = (0..16u64)
+                .map(|r| encode_hex(&(r * 0x11).to_le_bytes()))
+                .collect();
+            assert_eq!(exchange(s, &format!("G{registers}")), "OK");
+            assert_eq!(exchange(s, "g"), registers);
+            assert_eq!(exchange(s, "p4"), encode_hex(&0x44u64.to_le_bytes()));
+            assert_eq!(
+                exchange(s, &format!("P4={}", encode_hex(&7u64.to_le_bytes()))),
+                "OK"
+            );
+            assert_eq!(exchange(s, "p4"), "0700000000000000");
+            assert_eq!(exchange(s, "p10"), "E01");
+            assert_eq!(exchange(s, "M20,3:aabbcc"), "OK");
+            assert_eq!(exchange(s, "m20,3"), "aabbcc");
+            assert_eq!(exchange(s, "M20,3:aa"), "E01");
+            assert_eq!(exchange(s, "Z0,20,1"), "OK");
+            assert_eq!(exchange(s, "Z0,30,1"), "OK");
+            assert_eq!(exchange(s, "z0,20,1"), "OK");
+            // watchpoints are unsupported
+            assert_eq!(exchange(s, "Z2,20,1"), "");
+            // stops are reported once the vm re-enters the stub
+            assert_eq!(exchange(s, "s20"), STOP_REPLY);
+            send(s, "c");
+            // an interrupt arriving with the next packet must not swallow it
+            s.write_all(format!("\x03{}", frame("p1")).as_bytes())
+                .unwrap();
+            assert_eq!(reply(s), STOP_REPLY);
+            assert_eq!(reply(s), encode_hex(&0x20u64.to_le_bytes()));
+            send(s, "k");
+            // wait for the ack before hanging up
+            s.read_to_end(&mut Vec::new()).unwrap();
+        });
+
+        let mut cpu = CPU::new(0x100, 0x100, Logger::silent());
+        cpu.memory.load(vec![0; 0x40]).unwrap();
+        // set on the command line
+        let mut breakpoints = vec![0x40];
+        let mut stub = GdbStub::listen(path.to_str().unwrap(), Logger::silent()).unwrap();
+        let action = stub.enter(&mut cpu, &mut breakpoints).unwrap();
+        assert!(matches!(action, DebugAction::Step(1)));
+        assert_eq!(cpu.registers.read(PROGRAM_COUNTER), 0x20);
+        assert_eq!(breakpoints, [0x40, 0x30]);
+        let action = stub.enter(&mut cpu, &mut breakpoints).unwrap();
+        assert!(matches!(action, DebugAction::Continue));
+        while !stub.poll_interrupt() {
+            thread::sleep(Duration::from_millis(10));
+        }
+        let action = stub.enter(&mut cpu, &mut breakpoints).unwrap();
+        assert!(matches!(action, DebugAction::Quit));
+        assert_eq!(breakpoints, [0x40]);
+        drop(stub);
+        client.join().unwrap();
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn detaching_removes_the_clients_breakpoints() {
+        let path =
+            std::env::temp_dir().join(format!("nisvc-gdb-detach-{}.sock", std::process::id()));
+        let client_path = path.clone();
+        let client = thread::spawn(move || {
+            let mut stream = loop {
+                match UnixStream::connect(&client_path) {
+                    Ok(stream) => break stream,
+                    Err(_) => thread::sleep(Duration::from_millis(10)),
+                }
+            };
+            let s = &mut stream;
+            assert_eq!(exchange(s, "Z0,30,1"), "OK");
+            assert_eq!(exchange(s, "Z0,40,1"), "OK");
+            assert_eq!(exchange(s, "D"), "OK");
+        });
+
+        let mut cpu = CPU::new(0x100, 0x100, Logger::silent());
+        cpu.memory.load(vec![0; 0x40]).unwrap();
+        let mut breakpoints = vec![0x40];
+        let mut stub = GdbStub::listen(path.to_str().unwrap(), Logger::silent()).unwrap();
+        let action = stub.enter(&mut cpu, &mut breakpoints).unwrap();
+        assert!(matches!(action, DebugAction::Continue));
+        assert_eq!(breakpoints, [0x40]);
+        client.join().unwrap();
+        // the guest runs on without waiting for a client
+        thread::sleep(INTERRUPT_POLL_INTERVAL);
+        assert!(!stub.interrupt_requested());
+        let action = stub.enter(&mut cpu, &mut breakpoints).unwrap();
+        assert!(matches!(action, DebugAction::Continue));
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,142 @@
+use std::path::Path;
+
+/// file formats frames are written in
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum ImageFormat {
+    #[default]
+    Png,
+    /// binary netpbm, `P6`
+    Ppm,
+}
+
+impl ImageFormat {
+    /// the format named by the extension of `path`
+    pub fn of(path: &Path) -> Option<ImageFormat> {
+        match path.extension()?.to_str()? {
+            "png" => Some(ImageFormat::Png),
+            "ppm" => Some(ImageFormat::Ppm),
+            _ => None,
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ImageFormat::Png => "png",
+            ImageFormat::Ppm => "ppm",
+        }
+    }
+
+    /// encodes rgb24 pixels given row by row
+    pub fn encode(&self, width: u32, height: u32, rgb: &[u8]) -> Vec<u8> {
+        match self {
+            ImageFormat::Png => png(width, height, rgb),
+            ImageFormat::Ppm => {
+                let mut ppm = format!("P6\n{width} {height}\n255\n").into_bytes();
+                ppm.extend_from_slice(rgb);
+                ppm
+            }
+        }
+    }
+}
+
+/// an 8 bit rgb png with the image data stored uncompressed, frames stay small enough that
+/// compression is not worth a deflate implementation
+fn png(width: u32, height: u32, rgb: &[u8]) -> Vec<u8> {
+    let mut ihdr = Vec::new();
+    ihdr.extend_from_slice(&width.to_be_bytes());
+    ihdr.extend_from_slice(&height.to_be_bytes());
+    // bit depth, truecolor, deflate, adaptive filtering, not interlaced
+    ihdr.extend_from_slice(&[8, 2, 0, 0, 0]);
+    // each row starts with its filter type, 0 for none
+    let mut scanlines = Vec::with_capacity(rgb.len() + height as usize);
+    for row in rgb.chunks((width as usize * 3).max(1)) {
+        scanlines.push(0);
+        scanlines.extend_from_slice(row);
+    }
+    let mut png = b"\x89PNG\r\n\x1a\n".to_vec();
+    chunk(&mut png, b"IHDR", &ihdr);
+    chunk(&mut png, b"IDAT", &zlib_stored(&scanlines));
+    chunk(&mut png, b"IEND", &[]);
+    png
+}
+
+fn chunk(png: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    png.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    let start = png.len();
+    png.extend_from_slice(kind);
+    png.extend_from_slice(data);
+    let crc = crc32(&png[start..]);
+    png.extend_from_slice(&crc.to_be_bytes());
+}
+
+/// a zlib stream of uncompressed deflate blocks
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    // deflate with a 32K window and the lowest compression level
+    let mut zlib = vec![0x78, 0x01];
+    let mut blocks = data.chunks(u16::MAX as usize).peekable();
+    if blocks.peek().is_none() {
+        zlib.extend_from_slice(&[1, 0, 0, 0xff, 0xff]);
+    }
+    while let Some(block) = blocks.next() {
+        let last = blocks.peek().is_none();
+        let len = block.len() as u16;
+        zlib.push(last as u8);
+        zlib.extend_from_slice(&len.to_le_bytes());
+        zlib.extend_from_slice(&(!len).to_le_bytes());
+        zlib.extend_from_slice(block);
+    }
+    zlib.extend_from_slice(&adler32(data).to_be_bytes());
+    zlib
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = match crc & 1 {
+                1 => (crc >> 1) ^ 0xedb8_8320,
+                _ => crc >> 1,
+            };
+        }
+    }
+    !crc
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for &byte in data {
+        a = (a + byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    (b << 16) | a
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn png_chunks_are_checksummed() {
+        let png = ImageFormat::Png.encode(1, 1, &[0xff, 0, 0]);
+        assert!(png.starts_with(b"\x89PNG\r\n\x1a\n\0\0\0\x0dIHDR"));
+        assert!(png.ends_with(b"\0\0\0\0IEND\xae\x42\x60\x82"));
+        // filter byte and pixel in a single final stored block
+        let idat = &png[33 + 8..png.len() - 12 - 4];
+        assert_eq!(
+            idat,
+            b"\x78\x01\x01\x04\x00\xfb\xff\x00\xff\x00\x00\x03\x01\x01\x00"
+        );
+    }
+
+    #[test]
+    fn formats_follow_the_extension() {
+        assert_eq!(
+            ImageFormat::of(Path::new("out/f.ppm")),
+            Some(ImageFormat::Ppm)
+        );
+        assert_eq!(ImageFormat::of(Path::new("f.jpg")), None);
+        let ppm = ImageFormat::Ppm.encode(2, 1, &[1, 2, 3, 4, 5, 6]);
+        assert_eq!(ppm, b"P6\n2 1\n255\n\x01\x02\x03\x04\x05\x06");
+    }
+}
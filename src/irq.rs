@@ -0,0 +1,97 @@
+use crate::constant::{IRQ_INPUT, IRQ_TIMER};
+
+/// device interrupt lines, each is delivered as a program defined interrupt code
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Irq {
+    Timer,
+    Input,
+}
+
+impl Irq {
+    /// in delivery priority order
+    const ALL: [Irq; 2] = [Irq::Timer, Irq::Input];
+
+    /// the interrupt code whose guest handler receives the irq
+    pub fn code(&self) -> u8 {
+        match self {
+            Irq::Timer => IRQ_TIMER,
+            Irq::Input => IRQ_INPUT,
+        }
+    }
+
+    /// bit of the line in the pending and mask words
+    pub fn bit(&self) -> u64 {
+        1 << (self.code() - IRQ_TIMER)
+    }
+}
+
+/// fires once the cpu reaches `deadline`, then again every `period` cycles if set
+struct Timer {
+    deadline: u64,
+    period: Option<u64>,
+}
+
+/// latches device irqs until the guest can take them
+#[derive(Default)]
+pub struct InterruptController {
+    /// irqs are only delivered while enabled
+    enabled: bool,
+    /// masked lines stay pending until unmasked
+    mask: u64,
+    pending: u64,
+    timer: Option<Timer>,
+}
+
+impl InterruptController {
+    pub fn raise(&mut self, irq: Irq) {
+        self.pending |= irq.bit();
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+    }
+
+    /// returns the previous mask
+    pub fn set_mask(&mut self, mask: u64) -> u64 {
+        std::mem::replace(&mut self.mask, mask)
+    }
+
+    /// arms the timer to fire `cycles` after `now`, repeating if `periodic`. 0 disarms it
+    pub fn set_timer(&mut self, now: u64, cycles: u64, periodic: bool) {
+        self.timer = (cycles != 0).then_some(Timer {
+            deadline: now + cycles,
+            period: periodic.then_some(cycles),
+        });
+    }
+
+    /// raises the timer irq if its deadline passed by `cycle`
+    pub fn tick(&mut self, cycle: u64) {
+        let Some(timer) = self.timer.as_mut() else {
+            return;
+        };
+        if cycle < timer.deadline {
+            return;
+        }
+        match timer.period {
+            Some(period) => timer.deadline = cycle + period,
+            None => self.timer = None,
+        }
+        self.raise(Irq::Timer);
+    }
+
+    /// takes the highest priority pending irq that is enabled and unmasked
+    pub fn take(&mut self) -> Option<Irq> {
+        if !self.enabled {
+            return None;
+        }
+        let irq = Irq::ALL
+            .into_iter()
+            .find(|irq| self.pending & !self.mask & irq.bit() != 0)?;
+        self.pending &= !irq.bit();
+        Some(irq)
+    }
+}
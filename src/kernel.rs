@@ -1,42 +1,177 @@
 use std::{
-    collections::HashMap,
-    fs::{File, Metadata},
-    io::{stderr, stdin, stdout, Read, Seek, Stderr, Stdin, Stdout, Write},
+    collections::{HashMap, VecDeque},
+    fs::{self, File},
+    io::{self, stderr, stdin, stdout, IsTerminal, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
     time::Duration,
 };
 
 use crossterm::style::Stylize;
-use sdl2::{libc::MS_SILENT, sys::exit};
 
 use crate::{
-    constant::{DEFAULT_CLOCK_SPEED, MEM_HEAP, MEM_INVALID, MEM_STACK, MEM_STATIC, STACK_POINTER},
+    constant::PROGRAM_COUNTER,
     cpu::CPU,
-    kernel_log, ExecutionError, _kernel_log,
-    gpu::GPU,
+    debugger::{DebugAction, Debugger},
+    gpu::{GpuConfig, GPU},
+    irq::{InterruptController, Irq},
+    kernel_log,
+    loader::{DebugSymbols, NISVCEF},
+    logger::Logger,
+    sandbox::{Access, Sandbox},
+    syscall::{Errno, Syscall, SyscallHandler},
+    tty::{LineEditor, Tty, READLINE_EOF},
+    vfs::{FileKind, OpenMode, Stat, Vfs, VirtualFile},
+    ErrorKind, ExecutionError,
 };
 
-pub static mut KERNEL_LOG: bool = false;
+type NksHandler = fn(&mut Kernel, &[u64]) -> Result<Vec<u64>, ExecutionError>;
+
+/// `whence` values of the seek syscall
+const SEEK_SET: u64 = 0;
+const SEEK_CUR: u64 = 1;
+const SEEK_END: u64 = 2;
+
+/// `flags` bits of the open_flags syscall
+const OPEN_READ: u64 = 1 << 0;
+const OPEN_WRITE: u64 = 1 << 1;
+const OPEN_CREATE: u64 = 1 << 2;
+const OPEN_TRUNCATE: u64 = 1 << 3;
+const OPEN_APPEND: u64 = 1 << 4;
+const OPEN_EXCLUSIVE: u64 = 1 << 5;
+const OPEN_FLAGS: u64 = (1 << 6) - 1;
+
+/// the built-in NKS syscalls, 0x14 halts without reaching a handler
+const NKS_SYSCALLS: &[(u8, &str, usize, NksHandler)] = &[
+    (0x01, "open", 2, Kernel::sys_open),
+    (0x02, "write", 3, Kernel::sys_write),
+    (0x03, "read", 3, Kernel::sys_read),
+    (0x04, "seek", 3, Kernel::sys_seek),
+    (0x05, "close", 1, Kernel::sys_close),
+    (
+        0x06,
+        "runtime_silence_switch",
+        0,
+        Kernel::sys_runtime_silence_switch,
+    ),
+    (0x07, "raw_tty_switch", 1, Kernel::sys_raw_tty_switch),
+    (0x08, "tty_rel_cursor", 2, Kernel::sys_tty_rel_cursor),
+    (0x09, "tty_abs_cursor", 2, Kernel::sys_tty_abs_cursor),
+    (0x0a, "malloc", 1, Kernel::sys_malloc),
+    (0x0b, "realloc", 2, Kernel::sys_realloc),
+    (0x0c, "free", 1, Kernel::sys_free),
+    (0x0d, "memcpy", 3, Kernel::sys_memcpy),
+    (0x0e, "memset", 3, Kernel::sys_memset),
+    (0x0f, "init_fb", 4, Kernel::sys_init_fb),
+    (0x10, "draw_fb", 0, Kernel::sys_draw_fb),
+    (0x11, "get_fb_ptr", 0, Kernel::sys_get_fb_ptr),
+    (0x12, "get_file_size", 1, Kernel::sys_get_file_size),
+    (0x13, "dump", 0, Kernel::sys_dump),
+    (0x15, "get_argc", 0, Kernel::sys_get_argc),
+    (0x16, "get_argv", 1, Kernel::sys_get_argv),
+    (0x17, "memquery", 1, Kernel::sys_memquery),
+    (0x18, "set_fault_handler", 1, Kernel::sys_set_fault_handler),
+    (0x19, "exit", 1, Kernel::sys_exit),
+    (
+        0x1a,
+        "set_interrupt_handler",
+        2,
+        Kernel::sys_set_interrupt_handler,
+    ),
+    (
+        0x1b,
+        "clear_interrupt_handler",
+        1,
+        Kernel::sys_clear_interrupt_handler,
+    ),
+    (0x1c, "set_irq_enabled", 1, Kernel::sys_set_irq_enabled),
+    (0x1d, "set_irq_mask", 1, Kernel::sys_set_irq_mask),
+    (0x1e, "set_timer", 2, Kernel::sys_set_timer),
+    (0x1f, "get_input_event", 0, Kernel::sys_get_input_event),
+    (
+        0x20,
+        "wait_for_interrupt",
+        0,
+        Kernel::sys_wait_for_interrupt,
+    ),
+    (0x21, "tell", 1, Kernel::sys_tell),
+    (0x22, "open_flags", 3, Kernel::sys_open_flags),
+    (0x23, "stat", 3, Kernel::sys_stat),
+    (0x24, "opendir", 2, Kernel::sys_opendir),
+    (0x25, "readdir", 3, Kernel::sys_readdir),
+    (0x26, "mkdir", 2, Kernel::sys_mkdir),
+    (0x27, "rmdir", 2, Kernel::sys_rmdir),
+    (0x28, "unlink", 2, Kernel::sys_unlink),
+    (0x29, "rename", 4, Kernel::sys_rename),
+    (0x2a, "getcwd", 2, Kernel::sys_getcwd),
+    (0x2b, "chdir", 2, Kernel::sys_chdir),
+    (0x2c, "tty_control", 2, Kernel::sys_tty_control),
+    (0x2d, "tty_size", 1, Kernel::sys_tty_size),
+    (0x2e, "poll_input", 1, Kernel::sys_poll_input),
+    (0x2f, "read_key_event", 1, Kernel::sys_read_key_event),
+    (0x30, "readline", 4, Kernel::sys_readline),
+];
+
 /// - `0x01..0x30`: nhk interrupts
 /// - `0x31..0xfe`: program defined interrupts
 /// - `0xff`: hard execution stop
 pub struct Kernel {
     pub system: CPU,
     pub gpu: Option<GPU>,
+    /// backend and frame recording of the gpu created by init_fb
+    gpu_config: GpuConfig,
     clock_speed: f32,
     user_interrupt_vector: [u64; 205],
     breakpoint_vector: Vec<u64>,
     file_descriptor_vector: HashMap<u64, IOInterface>,
     cmdline: Vec<String>,
+    /// guest path relative paths are resolved against, chdir only affects this kernel
+    cwd: PathBuf,
+    /// what guest paths map to and which of them syscalls may touch
+    sandbox: Sandbox,
+    /// replaces the host filesystem for file syscalls when set
+    vfs: Option<Vfs>,
+    /// raw mode and styling of the host terminal, restored when the vm stops
+    tty: Tty,
+    /// whether readline edits lines on the host terminal, otherwise it reads them from fd 0
+    interactive: bool,
+    /// created by the first readline
+    line_editor: Option<LineEditor>,
+    /// where readline history is kept between runs
+    history_file: Option<PathBuf>,
     next_fd: u64,
     cores_dumped: usize,
+    /// set once a breakpoint hit without a debugger dumped core, later hits are only logged
+    breakpoint_dumped: bool,
+    debugger: Option<Debugger>,
+    debug_symbols: DebugSymbols,
+    /// instructions left to execute before re-entering the debug shell, None when continuing
+    debug_steps_remaining: Option<usize>,
+    /// guest handler entered on illegal instructions, 0 when unset
+    fault_handler: u64,
+    irq: InterruptController,
+    /// key codes from the frame buffer window not yet read by the guest
+    input_events: VecDeque<u64>,
+    /// set by `wait_for_interrupt`, the cpu idles until an irq is delivered
+    waiting: bool,
+    /// handlers by interrupt code, the NKS syscalls unless replaced
+    syscalls: HashMap<u8, Box<dyn SyscallHandler>>,
+    /// set by the exit syscall, or to 0 once the guest halts
+    exit_status: Option<u64>,
+    /// whether `core_dump` writes memory images to `core_dir`
+    core_dumps: bool,
+    /// empty for the working directory
+    core_dir: PathBuf,
+    /// whether failed file syscalls fault the vm instead of returning an errno
+    strict_syscalls: bool,
+    log: Logger,
     // frame_buffer_ptr: u64,
 }
 impl Kernel {
-    pub fn new(cmdline: Vec<String>, heap: u64, stack: u64, clock_speed: f32) -> Self {
+    pub fn new(cmdline: Vec<String>, heap: u64, stack: u64, clock_speed: f32, log: Logger) -> Self {
         let mut file_descriptor_vector = HashMap::new();
-        file_descriptor_vector.insert(0, IOInterface::Stdin(stdin()));
-        file_descriptor_vector.insert(1, IOInterface::Stdout(stdout()));
-        file_descriptor_vector.insert(2, IOInterface::Stderr(stderr()));
+        file_descriptor_vector.insert(0, IOInterface::Stdin(Box::new(stdin())));
+        file_descriptor_vector.insert(1, IOInterface::Stdout(Box::new(stdout())));
+        file_descriptor_vector.insert(2, IOInterface::Stderr(Box::new(stderr())));
         // let mut gpu_test = match GPU::new(0, 200, 200) {
         //     Ok(g) => g,
         //     Err(e) => {
@@ -44,223 +179,906 @@ impl Kernel {
         //     }
         // };
         Self {
-            system: CPU::new(heap, stack),
+            system: CPU::new(heap, stack, log.clone()),
             gpu: None,
+            gpu_config: GpuConfig::default(),
             clock_speed,
             user_interrupt_vector: [0; 205],
             breakpoint_vector: Vec::new(),
             file_descriptor_vector,
             next_fd: 3,
             cores_dumped: 0,
+            breakpoint_dumped: false,
             cmdline,
+            cwd: Sandbox::default().initial_cwd(),
+            sandbox: Sandbox::default(),
+            vfs: None,
+            tty: Tty::default(),
+            interactive: io::stdin().is_terminal(),
+            line_editor: None,
+            history_file: None,
+            debugger: None,
+            debug_symbols: DebugSymbols::default(),
+            debug_steps_remaining: None,
+            fault_handler: 0,
+            irq: InterruptController::default(),
+            input_events: VecDeque::new(),
+            waiting: false,
+            syscalls: NKS_SYSCALLS
+                .iter()
+                .map(|&(code, name, arity, handler)| {
+                    let handler: Box<dyn SyscallHandler> =
+                        Box::new(Syscall::new(name, arity, handler));
+                    (code, handler)
+                })
+                .collect(),
+            exit_status: None,
+            core_dumps: true,
+            core_dir: PathBuf::new(),
+            strict_syscalls: false,
+            log,
         }
     }
 
-    fn resolve_user_interrupt(&self, code: u8) -> u64 {
-        let real = code - 0x31;
-        self.user_interrupt_vector[real as usize]
+    /// replaces the guest's stdin, fd 0
+    pub fn set_stdin(&mut self, stdin: impl Read + 'static) {
+        self.interactive = false;
+        self.file_descriptor_vector
+            .insert(0, IOInterface::Stdin(Box::new(stdin)));
     }
 
-    fn handle_interrupt(&mut self, code: u8) -> Result<(), ExecutionError> {
-        match code {
-            0x01 => {
-                // file open
-
-                let str_len = self.system.pop()?;
-                let str_ptr = self.system.pop()?;
-                let str_bytes = self.system.memory.read(str_ptr, str_len)?;
-                let path = String::from_utf8_lossy(&str_bytes);
-                kernel_log!("open(2) {path}");
-                let file_descriptor = self.open_file(&path)?;
-                self.system.push(file_descriptor)?;
-                Ok(())
-            }
-            0x02 => {
-                // file write
-                kernel_log!("write(3)");
-                let buf_len = self.system.pop()?;
-                let buf_ptr = self.system.pop()?;
-                let file_descriptor = self.system.pop()?;
-                let buffer = self.system.memory.read(buf_ptr, buf_len)?;
-                // kernel_log!("write buffer: {:?}, len: {}", buffer, buffer.len());
-
-                self.write_file(file_descriptor, &buffer)?;
-                Ok(())
-            }
-            0x03 => {
-                //file read
-                kernel_log!("read(3)");
-                let n = self.system.pop()?;
-                let ptr = self.system.pop()?;
-                let fd = self.system.pop()?;
-                let buf = self.read_file(fd, n)?;
-                self.system.memory.write(ptr, &buf)?;
-                Ok(())
-            }
-            0x04 => {
-                //file seek
-                kernel_log!("seek");
-                todo!()
-            }
-            0x05 => {
-                // file close
+    /// replaces the guest's stdout, fd 1
+    pub fn set_stdout(&mut self, stdout: impl Write + 'static) {
+        self.file_descriptor_vector
+            .insert(1, IOInterface::Stdout(Box::new(stdout)));
+    }
 
-                let fd = self.system.pop()?;
-                kernel_log!("close({fd})");
-                self.close_file(fd)?;
-                Ok(())
-            }
-            0x06 => {
-                // silence print
-                unsafe { KERNEL_LOG = false };
-                Ok(())
-            }
-            0x07 => {
-                // rawtty toggle
-                kernel_log!("rawtty switch");
-                todo!()
-            }
-            0x08 => {
-                // tty cursor relative x y
-                kernel_log!("tty relative cursor");
-                todo!()
-            }
-            0x09 => {
-                // tty cursor absolute x y
-                kernel_log!("tty absolute cursor");
-                todo!()
-            }
-            0x0a => {
-                // malloc
-                kernel_log!("malloc(1)");
-                let size = self.system.pop()?;
-                let ptr = self.system.memory.malloc(size)?;
-                self.system.push(ptr)
-            }
-            0x0b => {
-                // realloc
-                kernel_log!("realloc(2)");
-                let new_size = self.system.pop()?;
-                let ptr = self.system.pop()?;
-                let new_ptr = self.system.memory.realloc(ptr, new_size)?;
-                self.system.push(new_ptr)
-            }
-            0x0c => {
-                // free
-                kernel_log!("free(1)");
-                let ptr = self.system.pop()?;
-                self.system.memory.free(ptr)
-            }
-            0x0d => {
-                // memcpy
-                kernel_log!("memcpy(3)");
-                let n = self.system.pop()?;
-                let src = self.system.pop()?;
-                let dest = self.system.pop()?;
-                self.system.memory.memcpy(dest, src, n)
-            }
-            0x0e => {
-                // memset
-                kernel_log!("memset(3)");
-                let n = self.system.pop()?;
-                let src = self.system.pop()?;
-                let dest = self.system.pop()?;
-                self.system.memory.memset(dest, src as u8, n)
+    /// replaces the guest's stderr, fd 2
+    pub fn set_stderr(&mut self, stderr: impl Write + 'static) {
+        self.file_descriptor_vector
+            .insert(2, IOInterface::Stderr(Box::new(stderr)));
+    }
+
+    pub fn set_core_dumps(&mut self, enabled: bool) {
+        self.core_dumps = enabled;
+    }
+
+    /// directory core dumps are written to, the working directory by default
+    pub fn set_core_dir(&mut self, dir: impl Into<PathBuf>) {
+        self.core_dir = dir.into();
+    }
+
+    /// restricts the paths file syscalls may touch, the guest starts at its initial working directory
+    pub fn set_sandbox(&mut self, sandbox: Sandbox) {
+        self.sandbox = sandbox;
+        self.cwd = self.initial_cwd();
+    }
+
+    /// runs file syscalls against `vfs` instead of the host filesystem, the guest starts at `/`
+    pub fn set_vfs(&mut self, vfs: Vfs) {
+        self.vfs = Some(vfs);
+        self.cwd = self.initial_cwd();
+    }
+
+    /// the in-memory filesystem as the guest left it
+    pub fn vfs(&self) -> Option<&Vfs> {
+        self.vfs.as_ref()
+    }
+
+    fn initial_cwd(&self) -> PathBuf {
+        match self.vfs {
+            Some(_) => PathBuf::from("/"),
+            None => self.sandbox.initial_cwd(),
+        }
+    }
+
+    pub fn set_strict_syscalls(&mut self, enabled: bool) {
+        self.strict_syscalls = enabled;
+    }
+
+    /// how the frame buffer is shown and recorded once the guest creates it
+    pub fn set_gpu_config(&mut self, config: GpuConfig) {
+        self.gpu_config = config;
+    }
+
+    /// writes the last drawn frame as a .png or .ppm image
+    pub fn capture_frame(&self, path: impl AsRef<Path>) -> Result<(), ExecutionError> {
+        let gpu = self.gpu.as_ref().ok_or(ExecutionError::new(
+            "no frame to capture: the program has not called init_fb".to_string(),
+        ))?;
+        gpu.capture(path.as_ref())
+    }
+
+    /// keeps the history of the readline syscall in `path` between runs
+    pub fn set_history_file(&mut self, path: impl Into<PathBuf>) {
+        self.history_file = Some(path.into());
+    }
+
+    /// attaches a debugger, which is entered before the first instruction
+    pub fn attach_debugger(&mut self, debugger: Debugger) {
+        self.debugger = Some(debugger);
+        self.debug_steps_remaining = Some(0);
+    }
+
+    /// pauses execution if a breakpoint was reached or a debugger step count ran out,
+    /// entering the debugger or dumping machine state when none is attached.
+    /// returns false if execution should stop
+    fn debug_hook(&mut self) -> Result<bool, ExecutionError> {
+        let pc = self.system.registers.read(PROGRAM_COUNTER);
+        let interrupted = self
+            .debugger
+            .as_mut()
+            .is_some_and(|d| d.interrupt_requested());
+        let hit_breakpoint =
+            self.system.pending_breakpoint || self.breakpoint_vector.contains(&pc) || interrupted;
+        self.system.pending_breakpoint = false;
+        let should_break = hit_breakpoint || self.debug_steps_remaining == Some(0);
+        if !should_break {
+            if let Some(n) = self.debug_steps_remaining.as_mut() {
+                *n -= 1;
             }
-            0x0f => {
-                kernel_log!("init_fb(3)");
-                if let Some(mut gpu) = self.gpu.as_mut() {
-                    gpu.free_fb();
+            return Ok(true);
+        }
+        let debugger = match self.debugger.as_mut() {
+            Some(d) => d,
+            None => {
+                self.log
+                    .print(&format!("{} @ {pc:#x}", "breakpoint".on_blue()));
+                self.log.print(&self.system.registers.print_all());
+                if !self.breakpoint_dumped {
+                    self.breakpoint_dumped = true;
+                    self.core_dump()?;
                 }
-                let mode = self.system.pop()?;
-                let height = self.system.pop()?;
-                let width = self.system.pop()?;
-                let frame_buffer_ptr = self.system.pop()?;
-                self.gpu = Some(GPU::new(
-                    frame_buffer_ptr,
-                    width as u32,
-                    height as u32,
-                    mode as u8,
-                )?);
-                Ok(())
+                return Ok(true);
             }
-            0x10 => {
-                kernel_log!("draw_fb(0)");
-                self.gpu_fb_refresh()
-            }
-            0x11 => {
-                kernel_log!("get_fb_ptr(0)");
-                if let Some(gpu) = self.gpu.as_ref() {
-                    self.system.push(gpu.stdmem_frame_buffer_ptr)?;
+        };
+        match debugger.enter(
+            &mut self.system,
+            &mut self.breakpoint_vector,
+            &self.debug_symbols,
+            self.gpu.as_ref(),
+        )? {
+            DebugAction::Step(n) => self.debug_steps_remaining = Some(n.saturating_sub(1)),
+            DebugAction::Continue => self.debug_steps_remaining = None,
+            DebugAction::Quit => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    /// resolves a breakpoint location, a label or an address
+    pub fn add_breakpoint(&mut self, location: &str) -> Result<(), ExecutionError> {
+        let addr = self
+            .debug_symbols
+            .resolve(location)
+            .ok_or(ExecutionError::new(format!(
+                "invalid breakpoint `{location}`: not an address or known label"
+            )))?;
+        if !self.breakpoint_vector.contains(&addr) {
+            self.breakpoint_vector.push(addr);
+        }
+        Ok(())
+    }
+
+    /// walks the guest call stack from `fault_pc`, symbolizing each frame
+    pub fn backtrace_report(&mut self, fault_pc: u64) -> String {
+        let mut frames = self.system.backtrace();
+        frames[0] = fault_pc;
+        let mut report = String::from("backtrace:");
+        for (i, pc) in frames.iter().enumerate() {
+            report += &format!("\n  #{i} {pc:#x} {}", self.debug_symbols.symbolize(*pc));
+        }
+        report
+    }
+
+    pub fn debug_symbols(&self) -> &DebugSymbols {
+        &self.debug_symbols
+    }
+
+    /// loads an executable into the cpu, keeping its breakpoints and debug symbols
+    pub fn load(&mut self, file_path: &str) -> Result<(), ExecutionError> {
+        let executable = self.system.load(file_path)?;
+        self.install(executable);
+        Ok(())
+    }
+
+    /// loads an executable that is already in host memory
+    pub fn load_executable(&mut self, executable: Vec<u8>) -> Result<(), ExecutionError> {
+        let executable = self.system.load_executable(executable)?;
+        self.install(executable);
+        Ok(())
+    }
+
+    fn install(&mut self, executable: NISVCEF) {
+        self.breakpoint_vector.extend(executable.break_points);
+        self.debug_symbols = executable.debug_symbols;
+    }
+
+    /// enters the guest fault handler for faults it can handle, otherwise returns the error.
+    /// the faulting address and the length of the instruction there are pushed before a call
+    /// frame returning to it
+    fn deliver_fault(&mut self, e: ExecutionError) -> Result<(), ExecutionError> {
+        let pc = match (&e.kind, e.pc) {
+            (ErrorKind::IllegalInstruction, Some(pc)) if self.fault_handler != 0 => pc,
+            _ => return Err(e),
+        };
+        kernel_log!(
+            self.log,
+            "delivering fault to handler {:#x}: {}",
+            self.fault_handler,
+            e.error
+        );
+        self.system.push(pc)?;
+        self.system.push(self.system.instruction_len(pc))?;
+        self.system.registers.write(PROGRAM_COUNTER, pc);
+        self.system.call(self.fault_handler)
+    }
+
+    /// the guest handler registered for a program defined interrupt, 0 when unset
+    fn resolve_user_interrupt(&self, code: u8) -> u64 {
+        code.checked_sub(0x31)
+            .and_then(|real| self.user_interrupt_vector.get(real as usize))
+            .copied()
+            .unwrap_or(0)
+    }
+
+    /// the handler slot of a program defined interrupt, `syscall` is the code reported on error
+    fn user_interrupt_slot(&mut self, code: u64, syscall: u8) -> Result<&mut u64, ExecutionError> {
+        code.checked_sub(0x31)
+            .and_then(|real| self.user_interrupt_vector.get_mut(real as usize))
+            .ok_or(ExecutionError::fault(
+                ErrorKind::SyscallArgument { code: syscall },
+                format!("{code:#x} is not a program defined interrupt (0x31..0xfe)"),
+            ))
+    }
+
+    /// enters a guest interrupt handler like a `call`, `iret` returns to after the `int`
+    fn enter_user_interrupt(&mut self, code: u8, handler: u64) -> Result<(), ExecutionError> {
+        kernel_log!(self.log, "interrupt {code:#x} -> handler {handler:#x}");
+        self.system.call(handler)?;
+        self.system.interrupt_depth += 1;
+        Ok(())
+    }
+
+    /// registers a handler for `int code`, returning the handler it replaces
+    pub fn register_syscall(
+        &mut self,
+        code: u8,
+        handler: impl SyscallHandler + 'static,
+    ) -> Option<Box<dyn SyscallHandler>> {
+        self.syscalls.insert(code, Box::new(handler))
+    }
+
+    /// removes the handler for `int code`, the guest faults if it is called afterwards
+    pub fn unregister_syscall(&mut self, code: u8) -> Option<Box<dyn SyscallHandler>> {
+        self.syscalls.remove(&code)
+    }
+
+    fn handle_interrupt(&mut self, code: u8) -> Result<(), ExecutionError> {
+        let user_handler = self.resolve_user_interrupt(code);
+        if user_handler != 0 {
+            return self.enter_user_interrupt(code, user_handler);
+        }
+        let mut handler = self.syscalls.remove(&code).ok_or(ExecutionError::fault(
+            ErrorKind::UnknownSyscall { code },
+            format!("unexpected interrupt {code:#x}"),
+        ))?;
+        let result = self.dispatch(handler.as_mut());
+        // keep a replacement the handler registered for itself
+        self.syscalls.entry(code).or_insert(handler);
+        result
+    }
+
+    /// the result word of a fallible syscall, failures the guest can recover from are
+    /// pushed as a negated [`Errno`] instead of faulting unless syscalls are strict
+    pub fn errno_result(
+        &self,
+        result: Result<u64, ExecutionError>,
+    ) -> Result<Vec<u64>, ExecutionError> {
+        match result {
+            Ok(value) => Ok(vec![value]),
+            Err(e) => match Errno::of(&e.kind) {
+                Some(errno) if !self.strict_syscalls => {
+                    kernel_log!(self.log, "failed with {errno:?}: {}", e.error);
+                    Ok(vec![errno.result()])
                 }
-                Ok(())
-            }
-            0x12 => {
-                kernel_log!("get_file_length(1)");
-                let file_descriptor = self.system.pop()?;
-                let metadata = self.stat_file(file_descriptor)?;
-                self.system.push(metadata.len())
-            }
-            0x13 => {
-                kernel_log!("dump(0)");
-                self.core_dump()
-            }
-            _ => {
-                return Err(ExecutionError::new(format!(
-                    "unexpected interrupt {code:#x}"
+                _ => Err(e),
+            },
+        }
+    }
+
+    /// the result word of write, read and close, which were void before errno results.
+    /// strict syscalls keep them void so older programs keep a balanced stack
+    fn void_result(&self, result: Result<u64, ExecutionError>) -> Result<Vec<u64>, ExecutionError> {
+        if self.strict_syscalls {
+            return result.map(|_| vec![]);
+        }
+        self.errno_result(result)
+    }
+
+    /// pops the handler's arguments, calls it and pushes what it returns
+    fn dispatch(&mut self, handler: &mut dyn SyscallHandler) -> Result<(), ExecutionError> {
+        let mut args = (0..handler.arity())
+            .map(|_| self.system.pop())
+            .collect::<Result<Vec<u64>, ExecutionError>>()?;
+        args.reverse();
+        kernel_log!(
+            self.log,
+            "{}({})",
+            handler.name(),
+            args.iter()
+                .map(|arg| format!("{arg:#x}"))
+                .collect::<Vec<String>>()
+                .join(", ")
+        );
+        for value in handler.handle(self, &args)? {
+            self.system.push(value)?;
+        }
+        Ok(())
+    }
+
+    fn sys_open(&mut self, args: &[u64]) -> Result<Vec<u64>, ExecutionError> {
+        let path = self.read_path(args[0], args[1])?;
+        kernel_log!(self.log, "open {}", path.display());
+        let result = self.open_file(&path, OpenMode::read_only());
+        self.errno_result(result)
+    }
+
+    fn sys_open_flags(&mut self, args: &[u64]) -> Result<Vec<u64>, ExecutionError> {
+        let path = self.read_path(args[0], args[1])?;
+        let flags = args[2];
+        if flags & !OPEN_FLAGS != 0 {
+            return self.errno_result(Err(ExecutionError::fault(
+                ErrorKind::SyscallArgument { code: 0x22 },
+                format!("unknown open flags {:#x}", flags & !OPEN_FLAGS),
+            )));
+        }
+        kernel_log!(self.log, "open {} with flags {flags:#b}", path.display());
+        let exclusive = flags & OPEN_EXCLUSIVE != 0;
+        let mode = OpenMode {
+            read: flags & OPEN_READ != 0,
+            write: flags & OPEN_WRITE != 0,
+            append: flags & OPEN_APPEND != 0,
+            truncate: flags & OPEN_TRUNCATE != 0,
+            create: flags & OPEN_CREATE != 0 && !exclusive,
+            create_new: exclusive,
+        };
+        let result = self.open_file(&path, mode);
+        self.errno_result(result)
+    }
+
+    fn sys_write(&mut self, args: &[u64]) -> Result<Vec<u64>, ExecutionError> {
+        let buffer = self.system.memory.read(args[1], args[2])?;
+        let result = self
+            .write_file(args[0], &buffer)
+            .map(|()| buffer.len() as u64);
+        self.void_result(result)
+    }
+
+    fn sys_read(&mut self, args: &[u64]) -> Result<Vec<u64>, ExecutionError> {
+        let result = self.read_file(args[0], args[2]);
+        if let Ok(buf) = &result {
+            self.system.memory.write(args[1], buf)?;
+        }
+        self.void_result(result.map(|buf| buf.len() as u64))
+    }
+
+    fn sys_seek(&mut self, args: &[u64]) -> Result<Vec<u64>, ExecutionError> {
+        let offset = args[1] as i64;
+        let from = match args[2] {
+            SEEK_SET => SeekFrom::Start(args[1]),
+            SEEK_CUR => SeekFrom::Current(offset),
+            SEEK_END => SeekFrom::End(offset),
+            whence => {
+                return self.errno_result(Err(ExecutionError::fault(
+                    ErrorKind::SyscallArgument { code: 0x04 },
+                    format!("invalid whence {whence}"),
                 )))
             }
-            0x15 => {
-                kernel_log!("arg_argc");
-                self.system.push(self.cmdline.len() as u64)?;
-                Ok(())
-            }
-            0x16 => {
-                let arg_idx = self.system.pop()?;
-                kernel_log!("get_argv({arg_idx})");
-                let arg = self
-                    .cmdline
-                    .get(arg_idx as usize)
-                    .ok_or(ExecutionError::new(format!(
-                        "argv[{arg_idx}] out of bounds; argc: {}",
-                        self.cmdline.len()
-                    )))?;
-                let len = arg.len() as u64;
-                let ptr = self.system.memory.malloc(len)?;
-                self.system.memory.write(ptr, arg.as_bytes())?;
-                self.system.push(ptr)?;
-                self.system.push(len)?;
-                Ok(())
+        };
+        let result = self.seek_file(args[0], from);
+        self.errno_result(result)
+    }
+
+    fn sys_tell(&mut self, args: &[u64]) -> Result<Vec<u64>, ExecutionError> {
+        let result = self.seek_file(args[0], SeekFrom::Current(0));
+        self.errno_result(result)
+    }
+
+    fn sys_close(&mut self, args: &[u64]) -> Result<Vec<u64>, ExecutionError> {
+        let result = self.close_file(args[0]).map(|()| 0);
+        self.void_result(result)
+    }
+
+    fn sys_runtime_silence_switch(&mut self, _args: &[u64]) -> Result<Vec<u64>, ExecutionError> {
+        self.log.set_kernel(false);
+        Ok(vec![])
+    }
+
+    fn sys_malloc(&mut self, args: &[u64]) -> Result<Vec<u64>, ExecutionError> {
+        Ok(vec![self.system.memory.malloc(args[0])?])
+    }
+
+    fn sys_realloc(&mut self, args: &[u64]) -> Result<Vec<u64>, ExecutionError> {
+        Ok(vec![self.system.memory.realloc(args[0], args[1])?])
+    }
+
+    fn sys_free(&mut self, args: &[u64]) -> Result<Vec<u64>, ExecutionError> {
+        self.system.memory.free(args[0])?;
+        Ok(vec![])
+    }
+
+    fn sys_memcpy(&mut self, args: &[u64]) -> Result<Vec<u64>, ExecutionError> {
+        self.system.memory.memcpy(args[0], args[1], args[2])?;
+        Ok(vec![])
+    }
+
+    fn sys_memset(&mut self, args: &[u64]) -> Result<Vec<u64>, ExecutionError> {
+        self.system.memory.memset(args[0], args[1] as u8, args[2])?;
+        Ok(vec![])
+    }
+
+    fn sys_init_fb(&mut self, args: &[u64]) -> Result<Vec<u64>, ExecutionError> {
+        // the previous window goes away before a new one opens
+        self.gpu = None;
+        self.gpu = Some(GPU::new(
+            args[0],
+            args[1] as u32,
+            args[2] as u32,
+            args[3] as u8,
+            &self.gpu_config,
+            self.log.clone(),
+        )?);
+        Ok(vec![])
+    }
+
+    fn sys_draw_fb(&mut self, _args: &[u64]) -> Result<Vec<u64>, ExecutionError> {
+        self.gpu_fb_refresh()?;
+        Ok(vec![])
+    }
+
+    fn sys_get_fb_ptr(&mut self, _args: &[u64]) -> Result<Vec<u64>, ExecutionError> {
+        Ok(self
+            .gpu
+            .as_ref()
+            .map(|gpu| gpu.stdmem_frame_buffer_ptr)
+            .into_iter()
+            .collect())
+    }
+
+    fn sys_get_file_size(&mut self, args: &[u64]) -> Result<Vec<u64>, ExecutionError> {
+        let result = self.stat_file(args[0]).map(|stat| stat.size);
+        self.errno_result(result)
+    }
+
+    fn sys_stat(&mut self, args: &[u64]) -> Result<Vec<u64>, ExecutionError> {
+        let path = self.read_path(args[0], args[1])?;
+        let stat = match self.with_path(&path, Access::Read, "stat", stat_at) {
+            Ok(stat) => stat,
+            Err(e) => return self.errno_result(Err(e)),
+        };
+        let stat: Vec<u8> = [stat.size, stat.kind as u64, stat.mtime]
+            .iter()
+            .flat_map(|word| word.to_le_bytes())
+            .collect();
+        self.system.memory.write(args[2], &stat)?;
+        Ok(vec![0])
+    }
+
+    fn sys_opendir(&mut self, args: &[u64]) -> Result<Vec<u64>, ExecutionError> {
+        let path = self.read_path(args[0], args[1])?;
+        let result = self
+            .with_path(&path, Access::Read, "open directory", |mut vfs, at| {
+                let names = match vfs.as_deref_mut() {
+                    Some(vfs) => vfs.read_dir(at)?,
+                    None => fs::read_dir(at)?
+                        .map(|entry| Ok(entry?.file_name().to_string_lossy().into_owned()))
+                        .collect::<io::Result<_>>()?,
+                };
+                Ok(IOInterface::Dir {
+                    stat: stat_at(vfs, at)?,
+                    entries: names.into_iter(),
+                })
+            })
+            .map(|dir| self.insert_interface(dir));
+        self.errno_result(result)
+    }
+
+    fn sys_readdir(&mut self, args: &[u64]) -> Result<Vec<u64>, ExecutionError> {
+        let result = self.read_dir_entry(args[0]);
+        let name = match result {
+            Ok(Some(name)) => name,
+            Ok(None) => return Ok(vec![0]),
+            Err(e) => return self.errno_result(Err(e)),
+        };
+        self.write_truncated(args[1], args[2], name.as_bytes())
+    }
+
+    fn sys_mkdir(&mut self, args: &[u64]) -> Result<Vec<u64>, ExecutionError> {
+        let path = self.read_path(args[0], args[1])?;
+        let result = self.with_path(
+            &path,
+            Access::Write,
+            "create directory",
+            |vfs, at| match vfs {
+                Some(vfs) => vfs.create_dir(at),
+                None => fs::create_dir(at),
+            },
+        );
+        self.errno_result(result.map(|()| 0))
+    }
+
+    fn sys_rmdir(&mut self, args: &[u64]) -> Result<Vec<u64>, ExecutionError> {
+        let path = self.read_path(args[0], args[1])?;
+        let result = self.with_path(
+            &path,
+            Access::Write,
+            "remove directory",
+            |vfs, at| match vfs {
+                Some(vfs) => vfs.remove_dir(at),
+                None => fs::remove_dir(at),
+            },
+        );
+        self.errno_result(result.map(|()| 0))
+    }
+
+    fn sys_unlink(&mut self, args: &[u64]) -> Result<Vec<u64>, ExecutionError> {
+        let path = self.read_path(args[0], args[1])?;
+        let result = self.with_path(&path, Access::Write, "remove file", |vfs, at| match vfs {
+            Some(vfs) => vfs.remove_file(at),
+            None => fs::remove_file(at),
+        });
+        self.errno_result(result.map(|()| 0))
+    }
+
+    fn sys_rename(&mut self, args: &[u64]) -> Result<Vec<u64>, ExecutionError> {
+        let from = self.read_path(args[0], args[1])?;
+        let to = self.read_path(args[2], args[3])?;
+        let result = self.backend_path(&to, Access::Write).and_then(|to| {
+            self.with_path(&from, Access::Write, "rename", |vfs, from| match vfs {
+                Some(vfs) => vfs.rename(from, &to),
+                None => fs::rename(from, &to),
+            })
+        });
+        self.errno_result(result.map(|()| 0))
+    }
+
+    fn sys_getcwd(&mut self, args: &[u64]) -> Result<Vec<u64>, ExecutionError> {
+        let cwd = self.cwd.to_string_lossy().into_owned();
+        self.write_truncated(args[0], args[1], cwd.as_bytes())
+    }
+
+    fn sys_chdir(&mut self, args: &[u64]) -> Result<Vec<u64>, ExecutionError> {
+        let path = self.read_path(args[0], args[1])?;
+        let result = self
+            .with_path(
+                &path,
+                Access::Read,
+                "change directory to",
+                |vfs, at| match stat_at(vfs, at)?.kind {
+                    FileKind::Dir => Ok(()),
+                    _ => Err(io::ErrorKind::NotADirectory.into()),
+                },
+            )
+            .and_then(|()| self.resolve_path(&path, Access::Read))
+            .map(|(guest, _)| guest);
+        let result = result.map(|guest| {
+            kernel_log!(self.log, "cwd {}", guest.display());
+            self.cwd = guest;
+            0
+        });
+        self.errno_result(result)
+    }
+
+    fn sys_raw_tty_switch(&mut self, args: &[u64]) -> Result<Vec<u64>, ExecutionError> {
+        let raw = args[0] != 0;
+        kernel_log!(self.log, "raw tty {raw}");
+        let result = self.tty.set_raw(raw).map(|()| 0);
+        self.errno_result(result)
+    }
+
+    fn sys_tty_rel_cursor(&mut self, args: &[u64]) -> Result<Vec<u64>, ExecutionError> {
+        let sequence = self.tty.move_by(args[0] as i64, args[1] as i64);
+        let result = self.write_tty(&sequence);
+        self.errno_result(result)
+    }
+
+    fn sys_tty_abs_cursor(&mut self, args: &[u64]) -> Result<Vec<u64>, ExecutionError> {
+        let result = self
+            .tty
+            .move_to(args[0], args[1])
+            .and_then(|sequence| self.write_tty(&sequence));
+        self.errno_result(result)
+    }
+
+    fn sys_tty_control(&mut self, args: &[u64]) -> Result<Vec<u64>, ExecutionError> {
+        let result = self
+            .tty
+            .control(args[0], args[1])
+            .and_then(|sequence| self.write_tty(&sequence));
+        self.errno_result(result)
+    }
+
+    fn sys_tty_size(&mut self, args: &[u64]) -> Result<Vec<u64>, ExecutionError> {
+        let (columns, rows) = match self.tty.size() {
+            Ok(size) => size,
+            Err(e) => return self.errno_result(Err(e)),
+        };
+        let size: Vec<u8> = [columns as u64, rows as u64]
+            .iter()
+            .flat_map(|word| word.to_le_bytes())
+            .collect();
+        self.system.memory.write(args[0], &size)?;
+        Ok(vec![0])
+    }
+
+    fn sys_poll_input(&mut self, args: &[u64]) -> Result<Vec<u64>, ExecutionError> {
+        let result = self.tty.poll(args[0]).map(u64::from);
+        self.errno_result(result)
+    }
+
+    fn sys_read_key_event(&mut self, args: &[u64]) -> Result<Vec<u64>, ExecutionError> {
+        let result = self.tty.read_key(args[0]).map(|key| key.unwrap_or(0));
+        self.errno_result(result)
+    }
+
+    fn sys_readline(&mut self, args: &[u64]) -> Result<Vec<u64>, ExecutionError> {
+        let prompt = self.system.memory.read(args[0], args[1])?;
+        let prompt = String::from_utf8_lossy(&prompt).into_owned();
+        match self.read_line(&prompt) {
+            Ok(Some(line)) => self.write_truncated(args[2], args[3], line.as_bytes()),
+            Ok(None) => Ok(vec![READLINE_EOF]),
+            Err(e) => self.errno_result(Err(e)),
+        }
+    }
+
+    /// edits a line on the host terminal, or reads it from fd 0 if stdin is not a terminal
+    fn read_line(&mut self, prompt: &str) -> Result<Option<String>, ExecutionError> {
+        let interactive = self.interactive;
+        let stdout = self.get_interface(1)?;
+        if !interactive {
+            stdout.write(prompt.as_bytes())?;
+            stdout.flush()?;
+            return self.read_stdin_line();
+        }
+        stdout.flush()?;
+        let editor = match &mut self.line_editor {
+            Some(editor) => editor,
+            editor => editor.insert(LineEditor::new(self.history_file.clone())?),
+        };
+        editor.readline(prompt)
+    }
+
+    /// reads up to a newline from fd 0, None at the end of input
+    fn read_stdin_line(&mut self) -> Result<Option<String>, ExecutionError> {
+        let stdin = self.get_interface(0)?;
+        let mut line = Vec::new();
+        loop {
+            match stdin.read(1)?.first() {
+                None if line.is_empty() => return Ok(None),
+                None | Some(b'\n') => break,
+                Some(&byte) => line.push(byte),
             }
-            0x17 => {
-                let addr = self.system.pop()?;
-                kernel_log!("memquery({addr})");
-                let region = self.system.memory.memquery(addr);
-                self.system.push(region as u64)?;
-                Ok(())
+        }
+        if line.last() == Some(&b'\r') {
+            line.pop();
+        }
+        Ok(Some(String::from_utf8_lossy(&line).into_owned()))
+    }
+
+    /// writes terminal escape sequences to the guest's stdout and flushes it
+    fn write_tty(&mut self, sequence: &[u8]) -> Result<u64, ExecutionError> {
+        let stdout = self.get_interface(1)?;
+        stdout.write(sequence)?;
+        stdout.flush()?;
+        Ok(0)
+    }
+
+    /// leaves raw mode and resets the colors and cursor the guest changed
+    pub fn restore_tty(&mut self) {
+        let sequence = self.tty.restore();
+        if !sequence.is_empty() {
+            if let Err(e) = self.write_tty(&sequence) {
+                kernel_log!(self.log, "could not restore the terminal: {}", e.error);
             }
         }
     }
 
-    pub fn run(&mut self) -> Result<(), ExecutionError> {
+    fn sys_dump(&mut self, _args: &[u64]) -> Result<Vec<u64>, ExecutionError> {
+        self.core_dump()?;
+        Ok(vec![])
+    }
+
+    fn sys_get_argc(&mut self, _args: &[u64]) -> Result<Vec<u64>, ExecutionError> {
+        Ok(vec![self.cmdline.len() as u64])
+    }
+
+    fn sys_get_argv(&mut self, args: &[u64]) -> Result<Vec<u64>, ExecutionError> {
+        let arg_idx = args[0];
+        let arg = self
+            .cmdline
+            .get(arg_idx as usize)
+            .ok_or(ExecutionError::fault(
+                ErrorKind::SyscallArgument { code: 0x16 },
+                format!(
+                    "argv[{arg_idx}] out of bounds; argc: {}",
+                    self.cmdline.len()
+                ),
+            ))?;
+        let len = arg.len() as u64;
+        let ptr = self.system.memory.malloc(len)?;
+        self.system.memory.write(ptr, arg.as_bytes())?;
+        Ok(vec![ptr, len])
+    }
+
+    fn sys_memquery(&mut self, args: &[u64]) -> Result<Vec<u64>, ExecutionError> {
+        Ok(vec![self.system.memory.memquery(args[0]) as u64])
+    }
+
+    fn sys_set_fault_handler(&mut self, args: &[u64]) -> Result<Vec<u64>, ExecutionError> {
+        self.fault_handler = args[0];
+        Ok(vec![])
+    }
+
+    fn sys_exit(&mut self, args: &[u64]) -> Result<Vec<u64>, ExecutionError> {
+        self.exit_status = Some(args[0]);
+        Ok(vec![])
+    }
+
+    fn sys_set_interrupt_handler(&mut self, args: &[u64]) -> Result<Vec<u64>, ExecutionError> {
+        *self.user_interrupt_slot(args[0], 0x1a)? = args[1];
+        Ok(vec![])
+    }
+
+    fn sys_clear_interrupt_handler(&mut self, args: &[u64]) -> Result<Vec<u64>, ExecutionError> {
+        *self.user_interrupt_slot(args[0], 0x1b)? = 0;
+        Ok(vec![])
+    }
+
+    fn sys_set_irq_enabled(&mut self, args: &[u64]) -> Result<Vec<u64>, ExecutionError> {
+        self.irq.set_enabled(args[0] != 0);
+        Ok(vec![])
+    }
+
+    fn sys_set_irq_mask(&mut self, args: &[u64]) -> Result<Vec<u64>, ExecutionError> {
+        Ok(vec![self.irq.set_mask(args[0])])
+    }
+
+    fn sys_set_timer(&mut self, args: &[u64]) -> Result<Vec<u64>, ExecutionError> {
+        self.irq
+            .set_timer(self.system.cycles, args[0], args[1] != 0);
+        Ok(vec![])
+    }
+
+    fn sys_get_input_event(&mut self, _args: &[u64]) -> Result<Vec<u64>, ExecutionError> {
+        Ok(vec![self.input_events.pop_front().unwrap_or(0)])
+    }
+
+    fn sys_wait_for_interrupt(&mut self, _args: &[u64]) -> Result<Vec<u64>, ExecutionError> {
+        if !self.irq.enabled() {
+            return Err(ExecutionError::fault(
+                ErrorKind::SyscallArgument { code: 0x20 },
+                "waiting for an interrupt while irqs are disabled would never wake".to_string(),
+            ));
+        }
+        self.waiting = true;
+        Ok(vec![])
+    }
+
+    /// runs until the guest stops, returning its exit status
+    pub fn run(&mut self) -> Result<u64, ExecutionError> {
         self.core_dump()?;
         let millis = (1000.0 * (1.0 / self.clock_speed)) as u64;
         // println!("Cycle {millis}ms");
         let cycle_duration = Duration::from_millis(millis);
-        println!("cycle duration: {millis}ms from {}Hz", self.clock_speed);
-        loop {
-            std::thread::sleep(cycle_duration);
-            self.system.step()?;
+        self.log.print(&format!(
+            "cycle duration: {millis}ms from {}Hz",
+            self.clock_speed
+        ));
+        let status = loop {
+            if !cycle_duration.is_zero() {
+                std::thread::sleep(cycle_duration);
+            }
+            if let Some(status) = self.step()? {
+                break status;
+            }
+        };
+        self.finish(status);
+        Ok(status)
+    }
+
+    /// executes one instruction and the interrupt it raised,
+    /// returns the exit status once the guest has stopped
+    pub fn step(&mut self) -> Result<Option<u64>, ExecutionError> {
+        // a fault stops the vm, which must not leave the host terminal raw
+        self.execute().inspect_err(|_| {
+            self.restore_tty();
+            if let Err(e) = self.core_dump() {
+                kernel_log!(self.log, "{}", e.error);
+            }
+        })
+    }
+
+    fn execute(&mut self) -> Result<Option<u64>, ExecutionError> {
+        if self.exit_status.is_some() {
+            return Ok(self.exit_status);
+        }
+        if self.waiting {
+            self.system.cycles += 1;
+        } else {
+            if !self.debug_hook()? {
+                return Ok(Some(*self.exit_status.insert(0)));
+            }
+            let pc = self.system.registers.read(PROGRAM_COUNTER);
+            if let Err(e) = self.system.step(&self.debug_symbols) {
+                self.deliver_fault(e)?;
+            }
             match self.system.pending_interrupt {
-                0x00 => continue,
-                0x14 => break,
+                0x00 => (),
+                0x14 => {
+                    self.exit_status.get_or_insert(0);
+                }
                 _ => {
                     // kernel_log!("decoding {:#x}", self.system.pending_interrupt);
-                    self.handle_interrupt(self.system.pending_interrupt)?;
+                    self.handle_interrupt(self.system.pending_interrupt)
+                        .map_err(|e| e.locate(pc, self.system.cycles))?;
                     self.system.pending_interrupt = 0;
                 }
             }
         }
+        if self.exit_status.is_none() {
+            self.poll_devices();
+            self.deliver_irq()?;
+        }
+        Ok(self.exit_status)
+    }
+
+    /// advances the timer and collects frame buffer window input, raising their irqs
+    fn poll_devices(&mut self) {
+        self.irq.tick(self.system.cycles);
+        let Some(gpu) = self.gpu.as_mut() else {
+            return;
+        };
+        let queued = self.input_events.len();
+        if gpu.poll_events(&mut self.input_events) {
+            self.log
+                .print("GPU Framebuffer terminated (host window closed by user): shutting down.");
+            self.gpu = None;
+            self.exit_status.get_or_insert(0);
+        }
+        if self.input_events.len() > queued {
+            self.irq.raise(Irq::Input);
+        }
+    }
+
+    /// preempts the guest with the next deliverable irq, irqs are held while a guest
+    /// interrupt handler runs
+    fn deliver_irq(&mut self) -> Result<(), ExecutionError> {
+        if self.system.interrupt_depth > 0 {
+            return Ok(());
+        }
+        let Some(irq) = self.irq.take() else {
+            return Ok(());
+        };
+        self.waiting = false;
+        let handler = self.resolve_user_interrupt(irq.code());
+        if handler == 0 {
+            kernel_log!(
+                self.log,
+                "{irq:?} irq dropped: no handler for {:#x}",
+                irq.code()
+            );
+            return Ok(());
+        }
+        self.enter_user_interrupt(irq.code(), handler)
+    }
+
+    /// restores the terminal, reports the exit to the debugger and keeps the frame buffer window
+    /// open until it is closed
+    pub fn finish(&mut self, status: u64) {
+        self.restore_tty();
+        if let Some(debugger) = self.debugger.as_mut() {
+            debugger.exited(status);
+        }
 
         if let Some(gpu) = self.gpu.as_mut() {
             loop {
@@ -269,7 +1087,6 @@ impl Kernel {
                 }
             }
         }
-        Ok(())
     }
     pub fn gpu_fb_refresh(&mut self) -> Result<(), ExecutionError> {
         // let gpu = self.gpu.as_mut().unwrap();
@@ -283,18 +1100,22 @@ impl Kernel {
                 &self.system.memory.physical[gpu.stdmem_frame_buffer_ptr as usize..end_addr];
             gpu.draw(frame_buffer)?;
         } else {
-            kernel_log!("refresh call ignored: gpu not initialized");
+            kernel_log!(self.log, "refresh call ignored: gpu not initialized");
         }
         Ok(())
     }
     pub fn core_dump(&mut self) -> Result<(), ExecutionError> {
         const CORE: &str = "nisvc.core";
-        let mut core_file = File::create(format!("{CORE}.{}", self.cores_dumped))
-            .map_err(|e| ExecutionError::new(format!("failed to dump core: {e}")))?;
+        if !self.core_dumps {
+            return Ok(());
+        }
+        let mut core_file =
+            File::create(self.core_dir.join(format!("{CORE}.{}", self.cores_dumped)))
+                .map_err(|e| ExecutionError::io(&e, format!("failed to dump core: {e}")))?;
         core_file
             .write_all(&self.system.memory.physical)
-            .map_err(|e| ExecutionError::new(format!("failed to dump core: {e}")))?;
-        println!("{}", "core dumped".on_red());
+            .map_err(|e| ExecutionError::io(&e, format!("failed to dump core: {e}")))?;
+        self.log.print(&"core dumped".on_red().to_string());
         self.cores_dumped += 1;
         Ok(())
     }
@@ -303,21 +1124,90 @@ impl Kernel {
     fn get_interface(&mut self, file_descriptor: u64) -> Result<&mut IOInterface, ExecutionError> {
         self.file_descriptor_vector
             .get_mut(&file_descriptor)
-            .ok_or(ExecutionError::new(format!(
-                "not a valid file descriptor: `{file_descriptor}`"
-            )))
+            .ok_or(ExecutionError::fault(
+                ErrorKind::BadFileDescriptor,
+                format!("not a valid file descriptor: `{file_descriptor}`"),
+            ))
+    }
+    /// reads a guest path string as the guest passed it
+    fn read_path(&self, ptr: u64, len: u64) -> Result<PathBuf, ExecutionError> {
+        let str_bytes = self.system.memory.read(ptr, len)?;
+        Ok(PathBuf::from(String::from_utf8_lossy(&str_bytes).as_ref()))
+    }
+    /// the absolute guest path and the host path of `path`, every path taking syscall goes
+    /// through here so the sandbox sees each access
+    fn resolve_path(
+        &self,
+        path: &Path,
+        access: Access,
+    ) -> Result<(PathBuf, PathBuf), ExecutionError> {
+        let resolved = self
+            .sandbox
+            .resolve(&self.cwd, path, access)
+            .and_then(|guest| {
+                let host = self.sandbox.host_path(&guest)?;
+                Ok((guest, host))
+            });
+        resolved.inspect_err(|e| kernel_log!(self.log, "{}", e.error))
     }
-    fn open_file(&mut self, path: &str) -> Result<u64, ExecutionError> {
-        let file = File::open(path)
-            .map_err(|e| ExecutionError::new(format!("could not open file `{path}`: {e}")))?;
+    /// the path file operations use, the guest path on the in-memory filesystem
+    fn backend_path(&self, path: &Path, access: Access) -> Result<PathBuf, ExecutionError> {
+        let (guest, host) = self.resolve_path(path, access)?;
+        Ok(if self.vfs.is_some() { guest } else { host })
+    }
+    /// runs a file operation on the backend path of `path`, `what` describes it in errors
+    fn with_path<T>(
+        &mut self,
+        path: &Path,
+        access: Access,
+        what: &str,
+        op: impl FnOnce(Option<&mut Vfs>, &Path) -> io::Result<T>,
+    ) -> Result<T, ExecutionError> {
+        let at = self.backend_path(path, access)?;
+        op(self.vfs.as_mut(), &at).map_err(|e| io_error(&e, what, path))
+    }
+    /// copies as much of `bytes` as fits in the guest buffer, returning the full length
+    fn write_truncated(
+        &mut self,
+        ptr: u64,
+        cap: u64,
+        bytes: &[u8],
+    ) -> Result<Vec<u64>, ExecutionError> {
+        let n = bytes.len().min(cap as usize);
+        self.system.memory.write(ptr, &bytes[..n])?;
+        Ok(vec![bytes.len() as u64])
+    }
+    fn insert_interface(&mut self, interface: IOInterface) -> u64 {
+        self.file_descriptor_vector.insert(self.next_fd, interface);
+        self.next_fd += 1;
+        self.next_fd - 1
+    }
+    fn open_file(&mut self, path: &Path, mode: OpenMode) -> Result<u64, ExecutionError> {
+        let access = match mode.modifies() {
+            true => Access::Write,
+            false => Access::Read,
+        };
+        let file = self.with_path(path, access, "open file", |vfs, at| match vfs {
+            Some(vfs) => vfs.open(at, mode).map(IOInterface::Virtual),
+            None => mode.options().open(at).map(IOInterface::File),
+        })?;
 
         // file.read_to_end(&mut buf)
         //     .map_err(|e| ExecutionError::new(format!("could not read file `{path}`: {e}")))?;
         // let f = FileWrapper::Reader(Box::new(stdin()));
-        self.file_descriptor_vector
-            .insert(self.next_fd, IOInterface::File(file));
-        self.next_fd += 1;
-        Ok(self.next_fd - 1)
+        Ok(self.insert_interface(file))
+    }
+    /// the next entry name of a directory opened with opendir, None once exhausted
+    fn read_dir_entry(&mut self, file_descriptor: u64) -> Result<Option<String>, ExecutionError> {
+        let IOInterface::Dir { entries, .. } = self.get_interface(file_descriptor)? else {
+            return Err(ExecutionError::fault(
+                ErrorKind::Io {
+                    errno: Errno::NotDir,
+                },
+                format!("file descriptor {file_descriptor} is not a directory"),
+            ));
+        };
+        Ok(entries.next())
     }
     fn read_file(&mut self, file_descriptor: u64, n: u64) -> Result<Vec<u8>, ExecutionError> {
         self.get_interface(file_descriptor)?.read(n)
@@ -325,91 +1215,183 @@ impl Kernel {
     fn write_file(&mut self, file_descriptor: u64, buffer: &[u8]) -> Result<(), ExecutionError> {
         self.get_interface(file_descriptor)?.write(buffer)
     }
-    fn stat_file(&mut self, file_descriptor: u64) -> Result<Metadata, ExecutionError> {
+    fn seek_file(&mut self, file_descriptor: u64, from: SeekFrom) -> Result<u64, ExecutionError> {
+        self.get_interface(file_descriptor)?.seek(from)
+    }
+    fn stat_file(&mut self, file_descriptor: u64) -> Result<Stat, ExecutionError> {
         self.get_interface(file_descriptor)?.stat()
     }
     fn close_file(&mut self, file_descriptor: u64) -> Result<(), ExecutionError> {
-        if file_descriptor < 4 {
-            return Err(ExecutionError::new(format!(
-                "cannot close stdin/stdout/stderr"
-            )));
+        if file_descriptor < 3 {
+            return Err(ExecutionError::fault(
+                ErrorKind::BadFileDescriptor,
+                "cannot close stdin/stdout/stderr".to_string(),
+            ));
         }
-        self.file_descriptor_vector.remove(&file_descriptor);
-        Ok(())
+        self.file_descriptor_vector
+            .remove(&file_descriptor)
+            .map(|_| ())
+            .ok_or(ExecutionError::fault(
+                ErrorKind::BadFileDescriptor,
+                format!("file descriptor {file_descriptor} is not open"),
+            ))
+    }
+}
+
+/// stats a backend path, on the in-memory filesystem if there is one
+fn stat_at(vfs: Option<&mut Vfs>, at: &Path) -> io::Result<Stat> {
+    match vfs {
+        Some(vfs) => vfs.stat(at),
+        None => fs::metadata(at).map(|metadata| Stat::from(&metadata)),
     }
 }
 
+/// a failed filesystem operation on `path`
+fn io_error(e: &std::io::Error, operation: &str, path: &Path) -> ExecutionError {
+    ExecutionError::io(
+        e,
+        format!("could not {operation} `{}`: {e}", path.display()),
+    )
+}
+
 enum IOInterface {
-    Stdin(Stdin),
-    Stdout(Stdout),
-    Stderr(Stderr),
+    Stdin(Box<dyn Read>),
+    Stdout(Box<dyn Write>),
+    Stderr(Box<dyn Write>),
     File(File),
+    /// a file of the in-memory filesystem
+    Virtual(VirtualFile),
+    /// a directory opened with opendir, `entries` is advanced by readdir
+    Dir {
+        stat: Stat,
+        entries: std::vec::IntoIter<String>,
+    },
 }
 impl IOInterface {
+    /// reads at most `n` bytes, fewer at the end of the stream
     fn read(&mut self, n: u64) -> Result<Vec<u8>, ExecutionError> {
         let mut buffer = vec![0; n as usize];
         let bytes_read = match self {
             IOInterface::Stdin(stdin) => stdin.read(&mut buffer),
             IOInterface::Stdout(_) => {
-                return Err(ExecutionError::new(format!("cannot read from stdout")))
+                return Err(ExecutionError::fault(
+                    ErrorKind::BadFileDescriptor,
+                    "cannot read from stdout".to_string(),
+                ))
             }
             IOInterface::Stderr(_) => {
-                return Err(ExecutionError::new(format!("cannot read from stderr")))
+                return Err(ExecutionError::fault(
+                    ErrorKind::BadFileDescriptor,
+                    "cannot read from stderr".to_string(),
+                ))
             }
             IOInterface::File(file) => file.read(&mut buffer),
+            IOInterface::Virtual(file) => file.read(&mut buffer),
+            IOInterface::Dir { .. } => {
+                return Err(ExecutionError::fault(
+                    ErrorKind::Io {
+                        errno: Errno::IsDir,
+                    },
+                    "cannot read a directory".to_string(),
+                ))
+            }
         }
-        .map_err(|e| ExecutionError::new(format!("failed to read from io stream: `{e}`")))?;
-        if bytes_read != n as usize {
-            return Err(ExecutionError::new(format!("incomplete read from reader attempted to read {n} bytes but only read back {bytes_read}\n{buffer:#?}")));
-        } else {
-            Ok(buffer)
-        }
-        // Ok(buffer)
+        .map_err(|e| ExecutionError::io(&e, format!("failed to read from io stream: `{e}`")))?;
+        buffer.truncate(bytes_read);
+        Ok(buffer)
     }
     fn write(&mut self, buffer: &[u8]) -> Result<(), ExecutionError> {
         match self {
             IOInterface::Stdin(_) => {
-                return Err(ExecutionError::new(format!("cannot write to stdin")))
+                return Err(ExecutionError::fault(
+                    ErrorKind::BadFileDescriptor,
+                    "cannot write to stdin".to_string(),
+                ))
             }
             IOInterface::Stdout(stdout) => stdout.write_all(buffer),
             IOInterface::Stderr(stderr) => stderr.write_all(buffer),
             IOInterface::File(file) => file.write_all(buffer),
+            IOInterface::Virtual(file) => file.write_all(buffer),
+            IOInterface::Dir { .. } => {
+                return Err(ExecutionError::fault(
+                    ErrorKind::Io {
+                        errno: Errno::IsDir,
+                    },
+                    "cannot write a directory".to_string(),
+                ))
+            }
         }
-        .map_err(|e| ExecutionError::new(format!("failed to write to io stream: `{e}`")))?;
+        .map_err(|e| ExecutionError::io(&e, format!("failed to write to io stream: `{e}`")))?;
         Ok(())
     }
-    fn seek(&mut self, offset: i64) -> Result<(), ExecutionError> {
+    /// pushes out what the std streams buffered, other interfaces are unbuffered
+    fn flush(&mut self) -> Result<(), ExecutionError> {
         match self {
-            IOInterface::Stdin(stdin) => {
-                return Err(ExecutionError::new(format!("cannot seek stdin")))
-            }
-            IOInterface::Stdout(stdout) => {
-                return Err(ExecutionError::new(format!("cannot seek stdout")))
-            }
-            IOInterface::Stderr(stderr) => {
-                return Err(ExecutionError::new(format!("cannot seek stderr")))
-            }
-            IOInterface::File(file) => file.seek_relative(offset),
+            IOInterface::Stdout(stream) | IOInterface::Stderr(stream) => stream
+                .flush()
+                .map_err(|e| ExecutionError::io(&e, format!("failed to flush io stream: `{e}`"))),
+            _ => Ok(()),
         }
-        .map_err(|e| ExecutionError::new(format!("failed to seek io stream: `{e}`")))
     }
-    fn stat(&mut self) -> Result<Metadata, ExecutionError> {
+    fn seek(&mut self, from: SeekFrom) -> Result<u64, ExecutionError> {
         match self {
-            IOInterface::Stdin(stdin) => {
-                return Err(ExecutionError::new(format!("cannot seek stdin")))
+            IOInterface::Stdin(_) => {
+                return Err(ExecutionError::fault(
+                    ErrorKind::Io {
+                        errno: Errno::SPipe,
+                    },
+                    "cannot seek stdin".to_string(),
+                ))
             }
-
-            IOInterface::Stdout(stdout) => {
-                return Err(ExecutionError::new(format!("cannot stat stdout")))
+            IOInterface::Stdout(_) => {
+                return Err(ExecutionError::fault(
+                    ErrorKind::Io {
+                        errno: Errno::SPipe,
+                    },
+                    "cannot seek stdout".to_string(),
+                ))
             }
-
-            IOInterface::Stderr(stderr) => {
-                return Err(ExecutionError::new(format!("cannot stat stderr")))
+            IOInterface::Stderr(_) => {
+                return Err(ExecutionError::fault(
+                    ErrorKind::Io {
+                        errno: Errno::SPipe,
+                    },
+                    "cannot seek stderr".to_string(),
+                ))
             }
-
+            IOInterface::File(file) => file.seek(from),
+            IOInterface::Virtual(file) => file.seek(from),
+            IOInterface::Dir { .. } => {
+                return Err(ExecutionError::fault(
+                    ErrorKind::Io {
+                        errno: Errno::IsDir,
+                    },
+                    "cannot seek a directory".to_string(),
+                ))
+            }
+        }
+        .map_err(|e| ExecutionError::io(&e, format!("failed to seek io stream: `{e}`")))
+    }
+    fn stat(&mut self) -> Result<Stat, ExecutionError> {
+        match self {
+            IOInterface::Stdin(_) => Err(ExecutionError::fault(
+                ErrorKind::BadFileDescriptor,
+                "cannot stat stdin".to_string(),
+            )),
+            IOInterface::Stdout(_) => Err(ExecutionError::fault(
+                ErrorKind::BadFileDescriptor,
+                "cannot stat stdout".to_string(),
+            )),
+            IOInterface::Stderr(_) => Err(ExecutionError::fault(
+                ErrorKind::BadFileDescriptor,
+                "cannot stat stderr".to_string(),
+            )),
             IOInterface::File(file) => file
                 .metadata()
-                .map_err(|e| ExecutionError::new(format!("failed to stat io stream: `{e}`"))),
+                .map(|metadata| Stat::from(&metadata))
+                .map_err(|e| ExecutionError::io(&e, format!("failed to stat io stream: `{e}`"))),
+            IOInterface::Virtual(file) => Ok(file.stat()),
+            IOInterface::Dir { stat, .. } => Ok(*stat),
         }
     }
 }
@@ -0,0 +1,176 @@
+// nisvc virtual machine rewrite
+pub mod assembler;
+pub mod constant;
+pub mod cpu;
+pub mod dap;
+pub mod debug_shell;
+pub mod debugger;
+pub mod disassembler;
+pub mod gdb;
+pub mod gpu;
+pub mod image;
+pub mod irq;
+pub mod kernel;
+pub mod loader;
+pub mod logger;
+pub mod memory;
+pub mod opcode;
+pub mod sandbox;
+pub mod syscall;
+pub mod tty;
+pub mod vfs;
+pub mod vm;
+pub mod window;
+use std::{fmt, io};
+
+use colorize::AnsiColor;
+use syscall::Errno;
+pub use vm::{Outcome, Vm, VmBuilder};
+
+/// what caused an execution error, each kind exits the host with its own status
+#[derive(Debug, Clone, PartialEq)]
+pub enum ErrorKind {
+    /// host side failure that is not attributable to the guest
+    Generic,
+    /// the executable could not be loaded
+    InvalidProgram,
+    /// a host io operation failed on behalf of the guest, `errno` is what the guest is told
+    Io {
+        errno: Errno,
+    },
+    /// `region` is what `Memory::memquery` reports for `address`
+    MemoryAccessViolation {
+        address: u64,
+        region: u8,
+    },
+    OutOfMemory {
+        size: u64,
+    },
+    HeapCorruption,
+    /// undecodable instruction or unusable operand
+    IllegalInstruction,
+    DivisionByZero,
+    BadFileDescriptor,
+    /// a syscall was passed an argument it cannot act on
+    SyscallArgument {
+        code: u8,
+    },
+    UnknownSyscall {
+        code: u8,
+    },
+}
+
+impl ErrorKind {
+    /// host exit status, always above [`MAX_GUEST_EXIT_STATUS`] so faults never look like a
+    /// status the guest exited with
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            ErrorKind::InvalidProgram => 160,
+            ErrorKind::Generic => 161,
+            ErrorKind::Io { .. } => 162,
+            ErrorKind::IllegalInstruction => 132,
+            ErrorKind::HeapCorruption => 134,
+            ErrorKind::DivisionByZero => 136,
+            ErrorKind::OutOfMemory { .. } => 137,
+            ErrorKind::MemoryAccessViolation { .. } => 139,
+            ErrorKind::BadFileDescriptor => 157,
+            ErrorKind::SyscallArgument { .. } => 158,
+            ErrorKind::UnknownSyscall { .. } => 159,
+        }
+    }
+}
+
+/// the highest host exit status a guest can exit with, the statuses above are the vm's
+pub const MAX_GUEST_EXIT_STATUS: u64 = 127;
+
+/// host exit status for a guest exit status, statuses in the vm's range are clamped to
+/// [`MAX_GUEST_EXIT_STATUS`] so they still read as a failure
+pub fn guest_exit_code(status: u64) -> i32 {
+    status.min(MAX_GUEST_EXIT_STATUS) as i32
+}
+
+#[derive(Debug)]
+pub struct ExecutionError {
+    pub error: String,
+    pub kind: ErrorKind,
+    /// guest pc the error occurred at, None for host errors
+    pub pc: Option<u64>,
+    /// cpu cycle the error occurred at, None for host errors
+    pub cycle: Option<u64>,
+}
+
+impl ExecutionError {
+    pub fn new(error: String) -> Self {
+        Self::fault(ErrorKind::Generic, error)
+    }
+    pub fn fault(kind: ErrorKind, error: String) -> Self {
+        Self {
+            error,
+            kind,
+            pc: None,
+            cycle: None,
+        }
+    }
+    /// a failed host io operation, the guest is told the errno matching `e`
+    pub fn io(e: &io::Error, error: String) -> Self {
+        Self::fault(ErrorKind::Io { errno: e.into() }, error)
+    }
+    pub fn prepend(mut self, prelude: String) -> Self {
+        self.error = prelude + self.error.as_str();
+        self
+    }
+    /// records where the guest was when the error occurred, keeping a location already set
+    pub fn locate(mut self, pc: u64, cycle: u64) -> Self {
+        self.pc = self.pc.or(Some(pc));
+        self.cycle = self.cycle.or(Some(cycle));
+        self
+    }
+    pub fn exit_code(&self) -> i32 {
+        self.kind.exit_code()
+    }
+}
+
+impl fmt::Display for ExecutionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", "error >".b_red().bold(), self.error) // make cooler
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fault_statuses_stay_out_of_the_guest_range() {
+        let kinds = [
+            ErrorKind::Generic,
+            ErrorKind::InvalidProgram,
+            ErrorKind::Io {
+                errno: Errno::NoEnt,
+            },
+            ErrorKind::MemoryAccessViolation {
+                address: 0,
+                region: 0,
+            },
+            ErrorKind::OutOfMemory { size: 0 },
+            ErrorKind::HeapCorruption,
+            ErrorKind::IllegalInstruction,
+            ErrorKind::DivisionByZero,
+            ErrorKind::BadFileDescriptor,
+            ErrorKind::SyscallArgument { code: 0 },
+            ErrorKind::UnknownSyscall { code: 0 },
+        ];
+        for kind in kinds {
+            assert!(kind.exit_code() > MAX_GUEST_EXIT_STATUS as i32, "{kind:?}");
+        }
+    }
+
+    #[test]
+    fn guest_statuses_are_clamped_below_the_fault_range() {
+        assert_eq!(guest_exit_code(0), 0);
+        assert_eq!(guest_exit_code(70), 70);
+        assert_eq!(guest_exit_code(128), 127);
+        assert_eq!(guest_exit_code(256), 127);
+        assert_eq!(guest_exit_code(u64::MAX), 127);
+    }
+}
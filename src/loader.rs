@@ -1,71 +1,198 @@
-use std::{collections::HashMap, fs::File, io::Read, mem::transmute, slice, vec};
+use std::{
+    collections::{BTreeMap, HashMap},
+    num::ParseIntError,
+    slice, vec,
+};
 
-use crate::{constant::SIGNATURE, memory::bytes_to_u64, ExecutionError};
+use crate::{constant::SIGNATURE, memory::bytes_to_u64, ErrorKind, ExecutionError};
 
 pub struct NISVCEF {
     pub entry_point: u64,
     pub image: Vec<u8>,
     pub debug_symbols: DebugSymbols,
-    // pub break_points: Vec<u64>,
+    pub break_points: Vec<u64>,
 }
 
 /*
 
-[--signature--][--entry_point--][--img_length--][program][--labels_block_length--][--labels--]
+[--signature--][--entry_point--][--img_length--][program][--breakpoints_block_length--][--breakpoints--][--labels_block_length--][--labels--]([--lines_block_length--][--lines--])
+
+the lines block is optional, older executables end after the labels
+
+breakpoint encoding
+
+[--address--]
 
 label encoding
 
 [--address--][--label_len--][--label--]
 
+lines encoding, the source file followed by the line of each instruction
+
+[--source_len--][--source--]([--address--][--line--])*
+
 */
 
 impl NISVCEF {
     pub fn load(file: Vec<u8>) -> Result<Self, ExecutionError> {
         let mut stream = file.into_iter();
         let read_signature = stream.by_ref().take(SIGNATURE.len()).collect::<Vec<u8>>();
-        if SIGNATURE != &read_signature {
-            return Err(ExecutionError::new(format!("Signature invalid : {}", {
-                String::from_utf8_lossy(&read_signature)
-            })));
+        if SIGNATURE != read_signature {
+            return Err(ExecutionError::fault(
+                ErrorKind::InvalidProgram,
+                format!("Signature invalid : {}", {
+                    String::from_utf8_lossy(&read_signature)
+                }),
+            ));
         }
         let entry_point = consume_double_word_vec(&mut stream)?;
         let program_img_len = consume_double_word_vec(&mut stream)?;
-        let image: Vec<u8> = stream.by_ref().take(program_img_len as usize).collect();
+        let image = consume_block(&mut stream, program_img_len, "program image")?;
         let break_point_len = consume_double_word_vec(&mut stream)?;
-        // let break_points: Vec<u64> =
-        //     build_breakpoint_vector(stream.by_ref().take(break_point_len as usize).collect())?; // convert to Vec<u64> likely gonna do some evil unsafe thing
+        let break_points =
+            build_breakpoint_vector(consume_block(&mut stream, break_point_len, "breakpoints")?)?;
         let debug_symbols_len = consume_double_word_vec(&mut stream)?;
-        let debug_symbols_len = 0;
-        let debug_symbols_img: Vec<u8> = stream.by_ref().take(debug_symbols_len as usize).collect();
-        println!("entry_point: {entry_point:#x}\nprogram_img_len: {program_img_len:#x}\ndebug_symbols_len:{debug_symbols_len}\ndebug_symbols_img:{debug_symbols_img:x?}");
+        let debug_symbols_img = consume_block(&mut stream, debug_symbols_len, "labels")?;
 
-        let debug_symbols = DebugSymbols::load_symbols(&debug_symbols_img)?;
+        let mut debug_symbols = DebugSymbols::load_symbols(&debug_symbols_img)?;
+        if stream.len() > 0 {
+            let lines_len = consume_double_word_vec(&mut stream)?;
+            debug_symbols.load_lines(&consume_block(&mut stream, lines_len, "lines")?)?;
+        }
         Ok(Self {
             entry_point,
             image,
             debug_symbols,
-            // break_points,
+            break_points,
         })
     }
+
+    /// returns the label pointing exactly at an address
+    pub fn label_at(&self, addr: u64) -> Option<&str> {
+        self.debug_symbols.label_at(addr)
+    }
+
+    /// returns the address a label points to
+    pub fn address_of(&self, label: &str) -> Option<u64> {
+        self.debug_symbols.address_of(label)
+    }
+
+    /// returns the closest label at or before an address and the offset from it
+    pub fn nearest_label(&self, addr: u64) -> Option<(&str, u64)> {
+        self.debug_symbols.nearest_label(addr)
+    }
 }
 
-struct DebugSymbols {
-    labels: HashMap<u64, String>,
+#[derive(Default)]
+pub struct DebugSymbols {
+    labels: BTreeMap<u64, String>,
+    addresses: HashMap<String, u64>,
+    /// the assembly file the line table refers to
+    source: Option<String>,
+    /// source line of each instruction by address
+    lines: BTreeMap<u64, u64>,
+    /// address of the first instruction on each source line
+    line_addresses: BTreeMap<u64, u64>,
 }
 
 impl DebugSymbols {
+    pub fn is_empty(&self) -> bool {
+        self.labels.is_empty()
+    }
+
+    /// returns the address a label points to
+    pub fn address_of(&self, label: &str) -> Option<u64> {
+        self.addresses.get(label).copied()
+    }
+
+    /// returns the label pointing exactly at an address
+    pub fn label_at(&self, addr: u64) -> Option<&str> {
+        self.labels.get(&addr).map(|l| l.as_str())
+    }
+
+    /// returns the closest label at or before an address and the offset from it
+    pub fn nearest_label(&self, addr: u64) -> Option<(&str, u64)> {
+        self.labels
+            .range(..=addr)
+            .next_back()
+            .map(|(label_addr, label)| (label.as_str(), addr - label_addr))
+    }
+
+    /// renders an address as `label+offset`, or hex when no label precedes it
+    pub fn symbolize(&self, addr: u64) -> String {
+        match self.nearest_label(addr) {
+            Some((label, 0)) => label.to_string(),
+            Some((label, offset)) => format!("{label}+{offset:#x}"),
+            None => format!("{addr:#x}"),
+        }
+    }
+
+    /// resolves a label or an address, see [`parse_address`]. labels win over addresses
+    /// that are also valid hex, like `add`
+    pub fn resolve(&self, location: &str) -> Option<u64> {
+        self.address_of(location)
+            .or_else(|| parse_address(location).ok())
+    }
+
+    /// the assembly file the executable was built from, None without a line table
+    pub fn source(&self) -> Option<&str> {
+        self.source.as_deref()
+    }
+
+    /// returns the source line of the instruction at or before an address
+    pub fn line_of(&self, addr: u64) -> Option<u64> {
+        self.lines.range(..=addr).next_back().map(|(_, line)| *line)
+    }
+
+    /// returns the first instruction on `line` or on the next line holding one, and that line
+    pub fn address_of_line(&self, line: u64) -> Option<(u64, u64)> {
+        self.line_addresses
+            .range(line..)
+            .next()
+            .map(|(line, addr)| (*addr, *line))
+    }
+
+    fn load_lines(&mut self, serialized_block: &[u8]) -> Result<(), ExecutionError> {
+        let truncated = || {
+            ExecutionError::fault(
+                ErrorKind::InvalidProgram,
+                "lines block truncated".to_string(),
+            )
+        };
+        let mut block_stream = serialized_block.iter();
+        let source_len = consume_double_word_ref(&mut block_stream).ok_or_else(truncated)?;
+        let source = block_stream
+            .by_ref()
+            .take(source_len as usize)
+            .copied()
+            .collect::<Vec<u8>>();
+        if source.len() as u64 != source_len {
+            return Err(truncated());
+        }
+        self.source = (!source.is_empty()).then(|| String::from_utf8_lossy(&source).to_string());
+        while block_stream.len() > 0 {
+            let addr = consume_double_word_ref(&mut block_stream).ok_or_else(truncated)?;
+            let line = consume_double_word_ref(&mut block_stream).ok_or_else(truncated)?;
+            self.lines.insert(addr, line);
+            let first = self.line_addresses.entry(line).or_insert(addr);
+            *first = (*first).min(addr);
+        }
+        Ok(())
+    }
+
     fn load_symbols(serialized_block: &[u8]) -> Result<Self, ExecutionError> {
-        let mut labels: HashMap<u64, String> = HashMap::new();
-        let mut block_stream = serialized_block.into_iter();
-        loop {
-            let entry = Self::deserialize_entry(&mut block_stream)?;
-            if let Some(e) = entry {
-                labels.insert(e.0, e.1);
-            } else {
-                break;
-            }
+        let mut labels: BTreeMap<u64, String> = BTreeMap::new();
+        let mut addresses: HashMap<String, u64> = HashMap::new();
+        let mut block_stream = serialized_block.iter();
+        while let Some((addr, label)) = Self::deserialize_entry(&mut block_stream)? {
+            addresses.insert(label.clone(), addr);
+            labels.insert(addr, label);
         }
-        Ok(Self { labels })
+        Ok(Self {
+            labels,
+            addresses,
+            ..Self::default()
+        })
     }
 
     fn deserialize_entry(
@@ -82,27 +209,36 @@ impl DebugSymbols {
             return Ok(None);
         };
 
-        let mut label = String::new();
-
-        for i in 0..str_len {
-            println!("{label} {i}");
-            label.push(*stream.next().ok_or(ExecutionError::new(format!(
-                "error decoding label associated with address {addr:#x}"
-            )))? as char);
+        let mut label = Vec::with_capacity(str_len as usize);
+        for _ in 0..str_len {
+            label.push(*stream.next().ok_or(ExecutionError::fault(
+                ErrorKind::InvalidProgram,
+                format!("error decoding label associated with address {addr:#x}"),
+            ))?);
         }
-        Ok(Some((addr, label)))
+        Ok(Some((addr, String::from_utf8_lossy(&label).to_string())))
+    }
+}
+
+/// parses hex with an optional `0x` prefix, the syntax for addresses given on the command line
+/// and to debuggers
+pub fn parse_address(s: &str) -> Result<u64, ParseIntError> {
+    u64::from_str_radix(s.strip_prefix("0x").unwrap_or(s), 16)
+}
+
+/// parses `0x` prefixed hex or decimal, the syntax for values given to debuggers
+pub fn parse_number(s: &str) -> Result<u64, ParseIntError> {
+    match s.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => s.parse(),
     }
 }
 
 /// returns None if dw is incomplete
 fn consume_double_word_ref(stream: &mut slice::Iter<'_, u8>) -> Option<u64> {
     let mut buf: [u8; 8] = [0; 8];
-    for n in 0..8 {
-        buf[n] = if let Some(b) = stream.next() {
-            *b
-        } else {
-            return None;
-        }
+    for b in buf.iter_mut() {
+        *b = *stream.next()?;
     }
     Some(bytes_to_u64(&buf))
 }
@@ -112,31 +248,50 @@ fn consume_double_word_vec(stream: &mut vec::IntoIter<u8>) -> Result<u64, Execut
         buf[n] = if let Some(b) = stream.next() {
             b
         } else {
-            return Err(ExecutionError::new(format!(
-                "entry point incomplete : {:?}",
-                buf
-            )));
+            return Err(ExecutionError::fault(
+                ErrorKind::InvalidProgram,
+                format!("entry point incomplete : {:?}", buf),
+            ));
         }
     }
     Ok(bytes_to_u64(&buf))
 }
 
+fn consume_block(
+    stream: &mut vec::IntoIter<u8>,
+    len: u64,
+    name: &str,
+) -> Result<Vec<u8>, ExecutionError> {
+    let block: Vec<u8> = stream.by_ref().take(len as usize).collect();
+    if block.len() as u64 != len {
+        return Err(ExecutionError::fault(
+            ErrorKind::InvalidProgram,
+            format!(
+                "{name} block truncated: expected {len} bytes but found {}",
+                block.len()
+            ),
+        ));
+    }
+    Ok(block)
+}
+
 fn build_breakpoint_vector(image: Vec<u8>) -> Result<Vec<u64>, ExecutionError> {
     // check if breakpoint has 8 byte alignment
     // generate list of pointers to the vec at each 8 byte alignment and recast as u64 ptr
     // build vec by dereferencing list
 
-    if (image.len() % 8) != 0 {
-        return Err(ExecutionError::new(format!(
-            "breakpoint vector does not have an 8 byte alignment"
-        )));
+    if !image.len().is_multiple_of(8) {
+        return Err(ExecutionError::fault(
+            ErrorKind::InvalidProgram,
+            "breakpoint vector does not have an 8 byte alignment".to_string(),
+        ));
     }
 
     let mut buf: Vec<u64> = Vec::new();
 
     for chunk in image.chunks_exact(8) {
         let mut bytes: [u8; 8] = [0; 8];
-        for (i, byte) in chunk.into_iter().enumerate() {
+        for (i, byte) in chunk.iter().enumerate() {
             bytes[i] = *byte;
         }
         buf.push(u64::from_le_bytes(bytes));
@@ -150,3 +305,86 @@ fn build_breakpoint_vector(image: Vec<u8>) -> Result<Vec<u64>, ExecutionError> {
 
     Ok(buf)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// an executable with a two byte image and the given breakpoint and label blocks
+    fn executable(breakpoints: &[u8], labels: &[u8]) -> Vec<u8> {
+        let mut file = SIGNATURE.to_vec();
+        file.extend_from_slice(&0u64.to_le_bytes());
+        file.extend_from_slice(&2u64.to_le_bytes());
+        file.extend_from_slice(&[0xaa, 0xbb]);
+        file.extend_from_slice(&(breakpoints.len() as u64).to_le_bytes());
+        file.extend_from_slice(breakpoints);
+        file.extend_from_slice(&(labels.len() as u64).to_le_bytes());
+        file.extend_from_slice(labels);
+        file
+    }
+
+    fn label(addr: u64, name: &str) -> Vec<u8> {
+        let mut entry = addr.to_le_bytes().to_vec();
+        entry.extend_from_slice(&(name.len() as u64).to_le_bytes());
+        entry.extend_from_slice(name.as_bytes());
+        entry
+    }
+
+    #[test]
+    fn breakpoint_block_is_a_list_of_addresses() {
+        let mut breakpoints = 0x10u64.to_le_bytes().to_vec();
+        breakpoints.extend_from_slice(&0x2au64.to_le_bytes());
+        let nef = NISVCEF::load(executable(&breakpoints, &[])).unwrap();
+        assert_eq!(nef.image, [0xaa, 0xbb]);
+        assert_eq!(nef.break_points, [0x10, 0x2a]);
+        assert!(nef.debug_symbols.is_empty());
+
+        let e = NISVCEF::load(executable(&breakpoints[..12], &[]))
+            .err()
+            .unwrap();
+        assert_eq!(e.kind, ErrorKind::InvalidProgram);
+    }
+
+    #[test]
+    fn label_block_maps_both_ways() {
+        let mut labels = label(0, "main");
+        labels.extend(label(0x20, "loop"));
+        let nef = NISVCEF::load(executable(&[], &labels)).unwrap();
+        assert_eq!(nef.label_at(0x20), Some("loop"));
+        assert_eq!(nef.label_at(0x21), None);
+        assert_eq!(nef.address_of("main"), Some(0));
+
+        // a label cut short by the end of the block is an error
+        labels.truncate(labels.len() - 2);
+        let e = NISVCEF::load(executable(&[], &labels)).err().unwrap();
+        assert_eq!(e.kind, ErrorKind::InvalidProgram);
+    }
+
+    #[test]
+    fn nearest_label_is_at_or_before_the_address() {
+        let mut labels = label(0x8, "main");
+        labels.extend(label(0x20, "loop"));
+        let symbols = DebugSymbols::load_symbols(&labels).unwrap();
+        assert_eq!(symbols.nearest_label(0x4), None);
+        assert_eq!(symbols.nearest_label(0x8), Some(("main", 0)));
+        assert_eq!(symbols.nearest_label(0x1f), Some(("main", 0x17)));
+        assert_eq!(symbols.nearest_label(0x30), Some(("loop", 0x10)));
+        assert_eq!(symbols.symbolize(0x22), "loop+0x2");
+        assert_eq!(symbols.symbolize(0x4), "0x4");
+        assert_eq!(symbols.resolve("loop"), Some(0x20));
+        assert_eq!(symbols.resolve("0x20"), Some(0x20));
+        assert_eq!(symbols.resolve("20h"), None);
+    }
+
+    #[test]
+    fn bare_addresses_are_hex() {
+        let mut labels = label(0x8, "add");
+        labels.extend(label(0x20, "loop"));
+        let symbols = DebugSymbols::load_symbols(&labels).unwrap();
+        assert_eq!(symbols.resolve("100"), Some(0x100));
+        assert_eq!(symbols.resolve("0x100"), Some(0x100));
+        assert_eq!(symbols.resolve("ad"), Some(0xad));
+        assert_eq!(symbols.resolve("add"), Some(0x8));
+        assert_eq!(parse_number("100"), Ok(100));
+    }
+}
@@ -0,0 +1,148 @@
+use std::{
+    cell::RefCell,
+    io::{self, Write},
+    rc::Rc,
+};
+
+use colorize::AnsiColor;
+
+use crate::constant::NAME;
+
+/// which diagnostics a vm writes to its log sink
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LogConfig {
+    /// print every executed instruction
+    pub disassemble: bool,
+    /// 1 verbose, 2 very verbose, 3 very very verbose
+    pub verbosity: usize,
+    /// print NKS syscalls
+    pub kernel: bool,
+}
+
+struct LogState {
+    config: LogConfig,
+    /// guest location prefixed to log lines
+    pc: u64,
+    cycle: u64,
+    sink: Box<dyn Write>,
+}
+
+/// a vm's diagnostics output, clones share configuration, sink and guest location
+#[derive(Clone)]
+pub struct Logger(Rc<RefCell<LogState>>);
+
+impl Logger {
+    pub fn new(config: LogConfig, sink: impl Write + 'static) -> Self {
+        Self(Rc::new(RefCell::new(LogState {
+            config,
+            pc: 0,
+            cycle: 0,
+            sink: Box::new(sink),
+        })))
+    }
+
+    pub fn stdout(config: LogConfig) -> Self {
+        Self::new(config, io::stdout())
+    }
+
+    /// discards everything
+    pub fn silent() -> Self {
+        Self::new(LogConfig::default(), io::sink())
+    }
+
+    pub fn config(&self) -> LogConfig {
+        self.0.borrow().config
+    }
+
+    pub fn set_kernel(&self, enabled: bool) {
+        self.0.borrow_mut().config.kernel = enabled;
+    }
+
+    /// records the guest location log lines are prefixed with
+    pub fn locate(&self, pc: u64, cycle: u64) {
+        let mut state = self.0.borrow_mut();
+        state.pc = pc;
+        state.cycle = cycle;
+    }
+
+    /// writes a line regardless of configuration, a failing sink is ignored
+    pub fn print(&self, msg: &str) {
+        let _ = writeln!(self.0.borrow_mut().sink, "{msg}");
+    }
+
+    /// `location` names the instruction, the current pc in padded hex when None
+    pub fn disassembly(&self, location: Option<&str>, msg: &str) {
+        let prefix = match location {
+            Some(location) => location.to_string(),
+            None => format!("{:0>4x}", self.0.borrow().pc),
+        };
+        self.print(&format!("{}: {msg}", prefix.b_green()));
+    }
+
+    pub fn kernel(&self, msg: &str) {
+        let pc = self.0.borrow().pc;
+        self.print(&format!("{}: {msg}", format!("{pc:0>4x} NKS:").b_green()));
+    }
+
+    /// `tag` names the verbosity level the message was logged at
+    pub fn verbose(&self, tag: &'static str, msg: &str) {
+        let cycle = self.0.borrow().cycle;
+        self.print(&format!("{NAME}: {cycle:0>4x}: {} {msg}", tag.yellow()));
+    }
+}
+
+#[macro_export]
+macro_rules! log_disassembly {
+    ($log:expr, @$location:expr, $($arg:tt)*) => {
+        if $log.config().disassemble {
+            let msg = format!($($arg)*);
+            $log.disassembly($location, &msg);
+        }
+    };
+    ($log:expr, $($arg:tt)*) => {
+        if $log.config().disassemble {
+            let msg = format!($($arg)*);
+            $log.disassembly(None, &msg);
+        }
+    };
+}
+
+#[macro_export]
+macro_rules! kernel_log {
+    ($log:expr, $($arg:tt)*) => {
+        if $log.config().kernel {
+            let msg = format!($($arg)*);
+            $log.kernel(&msg);
+        }
+    };
+}
+
+#[macro_export]
+macro_rules! verbose_println {
+    ($log:expr, $($arg:tt)*) => {
+        if $log.config().verbosity >= 1 {
+            let msg = format!($($arg)*);
+            $log.verbose("verbose:", &msg);
+        }
+    };
+}
+
+#[macro_export]
+macro_rules! very_verbose_println {
+    ($log:expr, $($arg:tt)*) => {
+        if $log.config().verbosity >= 2 {
+            let msg = format!($($arg)*);
+            $log.verbose("very-verbose:", &msg);
+        }
+    };
+}
+
+#[macro_export]
+macro_rules! very_very_verbose_println {
+    ($log:expr, $($arg:tt)*) => {
+        if $log.config().verbosity >= 3 {
+            let msg = format!($($arg)*);
+            $log.verbose("very-very-verbose:", &msg);
+        }
+    };
+}
@@ -1,58 +1,33 @@
 // nisvc virtual machine rewrite
-#![allow(static_mut_refs)]
-
-mod constant;
-mod cpu;
-mod debug_shell;
-mod gpu;
-mod kernel;
-mod loader;
-mod memory;
-mod opcode;
-use std::fmt;
-
-// use colorize::AnsiColor;
-use crate::constant::{NAME, PROGRAM_COUNTER};
-use clap::Parser;
+use clap::{Parser, Subcommand, ValueEnum};
 use colorize::AnsiColor;
+use nisvc_system::{
+    assembler,
+    constant::PROGRAM_COUNTER,
+    dap,
+    debug_shell::Shell,
+    debugger::Debugger,
+    disassembler,
+    gdb::GdbStub,
+    gpu::{GpuBackend, GpuConfig},
+    guest_exit_code,
+    image::ImageFormat,
+    kernel::Kernel,
+    logger::{LogConfig, Logger},
+    sandbox::Sandbox,
+    vfs::Vfs,
+    ExecutionError, MAX_GUEST_EXIT_STATUS,
+};
 // use crossterm::style::Stylize;
-use kernel::{Kernel, KERNEL_LOG};
-
-struct ExecutionError {
-    error: String,
-}
-
-impl ExecutionError {
-    fn new(error: String) -> Self {
-        Self { error }
-    }
-    fn prepend(mut self, prelude: String) -> Self {
-        self.error = prelude + self.error.as_str();
-        self
-    }
-}
-
-impl fmt::Display for ExecutionError {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{} {}", "error >".b_red().bold(), self.error) // make cooler
-    }
-}
-
-static mut GLOBAL_CLOCK: usize = 0;
-
-static mut GLOBAL_PROGRAM_COUNTER: u64 = 0;
-
-static mut DISASSEMBLE: bool = true;
-static mut VERBOSE_FLAG: usize = 0;
-
-// static mut OUTPUT_FLAG: bool = false;
-// static mut INPUT_FLAG: bool = false;
 
 #[derive(Parser)]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 struct Args {
-    #[arg()]
+    #[command(subcommand)]
+    mode: Option<Mode>,
+    #[arg(required = true)]
     /// NEF executable
-    program: String,
+    program: Option<String>,
     /// enable verbose logging
     #[arg(short, long, default_value_t = 0)]
     verbosity: usize,
@@ -62,10 +37,51 @@ struct Args {
     /// enable NKS syscall logging
     #[arg(short, long, default_value_t = false)]
     kernel: bool,
+    /// enter the interactive debug shell before the first instruction
     #[arg(long)]
     debug: bool,
+    /// serve the GDB remote protocol on a tcp port or unix socket path and wait for a client
+    #[arg(long, conflicts_with = "debug")]
+    gdb: Option<String>,
+    /// fault on failed file syscalls instead of returning an errno to the program
+    #[arg(long)]
+    strict_syscalls: bool,
+    /// confine file syscalls to a host directory, which the program sees as `/`
+    #[arg(long)]
+    fs_root: Option<String>,
+    /// refuse file syscalls that create, modify or remove files
+    #[arg(long)]
+    fs_readonly: bool,
+    /// only allow file syscalls on paths under this one, may be repeated
     #[arg(long)]
-    bkoffset: Option<String>,
+    fs_allow: Vec<String>,
+    /// refuse file syscalls on paths under this one, may be repeated
+    #[arg(long)]
+    fs_deny: Vec<String>,
+    /// run file syscalls against an in-memory copy of a directory or tar archive
+    #[arg(long, conflicts_with = "fs_root")]
+    vfs: Option<String>,
+    /// write the in-memory filesystem to a directory, or a tar archive if the path ends in `.tar`, at exit
+    #[arg(long, requires = "vfs")]
+    vfs_save: Option<String>,
+    /// where the frame buffer is shown
+    #[arg(long, value_enum, default_value_t = Gpu::Window)]
+    gpu: Gpu,
+    /// write drawn frames to this directory
+    #[arg(long)]
+    frames: Option<String>,
+    /// only write every nth drawn frame
+    #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u64).range(1..), requires = "frames")]
+    frame_every: u64,
+    /// image format of written frames
+    #[arg(long, value_enum, default_value_t = FrameFormat::Png, requires = "frames")]
+    frame_format: FrameFormat,
+    /// keep the history of the readline syscall in this file between runs
+    #[arg(long)]
+    history: Option<String>,
+    /// set a breakpoint at a hex address or label, may be repeated
+    #[arg(short, long, alias = "bkoffset")]
+    breakpoint: Vec<String>,
     /// allocated heap memory size in bytes
     #[arg(long, default_value_t = 1_000_000)]
     heap: u64,
@@ -74,7 +90,7 @@ struct Args {
     stack: u64,
     /// additional arguments passed to the executable
     cmdline: Vec<String>,
-    /// override executable's entrypoint
+    /// override executable's entrypoint with a hex address or label
     #[arg(short, long)]
     entry_point: Option<String>,
     /// override vm clock speed Hz
@@ -82,20 +98,70 @@ struct Args {
     clockspeed: f32,
 }
 
+#[derive(Clone, ValueEnum)]
+enum Gpu {
+    /// an SDL window
+    Window,
+    /// no window, frames are only kept in memory and written with --frames or the debug shell
+    Headless,
+}
+
+#[derive(Clone, ValueEnum)]
+enum FrameFormat {
+    Png,
+    Ppm,
+}
+
+#[derive(Subcommand)]
+enum Mode {
+    /// serve the Debug Adapter Protocol over stdio, the program is given by the launch request
+    Dap,
+    /// assemble a source file into a NEF executable
+    Asm {
+        /// assembly source
+        source: String,
+        /// output path, defaults to the source path with a `.nef` extension
+        #[arg(short, long)]
+        output: Option<String>,
+    },
+    /// print the disassembly of a NEF executable without running it
+    Disasm {
+        /// NEF executable
+        program: String,
+        /// only decode code reachable from the entry point through jumps and calls
+        #[arg(short, long)]
+        recursive: bool,
+    },
+}
+
 fn main() {
-    match real_main() {
-        Ok(()) => (),
-        Err(e) => println!("{e}"),
-    }
+    let status = match real_main() {
+        Ok(status) => status,
+        Err(e) => {
+            println!("{e}");
+            e.exit_code()
+        }
+    };
+    std::process::exit(status);
 }
 
-fn real_main() -> Result<(), ExecutionError> {
+/// returns the host exit status
+fn real_main() -> Result<i32, ExecutionError> {
     let args = Args::parse();
-    unsafe {
-        DISASSEMBLE = args.disassemble;
-        VERBOSE_FLAG = args.verbosity;
-        KERNEL_LOG = args.kernel;
+    let log = Logger::stdout(LogConfig {
+        disassemble: args.disassemble,
+        verbosity: args.verbosity,
+        kernel: args.kernel,
+    });
+    if let Some(mode) = args.mode {
+        return match mode {
+            Mode::Dap => dap::run(log),
+            Mode::Asm { source, output } => assembler::run(&source, output, &log),
+            Mode::Disasm { program, recursive } => disassembler::run(&program, recursive),
+        }
+        .map(|()| 0);
     }
+    let program = args.program.unwrap_or_default();
 
     // let heap = if let Some(heap) = args.heap {
     //     heap
@@ -108,142 +174,92 @@ fn real_main() -> Result<(), ExecutionError> {
     //     1_0000
     // };
     let cmdline = {
-        let mut cmdline = vec![args.program.clone()];
+        let mut cmdline = vec![program.clone()];
         cmdline.extend(args.cmdline.clone());
         cmdline
     };
     println!("cmdline: {:?}", cmdline);
-    let mut kernel = Kernel::new(args.cmdline, args.heap, args.stack, args.clockspeed);
+    let mut kernel = Kernel::new(
+        args.cmdline,
+        args.heap,
+        args.stack,
+        args.clockspeed,
+        log.clone(),
+    );
     kernel
-        .system
-        .load(&args.program)
+        .load(&program)
         .map_err(|e| e.prepend("PROGRAM LOAD FAULT: ".to_string().yellow()))?;
     if let Some(entry_override) = args.entry_point {
-        let addr =
-            u64::from_str_radix(entry_override.trim_start_matches("0x"), 16).map_err(|e| {
-                ExecutionError::new(format!("invalid entrypoint override {entry_override}"))
-            })?;
+        let addr = kernel
+            .debug_symbols()
+            .resolve(&entry_override)
+            .ok_or(ExecutionError::new(format!(
+                "invalid entrypoint override {entry_override}: not an address or known label"
+            )))?;
         println!("overriding entrypoint: {addr:#x}");
         kernel.system.registers.write(PROGRAM_COUNTER, addr);
     }
-    // kernel.gpu.as_mut().unwrap().renderer.present();
-    match kernel.run() {
-        Ok(()) => (),
-        Err(mut e) => {
-            // println!("stack dump:\n{:#?}", kernel.system.dump_stack());
-            e = unsafe {
-                e.prepend(format!("INTERNAL FAULT @ {GLOBAL_PROGRAM_COUNTER:#x}: ").yellow())
-            };
-            kernel.core_dump()?;
-            println!("{e}");
-        }
-    };
-    // kernel.core_dump()?;
-    Ok(())
-}
-
-fn _log_disassembly(msg: &str) {
-    unsafe {
-        if DISASSEMBLE {
-            println!(
-                "{}: {msg}",
-                format!("{GLOBAL_PROGRAM_COUNTER:0>4x}").b_green()
-            );
-        }
+    kernel.set_strict_syscalls(args.strict_syscalls);
+    kernel.set_gpu_config(GpuConfig {
+        backend: match args.gpu {
+            Gpu::Window => GpuBackend::Window,
+            Gpu::Headless => GpuBackend::Headless,
+        },
+        frames: args.frames.map(Into::into),
+        frame_every: args.frame_every,
+        frame_format: match args.frame_format {
+            FrameFormat::Png => ImageFormat::Png,
+            FrameFormat::Ppm => ImageFormat::Ppm,
+        },
+    });
+    if let Some(path) = &args.history {
+        kernel.set_history_file(path);
     }
-}
-
-fn _kernel_log(msg: &str) {
-    unsafe {
-        if KERNEL_LOG {
-            println!(
-                "{}: {}",
-                format!("{GLOBAL_PROGRAM_COUNTER:0>4x} NKS:").b_green(),
-                msg
-            )
-        }
+    let mut sandbox = Sandbox::default().readonly(args.fs_readonly);
+    if let Some(root) = &args.fs_root {
+        sandbox = sandbox.root(root)?;
     }
-}
-
-// fn _log_output(msg: &str) {
-//     unsafe {
-//         if OUTPUT_FLAG {
-//             println!("{NAME}: {GLOBAL_CLOCK:0>4x}: {} {}", "output:".blue(), msg)
-//         }
-//     }
-// }
-
-// fn _log_input(msg: &str) {
-//     unsafe {
-//         if INPUT_FLAG {
-//             println!("{NAME}: {GLOBAL_CLOCK:0>4x}: {} {}", "input: ".blue(), msg)
-//         }
-//     }
-// }
-
-fn _verbose_println(msg: &str) {
-    unsafe {
-        if VERBOSE_FLAG >= 1 {
-            println!(
-                "{NAME}: {GLOBAL_CLOCK:0>4x}: {} {}",
-                "verbose:".yellow(),
-                msg
-            )
-        }
+    for path in args.fs_allow {
+        sandbox = sandbox.allow(path);
     }
-}
-fn _very_verbose_println(msg: &str) {
-    unsafe {
-        if VERBOSE_FLAG >= 2 {
-            println!(
-                "{NAME}: {GLOBAL_CLOCK:0>4x}: {} {}",
-                "very-verbose:".yellow(),
-                msg
-            )
-        }
+    for path in args.fs_deny {
+        sandbox = sandbox.deny(path);
     }
-}
-
-fn _very_very_verbose_println(msg: &str) {
-    unsafe {
-        if VERBOSE_FLAG >= 3 {
-            println!(
-                "{NAME}: {GLOBAL_CLOCK:0>4x}: {} {}",
-                "very-very-verbose:".yellow(),
-                msg
-            )
+    kernel.set_sandbox(sandbox);
+    if let Some(source) = &args.vfs {
+        kernel.set_vfs(Vfs::load(source)?);
+    }
+    for breakpoint in &args.breakpoint {
+        kernel.add_breakpoint(breakpoint)?;
+    }
+    if args.debug {
+        kernel.attach_debugger(Debugger::Shell(Box::new(Shell::new()?)));
+    }
+    if let Some(address) = &args.gdb {
+        kernel.attach_debugger(Debugger::Gdb(GdbStub::listen(address, log.clone())?));
+    }
+    // kernel.gpu.as_mut().unwrap().renderer.present();
+    let result = kernel.run();
+    if let (Some(dest), Some(vfs)) = (&args.vfs_save, kernel.vfs()) {
+        vfs.save(dest)?;
+    }
+    match result {
+        Ok(status) => {
+            if status > MAX_GUEST_EXIT_STATUS {
+                println!("exit status {status} is reserved for faults, exiting with {MAX_GUEST_EXIT_STATUS}");
+            }
+            Ok(guest_exit_code(status))
+        }
+        Err(mut e) => {
+            // println!("stack dump:\n{:#?}", kernel.system.dump_stack());
+            let fault_pc =
+                e.pc.unwrap_or(kernel.system.registers.read(PROGRAM_COUNTER));
+            let fault_location = kernel.debug_symbols().symbolize(fault_pc);
+            let cycle = e.cycle.unwrap_or_default();
+            e = e.prepend(format!("INTERNAL FAULT @ {fault_location} (cycle {cycle}): ").yellow());
+            println!("{e}");
+            println!("{}", kernel.backtrace_report(fault_pc));
+            Ok(e.exit_code())
         }
     }
 }
-#[macro_export]
-macro_rules! log_disassembly {
-    ($($arg:tt)*) => (crate::_log_disassembly(&format!($($arg)*)));
-}
-
-#[macro_export]
-macro_rules! kernel_log {
-    ($($arg:tt)*) => (crate::_kernel_log(&format!($($arg)*)));
-}
-
-// #[macro_export]
-// macro_rules! log_output {
-//     ($($arg:tt)*) => (crate::_log_output(&format!($($arg)*)));
-// }
-
-// #[macro_export]
-// macro_rules! log_input {
-//     ($($arg:tt)*) => (crate::_log_input(&format!($($arg)*)));
-// }
-
-#[macro_export]
-macro_rules! verbose_println {
-    ($($arg:tt)*) => (crate::_verbose_println(&format!($($arg)*)));
-}
-#[macro_export]
-macro_rules! very_verbose_println {
-    ($($arg:tt)*) => (crate::_very_verbose_println(&format!($($arg)*)));
-}
-#[macro_export]
-macro_rules! very_very_verbose_println {
-    ($($arg:tt)*) => (crate::_very_very_verbose_println(&format!($($arg)*)));
-}
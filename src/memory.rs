@@ -2,7 +2,9 @@ use std::collections::BTreeSet;
 
 use crate::{
     constant::{MEM_HEAP, MEM_INVALID, MEM_STACK, MEM_STATIC, UNINITIALIZED_MEMORY},
-    kernel_log, very_very_verbose_println, ExecutionError,
+    kernel_log,
+    logger::Logger,
+    very_very_verbose_println, ErrorKind, ExecutionError,
 };
 const HPA_NODE_DATA_OFFSET: u64 = 9;
 const HPA_TAIL_SENTINEL_ADDRESS: u64 = 0;
@@ -17,10 +19,11 @@ pub struct Memory {
     total_heap_allocations: u64,
     /// record of all allocated block pointers
     allocation_record: BTreeSet<u64>,
+    log: Logger,
 }
 
 impl Memory {
-    pub fn new(heap: u64, stack: u64) -> Self {
+    pub fn new(heap: u64, stack: u64, log: Logger) -> Self {
         Self {
             physical: Vec::with_capacity((heap + stack) as usize),
             range: 0,
@@ -30,6 +33,7 @@ impl Memory {
             stack_start: 0,
             total_heap_allocations: 0,
             allocation_record: BTreeSet::new(),
+            log,
         }
     }
     pub fn load(&mut self, image: Vec<u8>) -> Result<(), ExecutionError> {
@@ -47,12 +51,12 @@ impl Memory {
         // setup heap and stack
         self.hpa_write_hpa_node(self.heap_start, self.stack_start, false)?; // heap
         self.hpa_write_hpa_node(self.stack_start, HPA_TAIL_SENTINEL_ADDRESS, true)?;
-        println!(
+        self.log.print(&format!(
             "physical memory size: {}\nheap_ptr: {}\nstack_ptr: {}",
             self.physical.len(),
             self.heap_start,
             self.stack_start
-        );
+        ));
         Ok(())
     }
     ///
@@ -71,23 +75,30 @@ impl Memory {
         }
     }
 
+    fn access_violation(&self, address: u64) -> ExecutionError {
+        ExecutionError::fault(
+            ErrorKind::MemoryAccessViolation {
+                address,
+                region: self.memquery(address),
+            },
+            format!(
+                "Memory Access Violation : address {}|{:#x} out of bounds",
+                address, address
+            ),
+        )
+    }
+
     pub fn read_byte(&self, address: u64) -> Result<u8, ExecutionError> {
         self.physical
             .get(address as usize)
-            .ok_or(ExecutionError::new(format!(
-                "Memory Access Violation : address {}|{:#x} out of bounds",
-                address, address
-            )))
+            .ok_or_else(|| self.access_violation(address))
             .map(|v| *v)
     }
     pub fn write_byte(&mut self, address: u64, value: u8) -> Result<(), ExecutionError> {
         if let Some(mem_cell) = self.physical.get_mut(address as usize) {
             *mem_cell = value;
         } else {
-            return Err(ExecutionError::new(format!(
-                "Memory Access Violation : address {}|{:#x} out of bounds",
-                address, address
-            )));
+            return Err(self.access_violation(address));
         }
         Ok(())
     }
@@ -97,12 +108,12 @@ impl Memory {
             bytes.push(self.read_byte(i)?);
         }
         let strb = String::from_utf8_lossy(&bytes);
-        very_very_verbose_println!("reading {bytes:x?} | \"{strb}\" <- ${address}");
+        very_very_verbose_println!(self.log, "reading {bytes:x?} | \"{strb}\" <- ${address}");
         Ok(bytes)
     }
     pub fn write(&mut self, address: u64, bytes: &[u8]) -> Result<u64, ExecutionError> {
         let strb = String::from_utf8_lossy(bytes);
-        very_very_verbose_println!("writing {bytes:x?} | \"{strb}\" -> ${address}");
+        very_very_verbose_println!(self.log, "writing {bytes:x?} | \"{strb}\" -> ${address}");
         let mut bytes_wrote = 0;
         for i in 0..bytes.len() as u64 {
             self.write_byte(address + i, bytes[i as usize])?;
@@ -120,6 +131,7 @@ impl Memory {
         let value_size = self.write(stack_ptr, &value.to_le_bytes())?;
         let ptr = stack_ptr + value_size;
         very_very_verbose_println!(
+            self.log,
             "STACKOP PUSH {value:#x}|{value} at sp {stack_ptr:#x} new sp {ptr:#x} (+{value_size})"
         );
         Ok(ptr)
@@ -127,9 +139,12 @@ impl Memory {
     // returns stack pointer and popped value
     pub fn pop(&mut self, stack_ptr: u64) -> Result<(u64, u64), ExecutionError> {
         let value_size = size_of::<u64>() as u64;
-        let ptr = stack_ptr - value_size;
+        let ptr = stack_ptr
+            .checked_sub(value_size)
+            .ok_or_else(|| self.access_violation(stack_ptr))?;
         let value = bytes_to_u64(&self.read(ptr, value_size)?);
         very_very_verbose_println!(
+            self.log,
             "STACKOP POP {value:#x}|{value} at sp {stack_ptr:#x} new sp {ptr:#x} (-{value_size})"
         );
         Ok((ptr, value))
@@ -201,9 +216,10 @@ impl Memory {
     pub fn free(&mut self, ptr: u64) -> Result<(), ExecutionError> {
         let memresp = self.memquery(ptr);
         if memresp != MEM_HEAP {
-            return Err(ExecutionError::new(format!(
-                "attempted to free non-heap memory, memquery({ptr}) -> {memresp}"
-            )));
+            return Err(ExecutionError::fault(
+                ErrorKind::HeapCorruption,
+                format!("attempted to free non-heap memory, memquery({ptr}) -> {memresp}"),
+            ));
         }
         let ptr = self.find_allocation_match(ptr)?;
         self.total_heap_allocations -= 1;
@@ -245,9 +261,10 @@ impl Memory {
                     break;
                 }
             }
-            last_ptr.ok_or(ExecutionError::new(format!(
-                "no lesser ptr found for {ptr}, are any blocks allocated?"
-            )))
+            last_ptr.ok_or(ExecutionError::fault(
+                ErrorKind::HeapCorruption,
+                format!("no lesser ptr found for {ptr}, are any blocks allocated?"),
+            ))
         }
     }
     /// returns a pointer to a free memory region that can be allocated into or None if none exists which is big enough
@@ -256,14 +273,15 @@ impl Memory {
             Ok(final_canditate)
         } else {
             // potential OOM error
-            kernel_log!("under memory pressure");
+            kernel_log!(self.log, "under memory pressure");
             self.hpa_defragment(self.heap_start, true)?;
             if let Some(final_canditate) = self.hpa_get_allocation_canditate_internal(size)? {
                 Ok(final_canditate)
             } else {
-                return Err(ExecutionError::new(format!(
-                    "OOM error: could not allocate region of {size} bytes"
-                )));
+                Err(ExecutionError::fault(
+                    ErrorKind::OutOfMemory { size },
+                    format!("OOM error: could not allocate region of {size} bytes"),
+                ))
             }
         }
     }
@@ -302,7 +320,7 @@ impl Memory {
         let is_allocated : bool = match self.read_byte(is_allocated_ptr)? {
             0 => false,
             1 => true,
-            _ => return Err(ExecutionError::new(format!(
+            _ => return Err(ExecutionError::fault(ErrorKind::HeapCorruption, format!(
                 "heap allocation error: corrupt allocation mapping at block {ptr} (is_allocated flag >1)"
             ))),
         };
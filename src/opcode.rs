@@ -1,219 +1,336 @@
-use crate::cpu::RegHandle;
-#[derive(Debug)]
-pub enum Operation {
-    Nop,
-    Cpy {
-        dest: RegHandle,
-        src: RegHandle,
-    },
-    Ldi {
-        dest: RegHandle,
-        src: u64,
-    },
-    Load {
-        dest: RegHandle,
-        n: RegHandle,
-        src: RegHandle,
-    },
-    Store {
-        dest: RegHandle,
-        n: RegHandle,
-        src: RegHandle,
-    },
-    Add {
-        dest: RegHandle,
-        op1: RegHandle,
-        op2: RegHandle,
-    },
-    Sub {
-        dest: RegHandle,
-        op1: RegHandle,
-        op2: RegHandle,
-    },
-    Mult {
-        dest: RegHandle,
-        op1: RegHandle,
-        op2: RegHandle,
-    },
-    Div {
-        dest: RegHandle,
-        op1: RegHandle,
-        op2: RegHandle,
-    },
-
-    Or {
-        dest: RegHandle,
-        op1: RegHandle,
-        op2: RegHandle,
-    },
-    Xor {
-        dest: RegHandle,
-        op1: RegHandle,
-        op2: RegHandle,
-    },
-    And {
-        dest: RegHandle,
-        op1: RegHandle,
-        op2: RegHandle,
-    },
-    Not {
-        dest: RegHandle,
-        op: RegHandle,
-    },
-    Shl {
-        dest: RegHandle,
-        n: RegHandle,
-        src: RegHandle,
-    },
-    Shr {
-        dest: RegHandle,
-        n: RegHandle,
-        src: RegHandle,
-    },
-    Rotl {
-        dest: RegHandle,
-        n: RegHandle,
-        src: RegHandle,
-    },
-    Rotr {
-        dest: RegHandle,
-        n: RegHandle,
-        src: RegHandle,
-    },
-    Neg {
-        dest: RegHandle,
-        op: RegHandle,
-    },
-
-    Jmp {
-        addr: u64,
-    },
-    Jifz {
-        addr: u64,
-        condition_reg: RegHandle,
-    },
-    Jifnz {
-        addr: u64,
-        condition_reg: RegHandle,
-    },
-
-    Inc {
-        reg: RegHandle,
-    },
-    Dec {
-        reg: RegHandle,
-    },
-
-    Push {
-        src: RegHandle,
-    },
-    Pop {
-        dest: RegHandle,
-    },
-
-    Call {
-        addr: u64,
-    },
-    Ret,
-    // fopen fd_store filep_ptr filep_len
-    // fwrite fd str_ptr str_len
-    // fread fd buf_ptr buf_len
-    // fclose fd
-    // Fopen {
-    //     dest_fd: RegHandle,
-    //     file_path_str_ptr: RegHandle,
-    //     file_path_str_len: RegHandle,
-    // },
-    // Fread {
-    //     fd: RegHandle,
-    //     buf_ptr: RegHandle,
-    //     buf_len: RegHandle,
-    // },
-    // Fwrite {
-    //     fd: RegHandle,
-    //     buf_ptr: RegHandle,
-    //     buf_len: RegHandle,
-    // },
-    // Fseek {
-    //     fd: RegHandle,
-    //     seek: RegHandle,
-    //     direction: RegHandle,
-    // },
-    // Fclose {
-    //     fd: RegHandle,
-    // },
-    // //new
-
-    // //heap management
-    // Malloc {
-    //     dest_ptr: RegHandle,
-    //     size: RegHandle,
-    // },
-    // Realloc {
-    //     dest_ptr: RegHandle,
-    //     ptr: RegHandle,
-    //     new_size: RegHandle,
-    // },
-    // Free {
-    //     ptr: RegHandle,
-    // },
-    // Memcpy {
-    //     dest: RegHandle,
-    //     n: RegHandle,
-    //     src: RegHandle,
-    // },
-    // Memset {
-    //     dest: RegHandle,
-    //     n: RegHandle,
-    //     value: RegHandle,
-    // },
-
-    // floating point
-    Itof {
-        destf: RegHandle,
-        srci: RegHandle,
-    },
-    Ftoi {
-        desti: RegHandle,
-        srcf: RegHandle,
-    },
-
-    Fadd {
-        dest: RegHandle,
-        op1: RegHandle,
-        op2: RegHandle,
-    },
-    Fsub {
-        dest: RegHandle,
-        op1: RegHandle,
-        op2: RegHandle,
-    },
-    Fmult {
-        dest: RegHandle,
-        op1: RegHandle,
-        op2: RegHandle,
-    },
-    Fdiv {
-        dest: RegHandle,
-        op1: RegHandle,
-        op2: RegHandle,
-    },
-    Fmod {
-        dest: RegHandle,
-        op1: RegHandle,
-        op2: RegHandle,
-    },
-    Mod {
-        dest: RegHandle,
-        op1: RegHandle,
-        op2: RegHandle,
-    },
-    Int {
-        code: u64,
-    },
-    Pushi {
-        immediate: u64,
-    },
-    Breakpoint,
-    HaltExe,
+use crate::{
+    constant::{BREAKPOINT, HALT_EXE, UNINITIALIZED_MEMORY},
+    cpu::{register_name, RegHandle},
+    loader::DebugSymbols,
+    memory::bytes_to_u64,
+    ErrorKind, ExecutionError,
+};
+
+/// how an operand is encoded and rendered
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OperandKind {
+    /// register handle byte
+    Reg,
+    /// register handle byte holding a float
+    FReg,
+    /// little endian u64 immediate
+    Const,
+    /// little endian u64 code address
+    Addr,
+}
+
+impl OperandKind {
+    /// encoded size in bytes
+    pub fn size(&self) -> usize {
+        match self {
+            OperandKind::Reg | OperandKind::FReg => 1,
+            OperandKind::Const | OperandKind::Addr => 8,
+        }
+    }
+}
+
+pub struct InstructionInfo {
+    pub mnemonic: &'static str,
+    pub opcode: u8,
+    /// operands in encoding order
+    pub operands: &'static [OperandKind],
+}
+
+impl InstructionInfo {
+    pub fn find(mnemonic: &str) -> Option<&'static InstructionInfo> {
+        INSTRUCTIONS.iter().find(|i| i.mnemonic == mnemonic)
+    }
+}
+
+macro_rules! operand_type {
+    (Reg) => {
+        RegHandle
+    };
+    (FReg) => {
+        RegHandle
+    };
+    (Const) => {
+        u64
+    };
+    (Addr) => {
+        u64
+    };
+}
+
+macro_rules! consume_operand {
+    ($source:ident, Reg) => {
+        $source.consume_byte()?
+    };
+    ($source:ident, FReg) => {
+        $source.consume_byte()?
+    };
+    ($source:ident, Const) => {
+        $source.consume_constant()?
+    };
+    ($source:ident, Addr) => {
+        $source.consume_constant()?
+    };
+}
+
+/// generates `Operation`, `INSTRUCTIONS` and the conversions between them from one table,
+/// operands are listed in the order they are encoded
+macro_rules! instruction_table {
+    ($($opcode:expr => $variant:ident $mnemonic:literal $({ $($field:ident: $kind:ident),* })?;)*) => {
+        #[derive(Debug, Clone, Copy, PartialEq)]
+        pub enum Operation {
+            $($variant $({ $($field: operand_type!($kind)),* })?,)*
+        }
+
+        pub const INSTRUCTIONS: &[InstructionInfo] = &[
+            $(InstructionInfo {
+                mnemonic: $mnemonic,
+                opcode: $opcode,
+                operands: &[$($(OperandKind::$kind),*)?],
+            },)*
+        ];
+
+        impl Operation {
+            /// decodes the next instruction, `CPU::fetch_decode` and the disassembler share this
+            pub fn decode(source: &mut impl ByteSource) -> Result<Self, ExecutionError> {
+                let opcode = source.consume_byte()?;
+                $(if opcode == $opcode {
+                    return Ok(Operation::$variant $({ $($field: consume_operand!(source, $kind)),* })?);
+                })*
+                if opcode == UNINITIALIZED_MEMORY {
+                    return Err(ExecutionError::fault(
+                        ErrorKind::IllegalInstruction,
+                        "malformed binary: attempted to execute uninitialized memory (opcode 0xfd)"
+                            .to_string(),
+                    ));
+                }
+                Err(ExecutionError::fault(
+                    ErrorKind::IllegalInstruction,
+                    format!("unrecognized opcode {opcode:#x}"),
+                ))
+            }
+
+            /// builds an operation from operand values in encoding order, registers are truncated to a byte
+            pub fn from_operands(info: &InstructionInfo, values: &[u64]) -> Option<Self> {
+                if values.len() != info.operands.len() {
+                    return None;
+                }
+                #[allow(unused_variables, unused_mut)]
+                let mut values = values.iter().copied();
+                $(if info.opcode == $opcode {
+                    return Some(Operation::$variant $({ $($field: values.next()? as operand_type!($kind)),* })?);
+                })*
+                None
+            }
+
+            pub fn info(&self) -> &'static InstructionInfo {
+                let opcode = match self {
+                    $(Operation::$variant { .. } => $opcode,)*
+                };
+                INSTRUCTIONS
+                    .iter()
+                    .find(|i| i.opcode == opcode)
+                    .expect("every operation is in the instruction table")
+            }
+
+            /// operand values in encoding order
+            pub fn operands(&self) -> Vec<u64> {
+                match *self {
+                    $(Operation::$variant $({ $($field),* })? => vec![$($($field as u64),*)?],)*
+                }
+            }
+        }
+    };
+}
+
+instruction_table! {
+    0x00 => Nop "nop";
+    0x01 => Cpy "cpy" { dest: Reg, src: Reg };
+    0x02 => Ldi "ldi" { dest: Reg, src: Const };
+    0x03 => Load "load" { dest: Reg, n: Reg, src: Reg };
+    0x04 => Store "store" { dest: Reg, n: Reg, src: Reg };
+    0x05 => Add "add" { dest: Reg, op1: Reg, op2: Reg };
+    0x06 => Sub "sub" { dest: Reg, op1: Reg, op2: Reg };
+    0x07 => Mult "mult" { dest: Reg, op1: Reg, op2: Reg };
+    0x08 => Div "div" { dest: Reg, op1: Reg, op2: Reg };
+    0x09 => Or "or" { dest: Reg, op1: Reg, op2: Reg };
+    0x0a => Xor "xor" { dest: Reg, op1: Reg, op2: Reg };
+    0x0b => And "and" { dest: Reg, op1: Reg, op2: Reg };
+    0x0c => Not "not" { dest: Reg, op: Reg };
+    0x0d => Shl "shl" { dest: Reg, n: Reg, src: Reg };
+    0x0e => Shr "shr" { dest: Reg, n: Reg, src: Reg };
+    0x0f => Rotl "rotl" { dest: Reg, n: Reg, src: Reg };
+    0x10 => Rotr "rotr" { dest: Reg, n: Reg, src: Reg };
+    0x11 => Neg "neg" { dest: Reg, op: Reg };
+    0x12 => Jmp "jmp" { addr: Addr };
+    0x13 => Jifz "jifz" { condition_reg: Reg, addr: Addr };
+    0x14 => Jifnz "jifnz" { condition_reg: Reg, addr: Addr };
+    0x16 => Inc "inc" { reg: Reg };
+    0x17 => Dec "dec" { reg: Reg };
+    0x18 => Push "push" { src: Reg };
+    0x19 => Pop "pop" { dest: Reg };
+    0x1a => Call "call" { addr: Addr };
+    0x1b => Ret "ret";
+    0x1c => Itof "itof" { destf: FReg, srci: Reg };
+    0x1d => Ftoi "ftoi" { desti: Reg, srcf: FReg };
+    0x1e => Fadd "fadd" { dest: FReg, op1: FReg, op2: FReg };
+    0x1f => Fsub "fsub" { dest: FReg, op1: FReg, op2: FReg };
+    0x20 => Fmult "fmult" { dest: FReg, op1: FReg, op2: FReg };
+    0x21 => Fdiv "fdiv" { dest: FReg, op1: FReg, op2: FReg };
+    0x22 => Fmod "fmod" { dest: FReg, op1: FReg, op2: FReg };
+    0x23 => Mod "mod" { dest: Reg, op1: Reg, op2: Reg };
+    0x24 => Int "int" { code: Const };
+    0x25 => Pushi "pushi" { immediate: Const };
+    0x26 => Iret "iret";
+    BREAKPOINT => Breakpoint "breakpoint";
+    HALT_EXE => HaltExe "haltexe";
+}
+
+/// a stream of instruction bytes, such as memory at pc or a program image
+pub trait ByteSource {
+    fn consume_byte(&mut self) -> Result<u8, ExecutionError>;
+    /// consumes a little endian double word
+    fn consume_constant(&mut self) -> Result<u64, ExecutionError>;
+}
+
+/// reads instructions straight out of a program image
+pub struct ImageCursor<'a> {
+    pub image: &'a [u8],
+    pub offset: usize,
+}
+
+impl ByteSource for ImageCursor<'_> {
+    fn consume_byte(&mut self) -> Result<u8, ExecutionError> {
+        let byte = *self
+            .image
+            .get(self.offset)
+            .ok_or(ExecutionError::new(format!(
+                "instruction truncated @ {:#x}",
+                self.offset
+            )))?;
+        self.offset += 1;
+        Ok(byte)
+    }
+
+    fn consume_constant(&mut self) -> Result<u64, ExecutionError> {
+        let bytes = self
+            .image
+            .get(self.offset..self.offset + 8)
+            .ok_or(ExecutionError::new(format!(
+                "instruction truncated @ {:#x}",
+                self.offset
+            )))?;
+        self.offset += 8;
+        Ok(bytes_to_u64(bytes))
+    }
+}
+
+impl Operation {
+    pub fn mnemonic(&self) -> &'static str {
+        self.info().mnemonic
+    }
+
+    /// encodes the operation into the bytes `Operation::decode` reads back
+    pub fn encode(&self) -> Vec<u8> {
+        let info = self.info();
+        let mut bytes = vec![info.opcode];
+        for (kind, value) in info.operands.iter().zip(self.operands()) {
+            match kind {
+                OperandKind::Reg | OperandKind::FReg => bytes.push(value as u8),
+                OperandKind::Const | OperandKind::Addr => bytes.extend(value.to_le_bytes()),
+            }
+        }
+        bytes
+    }
+
+    /// renders `mnemonic op, op..` with each operand rendered by `render`
+    pub fn format(&self, mut render: impl FnMut(OperandKind, u64) -> String) -> String {
+        let operands = self
+            .info()
+            .operands
+            .iter()
+            .zip(self.operands())
+            .map(|(kind, value)| render(*kind, value))
+            .collect::<Vec<String>>();
+        if operands.is_empty() {
+            self.mnemonic().to_string()
+        } else {
+            format!("{} {}", self.mnemonic(), operands.join(", "))
+        }
+    }
+
+    /// renders the operation as assembly the assembler accepts back
+    pub fn disassemble(&self, symbols: &DebugSymbols) -> String {
+        self.format(|kind, value| match kind {
+            OperandKind::Reg | OperandKind::FReg => register_name(value as RegHandle),
+            OperandKind::Addr => match symbols.label_at(value) {
+                Some(label) => format!("$!{label}"),
+                None => format!("$x{value:0>4x}"),
+            },
+            OperandKind::Const => format!("$x{value:x}"),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// distinct operand values per position so swapped operands are caught
+    fn sample(info: &InstructionInfo) -> Operation {
+        let values: Vec<u64> = info
+            .operands
+            .iter()
+            .enumerate()
+            .map(|(i, kind)| match kind {
+                OperandKind::Reg | OperandKind::FReg => 0x14 + i as u64,
+                OperandKind::Const | OperandKind::Addr => 0x1122_3344_5566_7788 + i as u64,
+            })
+            .collect();
+        Operation::from_operands(info, &values).unwrap()
+    }
+
+    #[test]
+    fn round_trip_every_instruction() {
+        for info in INSTRUCTIONS {
+            let operation = sample(info);
+            let bytes = operation.encode();
+            let expected_len: usize = 1 + info.operands.iter().map(|k| k.size()).sum::<usize>();
+            assert_eq!(bytes.len(), expected_len, "{}", info.mnemonic);
+            let mut cursor = ImageCursor {
+                image: &bytes,
+                offset: 0,
+            };
+            let decoded = Operation::decode(&mut cursor)
+                .unwrap_or_else(|e| panic!("{}: {}", info.mnemonic, e.error));
+            assert_eq!(cursor.offset, bytes.len(), "{}", info.mnemonic);
+            assert_eq!(decoded, operation, "{}", info.mnemonic);
+            assert_eq!(decoded.encode(), bytes, "{}", info.mnemonic);
+            assert_eq!(decoded.mnemonic(), info.mnemonic);
+        }
+    }
+
+    #[test]
+    fn opcodes_and_mnemonics_are_unique() {
+        for (i, a) in INSTRUCTIONS.iter().enumerate() {
+            for b in &INSTRUCTIONS[i + 1..] {
+                assert_ne!(a.opcode, b.opcode, "{} and {}", a.mnemonic, b.mnemonic);
+                assert_ne!(a.mnemonic, b.mnemonic);
+            }
+        }
+    }
+
+    #[test]
+    fn disassembly_names_the_instruction() {
+        let symbols = DebugSymbols::default();
+        let ftoi = Operation::Ftoi {
+            desti: 0x04,
+            srcf: 0x15,
+        };
+        assert_eq!(ftoi.disassemble(&symbols), "ftoi r1, r2b1");
+        let jifz = Operation::Jifz {
+            condition_reg: 0x16,
+            addr: 0xaa,
+        };
+        assert_eq!(jifz.disassemble(&symbols), "jifz r3b1, $x00aa");
+    }
 }
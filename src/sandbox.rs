@@ -0,0 +1,188 @@
+use std::path::{Component, Path, PathBuf};
+
+use crate::{syscall::Errno, ErrorKind, ExecutionError};
+
+/// what a syscall does with a path
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Access {
+    Read,
+    /// creates, modifies or removes what is at the path
+    Write,
+}
+
+/// confines the paths guest syscalls may touch, the default allows the whole host filesystem
+#[derive(Debug, Clone, Default)]
+pub struct Sandbox {
+    /// host directory the guest sees as `/`
+    root: Option<PathBuf>,
+    /// refuse every syscall that modifies the filesystem
+    readonly: bool,
+    /// when not empty only paths under one of these are accessible
+    allow: Vec<PathBuf>,
+    /// paths under these are never accessible, even if allowed
+    deny: Vec<PathBuf>,
+}
+
+impl Sandbox {
+    /// confines the guest to the host directory `root`, which it sees as `/`
+    pub fn root(mut self, root: impl AsRef<Path>) -> Result<Self, ExecutionError> {
+        let root = root.as_ref();
+        let root = root.canonicalize().map_err(|e| {
+            ExecutionError::io(&e, format!("invalid fs root `{}`: {e}", root.display()))
+        })?;
+        self.root = Some(root);
+        Ok(self)
+    }
+
+    pub fn readonly(mut self, readonly: bool) -> Self {
+        self.readonly = readonly;
+        self
+    }
+
+    /// a guest path prefix to allow, relative paths start at the initial working directory
+    pub fn allow(mut self, path: impl Into<PathBuf>) -> Self {
+        self.allow.push(path.into());
+        self
+    }
+
+    /// a guest path prefix to deny, relative paths start at the initial working directory
+    pub fn deny(mut self, path: impl Into<PathBuf>) -> Self {
+        self.deny.push(path.into());
+        self
+    }
+
+    /// `/` inside a root, otherwise the working directory of the host
+    pub fn initial_cwd(&self) -> PathBuf {
+        match self.root {
+            Some(_) => PathBuf::from("/"),
+            None => std::env::current_dir().unwrap_or_else(|_| PathBuf::from("/")),
+        }
+    }
+
+    /// the absolute guest path of `path` relative to `cwd`, if `access` to it is permitted
+    pub fn resolve(
+        &self,
+        cwd: &Path,
+        path: &Path,
+        access: Access,
+    ) -> Result<PathBuf, ExecutionError> {
+        let guest = normalize(cwd, path);
+        let base = self.initial_cwd();
+        let under = |prefixes: &[PathBuf]| {
+            prefixes
+                .iter()
+                .any(|prefix| guest.starts_with(normalize(&base, prefix)))
+        };
+        if under(&self.deny) {
+            return Err(violation(
+                Errno::Access,
+                format!("`{}` is denied", guest.display()),
+            ));
+        }
+        if !self.allow.is_empty() && !under(&self.allow) {
+            return Err(violation(
+                Errno::Access,
+                format!("`{}` is not allowed", guest.display()),
+            ));
+        }
+        if access == Access::Write && self.readonly {
+            return Err(violation(
+                Errno::RoFs,
+                format!("`{}` is read-only", guest.display()),
+            ));
+        }
+        Ok(guest)
+    }
+
+    /// the host path of a resolved guest path, refusing symbolic links that lead out of the root
+    pub fn host_path(&self, guest: &Path) -> Result<PathBuf, ExecutionError> {
+        let Some(root) = &self.root else {
+            return Ok(guest.to_path_buf());
+        };
+        let host = root.join(guest.strip_prefix("/").unwrap_or(guest));
+        for ancestor in host.ancestors() {
+            match ancestor.canonicalize() {
+                Ok(real) if real.starts_with(root) => return Ok(host),
+                Ok(_) => break,
+                // a dangling link could be created through
+                Err(_) if ancestor.symlink_metadata().is_ok() => break,
+                Err(_) => continue,
+            }
+        }
+        Err(violation(
+            Errno::Access,
+            format!("`{}` leads out of the fs root", guest.display()),
+        ))
+    }
+}
+
+fn violation(errno: Errno, error: String) -> ExecutionError {
+    ExecutionError::fault(ErrorKind::Io { errno }, format!("sandbox: {error}"))
+}
+
+/// joins `path` onto `cwd` and removes `.` and `..` without touching the filesystem,
+/// `..` at `/` stays at `/`
+pub(crate) fn normalize(cwd: &Path, path: &Path) -> PathBuf {
+    let mut normal = PathBuf::from("/");
+    for component in cwd.join(path).components() {
+        match component {
+            Component::Prefix(prefix) => normal = PathBuf::from(prefix.as_os_str()),
+            Component::RootDir => normal.push(Component::RootDir),
+            Component::CurDir => {}
+            Component::ParentDir => {
+                normal.pop();
+            }
+            Component::Normal(name) => normal.push(name),
+        }
+    }
+    normal
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sandbox() -> Sandbox {
+        Sandbox::default().root(std::env::temp_dir()).unwrap()
+    }
+
+    #[test]
+    fn parent_dirs_stay_inside_the_root() {
+        let guest = sandbox()
+            .resolve(
+                Path::new("/a"),
+                Path::new("../../../etc/passwd"),
+                Access::Read,
+            )
+            .unwrap();
+        assert_eq!(guest, Path::new("/etc/passwd"));
+        let host = sandbox().host_path(&guest).unwrap();
+        assert!(host.starts_with(std::env::temp_dir().canonicalize().unwrap()));
+    }
+
+    #[test]
+    fn deny_overrides_allow() {
+        let sandbox = sandbox().allow("/data").deny("/data/secret");
+        let resolve = |path: &str| sandbox.resolve(Path::new("/"), Path::new(path), Access::Read);
+        assert!(resolve("data/out.txt").is_ok());
+        assert!(resolve("/other").is_err());
+        let e = resolve("/data/secret/key").unwrap_err();
+        assert_eq!(
+            e.kind,
+            ErrorKind::Io {
+                errno: Errno::Access
+            }
+        );
+    }
+
+    #[test]
+    fn readonly_refuses_writes() {
+        let sandbox = sandbox().readonly(true);
+        let path = Path::new("out.txt");
+        assert!(sandbox.resolve(Path::new("/"), path, Access::Read).is_ok());
+        let e = sandbox
+            .resolve(Path::new("/"), path, Access::Write)
+            .unwrap_err();
+        assert_eq!(e.kind, ErrorKind::Io { errno: Errno::RoFs });
+    }
+}
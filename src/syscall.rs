@@ -0,0 +1,133 @@
+use std::io;
+
+use crate::{kernel::Kernel, ErrorKind, ExecutionError};
+
+/// a host call the guest makes with `int code`, registered on the kernel by code
+pub trait SyscallHandler {
+    /// name shown in the kernel log
+    fn name(&self) -> &str;
+    /// number of arguments the kernel pops off the guest stack before `handle`
+    fn arity(&self) -> usize;
+    /// `args` are in the order the guest pushed them, the returned values are pushed in order
+    fn handle(&mut self, kernel: &mut Kernel, args: &[u64]) -> Result<Vec<u64>, ExecutionError>;
+}
+
+/// a syscall implemented by a function or closure, the built-in NKS syscalls are these
+pub struct Syscall<F> {
+    name: &'static str,
+    arity: usize,
+    handler: F,
+}
+
+impl<F> Syscall<F>
+where
+    F: FnMut(&mut Kernel, &[u64]) -> Result<Vec<u64>, ExecutionError>,
+{
+    pub fn new(name: &'static str, arity: usize, handler: F) -> Self {
+        Self {
+            name,
+            arity,
+            handler,
+        }
+    }
+}
+
+impl<F> SyscallHandler for Syscall<F>
+where
+    F: FnMut(&mut Kernel, &[u64]) -> Result<Vec<u64>, ExecutionError>,
+{
+    fn name(&self) -> &str {
+        self.name
+    }
+
+    fn arity(&self) -> usize {
+        self.arity
+    }
+
+    fn handle(&mut self, kernel: &mut Kernel, args: &[u64]) -> Result<Vec<u64>, ExecutionError> {
+        (self.handler)(kernel, args)
+    }
+}
+
+impl<T: SyscallHandler + ?Sized> SyscallHandler for Box<T> {
+    fn name(&self) -> &str {
+        (**self).name()
+    }
+
+    fn arity(&self) -> usize {
+        (**self).arity()
+    }
+
+    fn handle(&mut self, kernel: &mut Kernel, args: &[u64]) -> Result<Vec<u64>, ExecutionError> {
+        (**self).handle(kernel, args)
+    }
+}
+
+/// NKS error codes, a failed syscall pushes the negated code in place of its result
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Errno {
+    /// the operation is not permitted
+    Perm = 1,
+    /// no such file or directory
+    NoEnt = 2,
+    /// the call was interrupted, such as by ctrl-c
+    Intr = 4,
+    /// host io failure without a more specific code
+    Io = 5,
+    /// the descriptor is not open or not open for the operation
+    BadF = 9,
+    /// permission denied
+    Access = 13,
+    /// the file already exists
+    Exist = 17,
+    NotDir = 20,
+    IsDir = 21,
+    /// invalid argument
+    Inval = 22,
+    /// there is no terminal to control
+    NoTty = 25,
+    /// no space left on the device
+    NoSpc = 28,
+    /// the stream cannot seek
+    SPipe = 29,
+    /// read-only file system
+    RoFs = 30,
+    /// the directory is not empty
+    NotEmpty = 39,
+}
+
+impl Errno {
+    /// the value pushed to the guest, -code as a signed word
+    pub fn result(&self) -> u64 {
+        (*self as i64).wrapping_neg() as u64
+    }
+
+    /// the code a failed syscall reports instead of faulting, None for faults the guest
+    /// cannot recover from such as memory access violations
+    pub fn of(kind: &ErrorKind) -> Option<Errno> {
+        match kind {
+            ErrorKind::Io { errno } => Some(*errno),
+            ErrorKind::BadFileDescriptor => Some(Errno::BadF),
+            ErrorKind::SyscallArgument { .. } => Some(Errno::Inval),
+            _ => None,
+        }
+    }
+}
+
+impl From<&io::Error> for Errno {
+    fn from(e: &io::Error) -> Self {
+        match e.kind() {
+            io::ErrorKind::NotFound => Errno::NoEnt,
+            io::ErrorKind::PermissionDenied => Errno::Access,
+            io::ErrorKind::AlreadyExists => Errno::Exist,
+            io::ErrorKind::NotADirectory => Errno::NotDir,
+            io::ErrorKind::IsADirectory => Errno::IsDir,
+            io::ErrorKind::InvalidInput => Errno::Inval,
+            io::ErrorKind::StorageFull => Errno::NoSpc,
+            io::ErrorKind::NotSeekable => Errno::SPipe,
+            io::ErrorKind::ReadOnlyFilesystem => Errno::RoFs,
+            io::ErrorKind::DirectoryNotEmpty => Errno::NotEmpty,
+            _ => Errno::Io,
+        }
+    }
+}
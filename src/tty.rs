@@ -0,0 +1,374 @@
+use std::{
+    io,
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+
+use crossterm::{
+    cursor::{Hide, MoveDown, MoveLeft, MoveRight, MoveTo, MoveUp, Show},
+    event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers},
+    queue,
+    style::{Color, ResetColor, SetBackgroundColor, SetForegroundColor},
+    terminal::{self, Clear, ClearType},
+};
+
+use rustyline::{error::ReadlineError, DefaultEditor};
+
+use crate::{syscall::Errno, ErrorKind, ExecutionError};
+
+/// `op` values of the tty_control syscall
+pub const TTY_CLEAR_SCREEN: u64 = 0;
+pub const TTY_CLEAR_LINE: u64 = 1;
+pub const TTY_FOREGROUND: u64 = 2;
+pub const TTY_BACKGROUND: u64 = 3;
+pub const TTY_RESET_COLORS: u64 = 4;
+pub const TTY_HIDE_CURSOR: u64 = 5;
+pub const TTY_SHOW_CURSOR: u64 = 6;
+
+/// set in a color argument when its low 24 bits are `0xrrggbb` rather than an ansi color index
+pub const TTY_RGB: u64 = 1 << 24;
+
+/// `timeout` of the input syscalls that waits until input arrives
+pub const TTY_BLOCK: u64 = u64::MAX;
+
+/// read_key_event codes of keys without a character, past the unicode range
+pub const KEY_UP: u64 = 0x11_0000;
+pub const KEY_DOWN: u64 = 0x11_0001;
+pub const KEY_LEFT: u64 = 0x11_0002;
+pub const KEY_RIGHT: u64 = 0x11_0003;
+pub const KEY_HOME: u64 = 0x11_0004;
+pub const KEY_END: u64 = 0x11_0005;
+pub const KEY_PAGE_UP: u64 = 0x11_0006;
+pub const KEY_PAGE_DOWN: u64 = 0x11_0007;
+pub const KEY_INSERT: u64 = 0x11_0008;
+pub const KEY_DELETE: u64 = 0x11_0009;
+/// function key n is `KEY_F0 + n`
+pub const KEY_F0: u64 = 0x11_0100;
+
+/// modifier bits of read_key_event results, above the key code
+pub const KEY_SHIFT: u64 = 1 << 32;
+pub const KEY_CONTROL: u64 = 1 << 33;
+pub const KEY_ALT: u64 = 1 << 34;
+pub const KEY_SUPER: u64 = 1 << 35;
+
+/// result of readline at the end of input, just below the errno range so it is negative as well
+pub const READLINE_EOF: u64 = -4096i64 as u64;
+
+/// state of the host terminal changed by the guest, undone when the vm stops
+#[derive(Debug, Default)]
+pub struct Tty {
+    raw: bool,
+    /// colors were set or the cursor hidden
+    styled: bool,
+}
+
+impl Tty {
+    pub fn raw(&self) -> bool {
+        self.raw
+    }
+
+    /// enters or leaves raw mode, where input is unbuffered and not echoed
+    pub fn set_raw(&mut self, raw: bool) -> Result<(), ExecutionError> {
+        let result = match raw {
+            true => terminal::enable_raw_mode(),
+            false => terminal::disable_raw_mode(),
+        };
+        result.map_err(|e| no_tty(format!("cannot switch raw mode: {e}")))?;
+        self.raw = raw;
+        Ok(())
+    }
+
+    /// the escape sequence moving the cursor `dx` columns right and `dy` rows down
+    pub fn move_by(&self, dx: i64, dy: i64) -> Vec<u8> {
+        let distance = |n: i64| n.unsigned_abs().min(u16::MAX as u64) as u16;
+        sequence(|out| {
+            // a 0 distance still moves one cell on most terminals
+            match dx.signum() {
+                1 => queue!(out, MoveRight(distance(dx)))?,
+                -1 => queue!(out, MoveLeft(distance(dx)))?,
+                _ => {}
+            }
+            match dy.signum() {
+                1 => queue!(out, MoveDown(distance(dy))),
+                -1 => queue!(out, MoveUp(distance(dy))),
+                _ => Ok(()),
+            }
+        })
+    }
+
+    /// the escape sequence moving the cursor to a 0 based column and row
+    pub fn move_to(&self, column: u64, row: u64) -> Result<Vec<u8>, ExecutionError> {
+        let (Ok(column), Ok(row)) = (u16::try_from(column), u16::try_from(row)) else {
+            return Err(ExecutionError::fault(
+                ErrorKind::SyscallArgument { code: 0x09 },
+                format!("cursor position {column},{row} is out of range"),
+            ));
+        };
+        Ok(sequence(|out| queue!(out, MoveTo(column, row))))
+    }
+
+    /// the escape sequence of a tty_control operation
+    pub fn control(&mut self, op: u64, arg: u64) -> Result<Vec<u8>, ExecutionError> {
+        let sequence = match op {
+            TTY_CLEAR_SCREEN => sequence(|out| queue!(out, Clear(ClearType::All), MoveTo(0, 0))),
+            TTY_CLEAR_LINE => sequence(|out| queue!(out, Clear(ClearType::CurrentLine))),
+            TTY_FOREGROUND => {
+                let color = color(arg)?;
+                sequence(|out| queue!(out, SetForegroundColor(color)))
+            }
+            TTY_BACKGROUND => {
+                let color = color(arg)?;
+                sequence(|out| queue!(out, SetBackgroundColor(color)))
+            }
+            TTY_RESET_COLORS => sequence(|out| queue!(out, ResetColor)),
+            TTY_HIDE_CURSOR => sequence(|out| queue!(out, Hide)),
+            TTY_SHOW_CURSOR => sequence(|out| queue!(out, Show)),
+            _ => {
+                return Err(ExecutionError::fault(
+                    ErrorKind::SyscallArgument { code: 0x2c },
+                    format!("invalid tty_control operation {op}"),
+                ))
+            }
+        };
+        self.styled |= matches!(op, TTY_FOREGROUND | TTY_BACKGROUND | TTY_HIDE_CURSOR);
+        Ok(sequence)
+    }
+
+    /// columns and rows of the host terminal
+    pub fn size(&self) -> Result<(u16, u16), ExecutionError> {
+        terminal::size().map_err(|e| no_tty(format!("cannot query the terminal size: {e}")))
+    }
+
+    /// whether an input event arrives within `timeout` milliseconds, without consuming it.
+    /// [`TTY_BLOCK`] waits indefinitely
+    pub fn poll(&self, timeout: u64) -> Result<bool, ExecutionError> {
+        let timeout = match timeout {
+            TTY_BLOCK => Duration::MAX,
+            _ => Duration::from_millis(timeout),
+        };
+        event::poll(timeout).map_err(|e| no_tty(format!("cannot poll terminal input: {e}")))
+    }
+
+    /// the [`key_code`] of the next key press within `timeout` milliseconds, discarding other
+    /// events. [`TTY_BLOCK`] waits indefinitely
+    pub fn read_key(&self, timeout: u64) -> Result<Option<u64>, ExecutionError> {
+        let deadline = match timeout {
+            TTY_BLOCK => None,
+            _ => Instant::now().checked_add(Duration::from_millis(timeout)),
+        };
+        loop {
+            if let Some(deadline) = deadline {
+                if !self.poll(
+                    deadline
+                        .saturating_duration_since(Instant::now())
+                        .as_millis() as u64,
+                )? {
+                    return Ok(None);
+                }
+            }
+            let event =
+                event::read().map_err(|e| no_tty(format!("cannot read terminal input: {e}")))?;
+            if let Some(code) = match event {
+                Event::Key(key) => key_code(key),
+                _ => None,
+            } {
+                return Ok(Some(code));
+            }
+        }
+    }
+
+    /// leaves raw mode and returns the escape sequence resetting colors and showing the cursor,
+    /// which is empty if the guest changed neither
+    pub fn restore(&mut self) -> Vec<u8> {
+        if self.raw {
+            // nothing is left to do if the terminal went away
+            let _ = self.set_raw(false);
+        }
+        if !std::mem::take(&mut self.styled) {
+            return Vec::new();
+        }
+        sequence(|out| queue!(out, ResetColor, Show))
+    }
+}
+
+impl Drop for Tty {
+    /// a vm dropped mid-run must not leave the host terminal raw
+    fn drop(&mut self) {
+        if self.raw {
+            let _ = terminal::disable_raw_mode();
+        }
+    }
+}
+
+/// line editing for the readline syscall
+pub struct LineEditor {
+    editor: DefaultEditor,
+    /// file the history is loaded from and appended to
+    history: Option<PathBuf>,
+}
+
+impl LineEditor {
+    pub fn new(history: Option<PathBuf>) -> Result<Self, ExecutionError> {
+        let mut editor = DefaultEditor::new()
+            .map_err(|e| readline_error(e, "failed to initialize line editing"))?;
+        if let Some(path) = &history {
+            // the file is created with the first line
+            let _ = editor.load_history(path);
+        }
+        Ok(Self { editor, history })
+    }
+
+    /// reads a line, None at the end of input
+    pub fn readline(&mut self, prompt: &str) -> Result<Option<String>, ExecutionError> {
+        let line = match self.editor.readline(prompt) {
+            Ok(line) => line,
+            Err(ReadlineError::Eof) => return Ok(None),
+            Err(ReadlineError::Interrupted) => {
+                return Err(ExecutionError::fault(
+                    ErrorKind::Io { errno: Errno::Intr },
+                    "readline interrupted".to_string(),
+                ))
+            }
+            Err(e) => return Err(readline_error(e, "readline failed")),
+        };
+        if let (Ok(true), Some(path)) = (self.editor.add_history_entry(&line), &self.history) {
+            // losing history is not worth failing the read over
+            let _ = self.editor.append_history(path);
+        }
+        Ok(Some(line))
+    }
+}
+
+fn readline_error(e: ReadlineError, context: &str) -> ExecutionError {
+    match e {
+        ReadlineError::Io(e) => ExecutionError::io(&e, format!("{context}: {e}")),
+        e => ExecutionError::fault(
+            ErrorKind::Io { errno: Errno::Io },
+            format!("{context}: {e}"),
+        ),
+    }
+}
+
+/// the unicode value of a character key or a `KEY_*` code, combined with the `KEY_*` modifier bits.
+/// releases and keys the guest cannot tell apart, such as caps lock, are None
+pub fn key_code(key: KeyEvent) -> Option<u64> {
+    if key.kind == KeyEventKind::Release {
+        return None;
+    }
+    let code = match key.code {
+        KeyCode::Char(c) => c as u64,
+        KeyCode::Enter => b'\r' as u64,
+        KeyCode::Tab | KeyCode::BackTab => b'\t' as u64,
+        KeyCode::Backspace => 0x7f,
+        KeyCode::Esc => 0x1b,
+        KeyCode::Up => KEY_UP,
+        KeyCode::Down => KEY_DOWN,
+        KeyCode::Left => KEY_LEFT,
+        KeyCode::Right => KEY_RIGHT,
+        KeyCode::Home => KEY_HOME,
+        KeyCode::End => KEY_END,
+        KeyCode::PageUp => KEY_PAGE_UP,
+        KeyCode::PageDown => KEY_PAGE_DOWN,
+        KeyCode::Insert => KEY_INSERT,
+        KeyCode::Delete => KEY_DELETE,
+        KeyCode::F(n) => KEY_F0 + n as u64,
+        _ => return None,
+    };
+    let modifiers = [
+        (KeyModifiers::SHIFT, KEY_SHIFT),
+        (KeyModifiers::CONTROL, KEY_CONTROL),
+        (KeyModifiers::ALT, KEY_ALT),
+        (KeyModifiers::SUPER, KEY_SUPER),
+    ]
+    .into_iter()
+    .filter(|&(modifier, _)| key.modifiers.contains(modifier))
+    .fold(0, |bits, (_, bit)| bits | bit);
+    // back tab is shift+tab, but some terminals report it without shift
+    let modifiers = match key.code {
+        KeyCode::BackTab => modifiers | KEY_SHIFT,
+        _ => modifiers,
+    };
+    Some(code | modifiers)
+}
+
+/// collects the escape sequence of crossterm commands queued by `queue`
+fn sequence(queue: impl FnOnce(&mut Vec<u8>) -> io::Result<()>) -> Vec<u8> {
+    let mut sequence = Vec::new();
+    queue(&mut sequence).expect("writing to a vec cannot fail");
+    sequence
+}
+
+/// an ansi color index below 256, or [`TTY_RGB`] with a 24-bit color
+fn color(arg: u64) -> Result<Color, ExecutionError> {
+    match arg {
+        0..=0xff => Ok(Color::AnsiValue(arg as u8)),
+        _ if arg & !0xff_ffff == TTY_RGB => Ok(Color::Rgb {
+            r: (arg >> 16) as u8,
+            g: (arg >> 8) as u8,
+            b: arg as u8,
+        }),
+        _ => Err(ExecutionError::fault(
+            ErrorKind::SyscallArgument { code: 0x2c },
+            format!("invalid color {arg:#x}"),
+        )),
+    }
+}
+
+fn no_tty(error: String) -> ExecutionError {
+    ExecutionError::fault(
+        ErrorKind::Io {
+            errno: Errno::NoTty,
+        },
+        error,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn relative_moves_skip_zero_distances() {
+        let tty = Tty::default();
+        assert_eq!(tty.move_by(3, -2), b"\x1b[3C\x1b[2A");
+        assert_eq!(tty.move_by(0, 1), b"\x1b[1B");
+        assert!(tty.move_by(0, 0).is_empty());
+    }
+
+    #[test]
+    fn colors_are_reset_on_restore() {
+        let mut tty = Tty::default();
+        assert!(tty.restore().is_empty());
+        let red = tty.control(TTY_FOREGROUND, TTY_RGB | 0xff0000).unwrap();
+        assert_eq!(red, b"\x1b[38;2;255;0;0m");
+        assert!(tty.control(TTY_BACKGROUND, 1 << 32).is_err());
+        assert!(tty.control(7, 0).is_err());
+        assert_eq!(tty.restore(), b"\x1b[0m\x1b[?25h");
+        assert!(tty.restore().is_empty());
+    }
+
+    #[test]
+    fn keys_are_encoded_with_modifiers() {
+        let key = |code, modifiers| key_code(KeyEvent::new(code, modifiers));
+        assert_eq!(
+            key(KeyCode::Char('q'), KeyModifiers::NONE),
+            Some('q' as u64)
+        );
+        assert_eq!(
+            key(KeyCode::Left, KeyModifiers::CONTROL | KeyModifiers::SHIFT),
+            Some(KEY_LEFT | KEY_CONTROL | KEY_SHIFT)
+        );
+        assert_eq!(
+            key(KeyCode::F(5), KeyModifiers::ALT),
+            Some((KEY_F0 + 5) | KEY_ALT)
+        );
+        assert_eq!(
+            key(KeyCode::BackTab, KeyModifiers::NONE),
+            Some(0x09 | KEY_SHIFT)
+        );
+        assert_eq!(key(KeyCode::CapsLock, KeyModifiers::NONE), None);
+        let release =
+            KeyEvent::new_with_kind(KeyCode::Up, KeyModifiers::NONE, KeyEventKind::Release);
+        assert_eq!(key_code(release), None);
+    }
+}
@@ -0,0 +1,595 @@
+use std::{
+    cell::RefCell,
+    collections::BTreeMap,
+    fs::{self, Metadata, OpenOptions},
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    rc::Rc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{sandbox::normalize, ExecutionError};
+
+const BLOCK: usize = 512;
+
+/// `type` values of the stat syscall
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FileKind {
+    File = 0,
+    Dir = 1,
+    Other = 2,
+}
+
+/// what stat reports about a file on either backend
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Stat {
+    pub size: u64,
+    pub kind: FileKind,
+    /// seconds since the unix epoch, 0 if unknown
+    pub mtime: u64,
+}
+
+impl From<&Metadata> for Stat {
+    fn from(metadata: &Metadata) -> Self {
+        let kind = if metadata.is_file() {
+            FileKind::File
+        } else if metadata.is_dir() {
+            FileKind::Dir
+        } else {
+            FileKind::Other
+        };
+        Self {
+            size: metadata.len(),
+            kind,
+            mtime: metadata.modified().map_or(0, seconds),
+        }
+    }
+}
+
+/// how a file is opened, the same flags as `OpenOptions` so both backends behave alike
+#[derive(Debug, Clone, Copy, Default)]
+pub struct OpenMode {
+    pub read: bool,
+    pub write: bool,
+    pub append: bool,
+    pub truncate: bool,
+    pub create: bool,
+    /// create the file, failing if it exists
+    pub create_new: bool,
+}
+
+impl OpenMode {
+    pub fn read_only() -> Self {
+        Self {
+            read: true,
+            ..Self::default()
+        }
+    }
+
+    /// whether the file can be created or changed through this mode
+    pub fn modifies(&self) -> bool {
+        self.write || self.append || self.truncate || self.create || self.create_new
+    }
+
+    pub fn options(&self) -> OpenOptions {
+        let mut options = OpenOptions::new();
+        options
+            .read(self.read)
+            .write(self.write)
+            .append(self.append)
+            .truncate(self.truncate)
+            .create(self.create)
+            .create_new(self.create_new);
+        options
+    }
+
+    /// rejects the combinations `OpenOptions::open` rejects
+    fn validate(&self) -> io::Result<()> {
+        let writes = self.write || self.append;
+        let creates = self.truncate || self.create || self.create_new;
+        let accessible = self.read || writes;
+        if !accessible || creates && !writes || self.truncate && self.append {
+            return Err(io::ErrorKind::InvalidInput.into());
+        }
+        Ok(())
+    }
+}
+
+fn seconds(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs())
+}
+
+fn now() -> u64 {
+    seconds(SystemTime::now())
+}
+
+struct Contents {
+    data: Vec<u8>,
+    mtime: u64,
+}
+
+enum Node {
+    Dir {
+        mtime: u64,
+    },
+    /// shared with the descriptors it is open in
+    File(Rc<RefCell<Contents>>),
+}
+
+impl Node {
+    fn stat(&self) -> Stat {
+        match self {
+            Node::Dir { mtime } => Stat {
+                size: 0,
+                kind: FileKind::Dir,
+                mtime: *mtime,
+            },
+            Node::File(contents) => {
+                let contents = contents.borrow();
+                Stat {
+                    size: contents.data.len() as u64,
+                    kind: FileKind::File,
+                    mtime: contents.mtime,
+                }
+            }
+        }
+    }
+}
+
+/// an in-memory file tree keyed by absolute guest path, file syscalls use it instead of
+/// the host filesystem once it is set on the kernel
+pub struct Vfs {
+    nodes: BTreeMap<PathBuf, Node>,
+}
+
+impl Default for Vfs {
+    /// an empty tree with only `/`
+    fn default() -> Self {
+        let mut nodes = BTreeMap::new();
+        nodes.insert(PathBuf::from("/"), Node::Dir { mtime: now() });
+        Self { nodes }
+    }
+}
+
+impl Vfs {
+    /// copies a host directory, or the contents of a tar archive, into memory
+    pub fn load(source: impl AsRef<Path>) -> Result<Self, ExecutionError> {
+        let source = source.as_ref();
+        let fail = |e: io::Error| {
+            ExecutionError::io(&e, format!("cannot load vfs `{}`: {e}", source.display()))
+        };
+        let mut vfs = Self::default();
+        if source.is_dir() {
+            vfs.copy_dir(source, Path::new("/")).map_err(fail)?;
+        } else {
+            vfs.unpack(&fs::read(source).map_err(fail)?)
+                .map_err(|e| e.prepend(format!("cannot load vfs `{}`: ", source.display())))?;
+        }
+        Ok(vfs)
+    }
+
+    /// writes the tree to a tar archive if `dest` ends in `.tar`, otherwise into the directory `dest`
+    pub fn save(&self, dest: impl AsRef<Path>) -> Result<(), ExecutionError> {
+        let dest = dest.as_ref();
+        let fail = |e: io::Error| {
+            ExecutionError::io(&e, format!("cannot save vfs to `{}`: {e}", dest.display()))
+        };
+        if dest.extension().is_some_and(|extension| extension == "tar") {
+            return fs::write(dest, self.pack()?).map_err(fail);
+        }
+        for (path, node) in &self.nodes {
+            let host = dest.join(path.strip_prefix("/").unwrap_or(path));
+            match node {
+                Node::Dir { .. } => fs::create_dir_all(host),
+                Node::File(contents) => fs::write(host, &contents.borrow().data),
+            }
+            .map_err(fail)?;
+        }
+        Ok(())
+    }
+
+    /// the contents of a file
+    pub fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        match self.node(path)? {
+            Node::Dir { .. } => Err(io::ErrorKind::IsADirectory.into()),
+            Node::File(contents) => Ok(contents.borrow().data.clone()),
+        }
+    }
+
+    pub fn stat(&self, path: &Path) -> io::Result<Stat> {
+        Ok(self.node(path)?.stat())
+    }
+
+    /// names of the entries of a directory
+    pub fn read_dir(&self, path: &Path) -> io::Result<Vec<String>> {
+        match self.node(path)? {
+            Node::Dir { .. } => Ok(self
+                .children(path)
+                .filter_map(|child| child.file_name())
+                .map(|name| name.to_string_lossy().into_owned())
+                .collect()),
+            Node::File(_) => Err(io::ErrorKind::NotADirectory.into()),
+        }
+    }
+
+    pub fn create_dir(&mut self, path: &Path) -> io::Result<()> {
+        self.parent_dir(path)?;
+        if self.nodes.contains_key(path) {
+            return Err(io::ErrorKind::AlreadyExists.into());
+        }
+        self.nodes
+            .insert(path.to_path_buf(), Node::Dir { mtime: now() });
+        Ok(())
+    }
+
+    pub fn remove_dir(&mut self, path: &Path) -> io::Result<()> {
+        match self.node(path)? {
+            Node::File(_) => Err(io::ErrorKind::NotADirectory.into()),
+            _ if path == Path::new("/") => Err(io::ErrorKind::PermissionDenied.into()),
+            _ if self.children(path).next().is_some() => {
+                Err(io::ErrorKind::DirectoryNotEmpty.into())
+            }
+            _ => {
+                self.nodes.remove(path);
+                Ok(())
+            }
+        }
+    }
+
+    pub fn remove_file(&mut self, path: &Path) -> io::Result<()> {
+        match self.node(path)? {
+            Node::Dir { .. } => Err(io::ErrorKind::IsADirectory.into()),
+            Node::File(_) => {
+                self.nodes.remove(path);
+                Ok(())
+            }
+        }
+    }
+
+    /// moves a file or a directory with everything in it, replacing a file or empty directory at `to`
+    pub fn rename(&mut self, from: &Path, to: &Path) -> io::Result<()> {
+        let moving_dir = matches!(self.node(from)?, Node::Dir { .. });
+        self.parent_dir(to)?;
+        if from == Path::new("/") || to != from && to.starts_with(from) {
+            return Err(io::ErrorKind::InvalidInput.into());
+        }
+        match self.nodes.get(to) {
+            Some(Node::Dir { .. }) if !moving_dir => return Err(io::ErrorKind::IsADirectory.into()),
+            Some(Node::Dir { .. }) if self.children(to).next().is_some() => {
+                return Err(io::ErrorKind::DirectoryNotEmpty.into())
+            }
+            Some(Node::File(_)) if moving_dir => return Err(io::ErrorKind::NotADirectory.into()),
+            _ => {}
+        }
+        let moved: Vec<PathBuf> = self
+            .nodes
+            .range(from.to_path_buf()..)
+            .map(|(path, _)| path)
+            .take_while(|path| path.starts_with(from))
+            .cloned()
+            .collect();
+        for path in moved {
+            let node = self.nodes.remove(&path).expect("collected above");
+            let rest = path.strip_prefix(from).expect("collected above");
+            self.nodes.insert(to.join(rest), node);
+        }
+        Ok(())
+    }
+
+    pub fn open(&mut self, path: &Path, mode: OpenMode) -> io::Result<VirtualFile> {
+        mode.validate()?;
+        let contents = match self.nodes.get(path) {
+            Some(Node::Dir { .. }) => return Err(io::ErrorKind::IsADirectory.into()),
+            Some(Node::File(_)) if mode.create_new => {
+                return Err(io::ErrorKind::AlreadyExists.into())
+            }
+            Some(Node::File(contents)) => contents.clone(),
+            None if mode.create || mode.create_new => {
+                self.parent_dir(path)?;
+                let contents = Rc::new(RefCell::new(Contents {
+                    data: Vec::new(),
+                    mtime: now(),
+                }));
+                self.nodes
+                    .insert(path.to_path_buf(), Node::File(contents.clone()));
+                contents
+            }
+            None => return Err(io::ErrorKind::NotFound.into()),
+        };
+        if mode.truncate {
+            let mut contents = contents.borrow_mut();
+            contents.data.clear();
+            contents.mtime = now();
+        }
+        Ok(VirtualFile {
+            contents,
+            position: 0,
+            mode,
+        })
+    }
+
+    fn node(&self, path: &Path) -> io::Result<&Node> {
+        self.nodes.get(path).ok_or(io::ErrorKind::NotFound.into())
+    }
+
+    fn parent_dir(&self, path: &Path) -> io::Result<()> {
+        match path.parent().map(|parent| self.node(parent)) {
+            Some(Ok(Node::Dir { .. })) => Ok(()),
+            Some(Ok(Node::File(_))) => Err(io::ErrorKind::NotADirectory.into()),
+            Some(Err(e)) => Err(e),
+            // `/` itself
+            None => Err(io::ErrorKind::AlreadyExists.into()),
+        }
+    }
+
+    fn children<'a>(&'a self, path: &'a Path) -> impl Iterator<Item = &'a PathBuf> {
+        self.nodes
+            .range(path.to_path_buf()..)
+            .map(|(child, _)| child)
+            .take_while(move |child| child.starts_with(path))
+            .filter(move |child| child.parent() == Some(path))
+    }
+
+    /// inserts a file, creating missing parent directories like tar archives expect
+    fn insert_file(&mut self, path: PathBuf, data: Vec<u8>, mtime: u64) {
+        if let Some(parent) = path.parent() {
+            self.insert_dir(parent.to_path_buf(), mtime);
+        }
+        let contents = Rc::new(RefCell::new(Contents { data, mtime }));
+        self.nodes.insert(path, Node::File(contents));
+    }
+
+    fn insert_dir(&mut self, path: PathBuf, mtime: u64) {
+        for ancestor in path.ancestors() {
+            self.nodes
+                .entry(ancestor.to_path_buf())
+                .or_insert(Node::Dir { mtime });
+        }
+    }
+
+    fn copy_dir(&mut self, host: &Path, guest: &Path) -> io::Result<()> {
+        for entry in fs::read_dir(host)? {
+            let entry = entry?;
+            let path = guest.join(entry.file_name());
+            let metadata = fs::metadata(entry.path())?;
+            let mtime = metadata.modified().map_or(0, seconds);
+            if metadata.is_dir() {
+                self.insert_dir(path.clone(), mtime);
+                self.copy_dir(&entry.path(), &path)?;
+            } else if metadata.is_file() {
+                self.insert_file(path, fs::read(entry.path())?, mtime);
+            }
+        }
+        Ok(())
+    }
+
+    /// reads ustar archives, including gnu long names. links, devices and pax headers are skipped
+    fn unpack(&mut self, archive: &[u8]) -> Result<(), ExecutionError> {
+        let mut offset = 0;
+        let mut long_name = None;
+        while let Some(header) = archive.get(offset..offset + BLOCK) {
+            if header.iter().all(|&byte| byte == 0) {
+                break;
+            }
+            let size = octal(&header[124..136])? as usize;
+            let mtime = octal(&header[136..148]).unwrap_or(0);
+            let start = offset + BLOCK;
+            let data = archive
+                .get(start..start + size)
+                .ok_or(ExecutionError::new("truncated tar archive".to_string()))?;
+            offset = start + size.div_ceil(BLOCK) * BLOCK;
+            let name = match long_name.take() {
+                Some(name) => name,
+                None if &header[257..262] == b"ustar" && header[345] != 0 => {
+                    format!("{}/{}", field(&header[345..500]), field(&header[..100]))
+                }
+                None => field(&header[..100]),
+            };
+            let path = normalize(Path::new("/"), Path::new(&name));
+            match header[156] {
+                b'L' => long_name = Some(field(data)),
+                b'0' | b'7' | 0 => self.insert_file(path, data.to_vec(), mtime),
+                b'5' => self.insert_dir(path, mtime),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    fn pack(&self) -> Result<Vec<u8>, ExecutionError> {
+        let mut archive = Vec::new();
+        for (path, node) in self.nodes.iter().skip(1) {
+            let name = path.strip_prefix("/").unwrap_or(path).to_string_lossy();
+            let header = match node {
+                Node::Dir { mtime } => header(&format!("{name}/"), 0, *mtime, b'5')?,
+                Node::File(contents) => {
+                    let contents = contents.borrow();
+                    header(&name, contents.data.len(), contents.mtime, b'0')?
+                }
+            };
+            archive.extend(header);
+            if let Node::File(contents) = node {
+                let data = &contents.borrow().data;
+                archive.extend(data);
+                archive.resize(archive.len().next_multiple_of(BLOCK), 0);
+            }
+        }
+        archive.resize(archive.len() + 2 * BLOCK, 0);
+        Ok(archive)
+    }
+}
+
+/// a nul terminated header field
+fn field(bytes: &[u8]) -> String {
+    let end = bytes
+        .iter()
+        .position(|&byte| byte == 0)
+        .unwrap_or(bytes.len());
+    String::from_utf8_lossy(&bytes[..end]).into_owned()
+}
+
+fn octal(bytes: &[u8]) -> Result<u64, ExecutionError> {
+    let digits = field(bytes);
+    let digits = digits.trim_matches(|c: char| c == ' ' || c == '\0');
+    u64::from_str_radix(digits, 8)
+        .or_else(|e| match digits.is_empty() {
+            true => Ok(0),
+            false => Err(e),
+        })
+        .map_err(|_| ExecutionError::new(format!("invalid tar header number `{digits}`")))
+}
+
+fn header(name: &str, size: usize, mtime: u64, kind: u8) -> Result<[u8; BLOCK], ExecutionError> {
+    // names over 100 bytes are split into a prefix at a `/`
+    let (prefix, name) = match name.len() {
+        0..=100 => ("", name),
+        _ => name
+            .char_indices()
+            .filter(|&(i, c)| c == '/' && i <= 155 && name.len() - i - 1 <= 100)
+            .map(|(i, _)| (&name[..i], &name[i + 1..]))
+            .next()
+            .ok_or(ExecutionError::new(format!(
+                "`{name}` is too long for a tar archive"
+            )))?,
+    };
+    let mode = if kind == b'5' { 0o755 } else { 0o644 };
+    let mut header = [0; BLOCK];
+    let mut put = |offset: usize, value: &[u8]| {
+        header[offset..offset + value.len()].copy_from_slice(value);
+    };
+    put(0, name.as_bytes());
+    put(100, format!("{mode:07o}\0").as_bytes());
+    put(108, b"0000000\0");
+    put(116, b"0000000\0");
+    put(124, format!("{size:011o}\0").as_bytes());
+    put(136, format!("{mtime:011o}\0").as_bytes());
+    put(148, b"        ");
+    put(156, &[kind]);
+    put(257, b"ustar\x0000");
+    put(345, prefix.as_bytes());
+    let checksum: u32 = header.iter().map(|&byte| byte as u32).sum();
+    header[148..156].copy_from_slice(format!("{checksum:06o}\0 ").as_bytes());
+    Ok(header)
+}
+
+/// an open file of a [`Vfs`]
+pub struct VirtualFile {
+    contents: Rc<RefCell<Contents>>,
+    position: u64,
+    mode: OpenMode,
+}
+
+impl VirtualFile {
+    pub fn stat(&self) -> Stat {
+        let contents = self.contents.borrow();
+        Stat {
+            size: contents.data.len() as u64,
+            kind: FileKind::File,
+            mtime: contents.mtime,
+        }
+    }
+}
+
+impl Read for VirtualFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if !self.mode.read {
+            return Err(io::ErrorKind::PermissionDenied.into());
+        }
+        let contents = self.contents.borrow();
+        let start = (self.position as usize).min(contents.data.len());
+        let n = buf.len().min(contents.data.len() - start);
+        buf[..n].copy_from_slice(&contents.data[start..start + n]);
+        self.position += n as u64;
+        Ok(n)
+    }
+}
+
+impl Write for VirtualFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if !self.mode.write && !self.mode.append {
+            return Err(io::ErrorKind::PermissionDenied.into());
+        }
+        let mut contents = self.contents.borrow_mut();
+        if self.mode.append {
+            self.position = contents.data.len() as u64;
+        }
+        let start = self.position as usize;
+        let end = start + buf.len();
+        if contents.data.len() < end {
+            contents.data.resize(end, 0);
+        }
+        contents.data[start..end].copy_from_slice(buf);
+        contents.mtime = now();
+        self.position = end as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for VirtualFile {
+    fn seek(&mut self, from: SeekFrom) -> io::Result<u64> {
+        let (base, offset) = match from {
+            SeekFrom::Start(offset) => (0, offset as i64),
+            SeekFrom::Current(offset) => (self.position, offset),
+            SeekFrom::End(offset) => (self.contents.borrow().data.len() as u64, offset),
+        };
+        self.position = base
+            .checked_add_signed(offset)
+            .ok_or(io::Error::from(io::ErrorKind::InvalidInput))?;
+        Ok(self.position)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tar_archives_round_trip() {
+        let mut vfs = Vfs::default();
+        vfs.create_dir(Path::new("/dir")).unwrap();
+        let mut file = vfs
+            .open(
+                Path::new("/dir/file"),
+                OpenMode {
+                    write: true,
+                    create: true,
+                    ..OpenMode::default()
+                },
+            )
+            .unwrap();
+        file.write_all(b"contents").unwrap();
+        let long = format!("/dir/{}", "x".repeat(120));
+        vfs.create_dir(Path::new(&long)).unwrap();
+
+        let mut unpacked = Vfs::default();
+        unpacked.unpack(&vfs.pack().unwrap()).unwrap();
+        assert_eq!(unpacked.read_dir(Path::new("/dir")).unwrap().len(), 2);
+        let mut data = String::new();
+        unpacked
+            .open(Path::new("/dir/file"), OpenMode::read_only())
+            .unwrap()
+            .read_to_string(&mut data)
+            .unwrap();
+        assert_eq!(data, "contents");
+    }
+
+    #[test]
+    fn renaming_a_directory_moves_its_entries() {
+        let mut vfs = Vfs::default();
+        vfs.insert_file(PathBuf::from("/a/b/c"), b"c".to_vec(), 0);
+        vfs.rename(Path::new("/a"), Path::new("/z")).unwrap();
+        assert_eq!(vfs.stat(Path::new("/z/b/c")).unwrap().size, 1);
+        assert!(vfs.stat(Path::new("/a")).is_err());
+        assert_eq!(
+            vfs.rename(Path::new("/z"), Path::new("/z/b/y"))
+                .unwrap_err()
+                .kind(),
+            io::ErrorKind::InvalidInput
+        );
+    }
+}
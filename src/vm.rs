@@ -0,0 +1,1034 @@
+use std::{
+    cell::RefCell,
+    io::{self, Read, Write},
+    path::PathBuf,
+    rc::Rc,
+};
+
+use crate::{
+    constant::{DEFAULT_HEAP_SIZE, DEFAULT_STACK_SIZE},
+    gpu::{GpuBackend, GpuConfig},
+    kernel::Kernel,
+    logger::{LogConfig, Logger},
+    sandbox::Sandbox,
+    syscall::SyscallHandler,
+    vfs::Vfs,
+    ExecutionError,
+};
+
+/// a guest output stream kept in memory until it is taken
+#[derive(Clone, Default)]
+struct Capture(Rc<RefCell<Vec<u8>>>);
+
+impl Capture {
+    fn take(&self) -> Vec<u8> {
+        std::mem::take(&mut self.0.borrow_mut())
+    }
+}
+
+impl Write for Capture {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// configures a [`Vm`], by default stdin is empty, stdout and stderr are captured,
+/// the clock is unthrottled, nothing is logged, no core dumps are written and the
+/// frame buffer is headless
+pub struct VmBuilder {
+    heap: u64,
+    stack: u64,
+    clock_speed: f32,
+    args: Vec<String>,
+    stdin: Box<dyn Read>,
+    stdout: Option<Box<dyn Write>>,
+    stderr: Option<Box<dyn Write>>,
+    log: Logger,
+    core_dumps: bool,
+    core_dir: PathBuf,
+    strict_syscalls: bool,
+    sandbox: Sandbox,
+    vfs: Option<Vfs>,
+    gpu: GpuConfig,
+    syscalls: Vec<(u8, Box<dyn SyscallHandler>)>,
+}
+
+impl Default for VmBuilder {
+    fn default() -> Self {
+        Self {
+            heap: DEFAULT_HEAP_SIZE,
+            stack: DEFAULT_STACK_SIZE,
+            clock_speed: f32::INFINITY,
+            args: Vec::new(),
+            stdin: Box::new(io::empty()),
+            stdout: None,
+            stderr: None,
+            log: Logger::silent(),
+            core_dumps: false,
+            core_dir: PathBuf::new(),
+            strict_syscalls: false,
+            sandbox: Sandbox::default(),
+            vfs: None,
+            gpu: GpuConfig {
+                backend: GpuBackend::Headless,
+                ..GpuConfig::default()
+            },
+            syscalls: Vec::new(),
+        }
+    }
+}
+
+impl VmBuilder {
+    /// heap size in bytes
+    pub fn heap(mut self, heap: u64) -> Self {
+        self.heap = heap;
+        self
+    }
+
+    /// stack size in bytes
+    pub fn stack(mut self, stack: u64) -> Self {
+        self.stack = stack;
+        self
+    }
+
+    /// steps per second
+    pub fn clock_speed(mut self, clock_speed: f32) -> Self {
+        self.clock_speed = clock_speed;
+        self
+    }
+
+    /// arguments returned by `get_argc` and `get_argv`
+    pub fn args(mut self, args: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        self.args = args.into_iter().map(Into::into).collect();
+        self
+    }
+
+    pub fn stdin(mut self, stdin: impl Read + 'static) -> Self {
+        self.stdin = Box::new(stdin);
+        self
+    }
+
+    /// sends guest stdout to `stdout` instead of capturing it
+    pub fn stdout(mut self, stdout: impl Write + 'static) -> Self {
+        self.stdout = Some(Box::new(stdout));
+        self
+    }
+
+    /// sends guest stderr to `stderr` instead of capturing it
+    pub fn stderr(mut self, stderr: impl Write + 'static) -> Self {
+        self.stderr = Some(Box::new(stderr));
+        self
+    }
+
+    /// writes the diagnostics enabled by `config` to `sink`
+    pub fn log(mut self, config: LogConfig, sink: impl Write + 'static) -> Self {
+        self.log = Logger::new(config, sink);
+        self
+    }
+
+    /// writes `nisvc.core.N` memory images when the guest starts and when it faults
+    pub fn core_dumps(mut self, enabled: bool) -> Self {
+        self.core_dumps = enabled;
+        self
+    }
+
+    /// directory core dumps are written to, the working directory by default
+    pub fn core_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.core_dir = dir.into();
+        self
+    }
+
+    /// faults instead of returning an errno when a file syscall fails
+    pub fn strict_syscalls(mut self, enabled: bool) -> Self {
+        self.strict_syscalls = enabled;
+        self
+    }
+
+    /// restricts the host paths file syscalls may touch, unrestricted by default
+    pub fn sandbox(mut self, sandbox: Sandbox) -> Self {
+        self.sandbox = sandbox;
+        self
+    }
+
+    /// runs file syscalls against an in-memory filesystem, see [`Kernel::vfs`] to read it back
+    pub fn vfs(mut self, vfs: Vfs) -> Self {
+        self.vfs = Some(vfs);
+        self
+    }
+
+    /// how the frame buffer is shown and recorded, read frames back through [`Kernel::gpu`]
+    pub fn gpu(mut self, config: GpuConfig) -> Self {
+        self.gpu = config;
+        self
+    }
+
+    /// adds or replaces the handler for `int code`
+    pub fn syscall(mut self, code: u8, handler: impl SyscallHandler + 'static) -> Self {
+        self.syscalls.push((code, Box::new(handler)));
+        self
+    }
+
+    /// loads a NISVC-EF executable
+    pub fn load(self, executable: &[u8]) -> Result<Vm, ExecutionError> {
+        let mut kernel = Kernel::new(self.args, self.heap, self.stack, self.clock_speed, self.log);
+        kernel.set_core_dumps(self.core_dumps);
+        kernel.set_core_dir(self.core_dir);
+        kernel.set_strict_syscalls(self.strict_syscalls);
+        kernel.set_sandbox(self.sandbox);
+        kernel.set_gpu_config(self.gpu);
+        if let Some(vfs) = self.vfs {
+            kernel.set_vfs(vfs);
+        }
+        for (code, handler) in self.syscalls {
+            kernel.register_syscall(code, handler);
+        }
+        kernel.set_stdin(self.stdin);
+        let stdout = match self.stdout {
+            Some(stdout) => {
+                kernel.set_stdout(stdout);
+                None
+            }
+            None => {
+                let capture = Capture::default();
+                kernel.set_stdout(capture.clone());
+                Some(capture)
+            }
+        };
+        let stderr = match self.stderr {
+            Some(stderr) => {
+                kernel.set_stderr(stderr);
+                None
+            }
+            None => {
+                let capture = Capture::default();
+                kernel.set_stderr(capture.clone());
+                Some(capture)
+            }
+        };
+        kernel.load_executable(executable.to_vec())?;
+        Ok(Vm {
+            kernel,
+            stdout,
+            stderr,
+            status: None,
+        })
+    }
+}
+
+/// what a guest did during a call to [`Vm::run`] or [`Vm::run_for`]
+#[derive(Debug, Clone, PartialEq)]
+pub struct Outcome {
+    /// exit status, None if the guest is still running
+    pub status: Option<u64>,
+    /// stdout captured since the previous call, empty if stdout was redirected
+    pub stdout: Vec<u8>,
+    /// stderr captured since the previous call, empty if stderr was redirected
+    pub stderr: Vec<u8>,
+}
+
+/// an isolated virtual machine running one NISVC-EF executable
+pub struct Vm {
+    kernel: Kernel,
+    stdout: Option<Capture>,
+    stderr: Option<Capture>,
+    /// set once the guest has stopped
+    status: Option<u64>,
+}
+
+impl Vm {
+    pub fn builder() -> VmBuilder {
+        VmBuilder::default()
+    }
+
+    /// runs until the guest stops
+    pub fn run(&mut self) -> Result<Outcome, ExecutionError> {
+        if self.status.is_none() {
+            self.status = Some(self.kernel.run()?);
+        }
+        Ok(self.outcome())
+    }
+
+    /// executes at most `steps` instructions
+    pub fn run_for(&mut self, steps: usize) -> Result<Outcome, ExecutionError> {
+        for _ in 0..steps {
+            if self.status.is_some() {
+                break;
+            }
+            self.status = self.kernel.step()?;
+            if let Some(status) = self.status {
+                self.kernel.finish(status);
+            }
+        }
+        Ok(self.outcome())
+    }
+
+    pub fn kernel(&mut self) -> &mut Kernel {
+        &mut self.kernel
+    }
+
+    /// takes output captured so far, such as after a run returned an error
+    pub fn take_output(&mut self) -> (Vec<u8>, Vec<u8>) {
+        let take = |capture: &Option<Capture>| capture.as_ref().map(Capture::take);
+        (
+            take(&self.stdout).unwrap_or_default(),
+            take(&self.stderr).unwrap_or_default(),
+        )
+    }
+
+    fn outcome(&mut self) -> Outcome {
+        let (stdout, stderr) = self.take_output();
+        Outcome {
+            status: self.status,
+            stdout,
+            stderr,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        assembler::Assembler,
+        cpu::encode_register,
+        image::ImageFormat,
+        syscall::{Errno, Syscall},
+        tty::READLINE_EOF,
+        vfs::OpenMode,
+        ErrorKind,
+    };
+    use std::path::Path;
+
+    const HELLO: &str = r#"
+        _start:
+                pushi $1
+                pushi $!msg
+                pushi $6
+                int $x02
+                pushi $2
+                pushi $!err
+                pushi $4
+                int $x02
+                pushi $7
+                int $x19
+        msg:    .string "hello\n"
+        err:    .string "oops"
+    "#;
+
+    fn assemble(source: &str) -> Vec<u8> {
+        Assembler::assemble(source).unwrap_or_else(|e| panic!("{}", e.error))
+    }
+
+    /// assembles and runs a guest on a default vm
+    fn run_program(source: &str) -> Result<Outcome, ExecutionError> {
+        Vm::builder().load(&assemble(source)).unwrap().run()
+    }
+
+    /// a fresh directory under the system temp dir, removed again when dropped
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!("nisvc-{name}-{}", std::process::id()));
+            let _ = std::fs::remove_dir_all(&dir);
+            std::fs::create_dir_all(&dir).unwrap();
+            Self(dir)
+        }
+        fn path(&self) -> &Path {
+            &self.0
+        }
+        /// `name` inside the directory as a string for guest sources
+        fn join(&self, name: &str) -> String {
+            self.0.join(name).to_str().unwrap().to_string()
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn hello() -> Vec<u8> {
+        assemble(HELLO)
+    }
+
+    #[test]
+    fn run_captures_output_and_status() {
+        let mut vm = Vm::builder().load(&hello()).unwrap();
+        let outcome = vm.run().unwrap();
+        assert_eq!(outcome.status, Some(7));
+        assert_eq!(outcome.stdout, b"hello\n");
+        assert_eq!(outcome.stderr, b"oops");
+    }
+
+    #[test]
+    fn run_for_stops_between_steps() {
+        let mut vm = Vm::builder().load(&hello()).unwrap();
+        let outcome = vm.run_for(4).unwrap();
+        assert_eq!(outcome.status, None);
+        assert_eq!(outcome.stdout, b"hello\n");
+        assert!(outcome.stderr.is_empty());
+        let outcome = vm.run_for(100).unwrap();
+        assert_eq!(outcome.status, Some(7));
+        assert!(outcome.stdout.is_empty());
+        assert_eq!(outcome.stderr, b"oops");
+    }
+
+    #[test]
+    fn custom_syscalls_are_dispatched() {
+        let program = assemble(
+            "
+                pushi $40
+                pushi $2
+                int $x40
+                int $x19
+            ",
+        );
+        let add = Syscall::new("add", 2, |_: &mut Kernel, args: &[u64]| {
+            Ok(vec![args[0] + args[1]])
+        });
+        let mut vm = Vm::builder().syscall(0x40, add).load(&program).unwrap();
+        assert_eq!(vm.run().unwrap().status, Some(42));
+    }
+
+    #[test]
+    fn unregistered_syscalls_fault() {
+        let mut vm = Vm::builder().load(&hello()).unwrap();
+        vm.kernel().unregister_syscall(0x02);
+        let e = vm.run().unwrap_err();
+        assert_eq!(e.kind, ErrorKind::UnknownSyscall { code: 0x02 });
+    }
+
+    #[test]
+    fn guest_interrupt_handlers_return_with_iret() {
+        let outcome = run_program(
+            r#"
+            _start:
+                    pushi $x40
+                    pushi $!handler
+                    int $x1a
+                    int $x40
+                    pushi $3
+                    int $x19
+            handler:
+                    pushi $1
+                    pushi $!msg
+                    pushi $2
+                    int $x02
+                    pop r5
+                    iret
+            msg:    .string "ok"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(outcome.status, Some(3));
+        assert_eq!(outcome.stdout, b"ok");
+    }
+
+    #[test]
+    fn timer_irqs_wake_a_waiting_guest() {
+        let outcome = run_program(
+            "
+            _start:
+                    pushi $x31
+                    pushi $!tick
+                    int $x1a
+                    pushi $1
+                    int $x1c
+                    pushi $5
+                    pushi $1
+                    int $x1e
+                    ldi r5, $0
+                    ldi r6, $3
+            wait:   int $x20
+                    sub r7, r6, r5
+                    jifnz r7, $!wait
+                    push r5
+                    int $x19
+            tick:   inc r5
+                    iret
+            ",
+        )
+        .unwrap();
+        assert_eq!(outcome.status, Some(3));
+    }
+
+    #[test]
+    fn iret_outside_a_handler_faults() {
+        let e = run_program("iret").unwrap_err();
+        assert_eq!(e.kind, ErrorKind::IllegalInstruction);
+    }
+
+    #[test]
+    fn illegal_instructions_fault_and_dump_core() {
+        let dir = TempDir::new("core");
+        let program = assemble(".bytes $xff");
+        let e = Vm::builder()
+            .core_dumps(true)
+            .core_dir(dir.path())
+            .load(&program)
+            .unwrap()
+            .run()
+            .unwrap_err();
+        assert_eq!(e.kind, ErrorKind::IllegalInstruction);
+        assert_eq!(e.pc, Some(0));
+        // one image when the guest started, one when it faulted
+        assert!(dir.path().join("nisvc.core.1").exists());
+    }
+
+    #[test]
+    fn fault_handlers_can_skip_the_faulting_instruction() {
+        let program = assemble(
+            "
+            _start: pushi $!on_fault
+                    int $x18
+                    ldi r4, $0
+                    ldi r5, $0
+                    .bytes $xff
+                    inc r5
+                    ldi r6, $9
+                    load r7, r6, r6
+                    inc r5
+                    push r5
+                    int $x19
+            on_fault:
+                    pop r1
+                    pop fp
+                    pop r2
+                    pop r3
+                    add r1, r1, r2
+                    add r4, r4, r2
+                    cpy pc, r1
+            ",
+        );
+        let mut vm = Vm::builder().load(&program).unwrap();
+        let outcome = vm.run().unwrap();
+        assert_eq!(outcome.status, Some(2));
+        // an unknown opcode is skipped as one byte, a `load` as its whole encoding
+        let r4 = encode_register("r4").unwrap();
+        assert_eq!(vm.kernel().system.registers.read(r4), 1 + 4);
+    }
+
+    const BREAKPOINTS: &str = "
+        _start: ldi r5, $1
+                .break
+                inc r5
+                breakpoint
+                inc r5
+        last:   inc r5
+                push r5
+                int $x19
+    ";
+
+    /// the log lines of a vm without colors
+    fn log_lines(log: &Capture) -> Vec<String> {
+        let log = String::from_utf8(log.take()).unwrap();
+        let mut plain = String::new();
+        let mut chars = log.chars();
+        while let Some(c) = chars.next() {
+            if c == '\x1b' {
+                chars.by_ref().find(|&c| c == 'm');
+            } else {
+                plain.push(c);
+            }
+        }
+        plain.lines().map(str::to_string).collect()
+    }
+
+    #[test]
+    fn breakpoints_dump_state_and_resume_without_a_debugger() {
+        let dir = TempDir::new("breakpoints");
+        let log = Capture::default();
+        let mut vm = Vm::builder()
+            .log(LogConfig::default(), log.clone())
+            .core_dumps(true)
+            .core_dir(dir.path())
+            .load(&assemble(BREAKPOINTS))
+            .unwrap();
+        vm.kernel().add_breakpoint("last").unwrap();
+        assert_eq!(vm.run().unwrap().status, Some(4));
+        let lines = log_lines(&log);
+        let hits: Vec<&str> = lines
+            .iter()
+            .filter_map(|line| line.strip_prefix("breakpoint @ "))
+            .collect();
+        // the image's `.break`, the opcode and the cli breakpoint on a label, in order
+        assert_eq!(hits, ["0xa", "0xd", "0xf"]);
+        // the registers are dumped at each hit
+        let r5: Vec<&str> = lines
+            .iter()
+            .filter_map(|line| line.strip_prefix("r5f("))
+            .map(|value| value.split(')').next().unwrap())
+            .collect();
+        assert_eq!(r5, ["0x01|1", "0x02|2", "0x03|3"]);
+        // one image when the guest started and one for the first hit only
+        assert!(dir.path().join("nisvc.core.1").exists());
+        assert!(!dir.path().join("nisvc.core.2").exists());
+    }
+
+    #[test]
+    fn breakpoints_on_unknown_labels_are_rejected() {
+        let mut vm = Vm::builder().load(&assemble(BREAKPOINTS)).unwrap();
+        assert!(vm.kernel().add_breakpoint("nowhere").is_err());
+        vm.kernel().add_breakpoint("0xf").unwrap();
+        assert_eq!(vm.run().unwrap().status, Some(4));
+    }
+
+    #[test]
+    fn seek_reports_positions_and_fails_on_streams() {
+        let dir = TempDir::new("seek");
+        let path = dir.join("file");
+        std::fs::write(&path, b"0123456789").unwrap();
+        let outcome = run_program(&format!(
+            r#"
+            _start:
+                    pushi $!path
+                    pushi ${}
+                    int $x01
+                    pop r5
+                    push r5
+                    pushi $-3
+                    pushi $2
+                    int $x04
+                    pop r6
+                    push r5
+                    int $x21
+                    pop r7
+                    pushi $1
+                    pushi $0
+                    pushi $0
+                    int $x04
+                    pop r8
+                    add r6, r6, r7
+                    add r6, r6, r8
+                    push r6
+                    int $x19
+            path:   .string "{path}"
+            "#,
+            path.len()
+        ));
+        // 7 + 7 - ESPIPE
+        assert_eq!(
+            outcome.unwrap().status,
+            Some(14u64.wrapping_add(Errno::SPipe.result()))
+        );
+    }
+
+    #[test]
+    fn open_flags_creates_and_appends() {
+        let dir = TempDir::new("open");
+        let path = dir.join("file");
+        let outcome = run_program(&format!(
+            r#"
+            _start:
+                    ldi r6, $2
+            again:  pushi $!path
+                    pushi ${}
+                    pushi $x16
+                    int $x22
+                    pop r5
+                    push r5
+                    pushi $!msg
+                    pushi $3
+                    int $x02
+                    push r5
+                    int $x05
+                    dec r6
+                    jifnz r6, $!again
+                    pushi $0
+                    int $x19
+            msg:    .string "ab\n"
+            path:   .string "{path}"
+            "#,
+            path.len()
+        ));
+        assert_eq!(outcome.unwrap().status, Some(0));
+        assert_eq!(std::fs::read(&path).unwrap(), b"ab\nab\n");
+    }
+
+    const OPEN_MISSING: &str = r#"
+        _start:
+                pushi $!path
+                pushi $18
+                int $x01
+                int $x19
+        path:   .string "/nonexistent/nisvc"
+    "#;
+
+    #[test]
+    fn failed_syscalls_return_errno() {
+        let outcome = run_program(OPEN_MISSING).unwrap();
+        assert_eq!(outcome.status, Some(Errno::NoEnt.result()));
+    }
+
+    #[test]
+    fn strict_syscalls_fault() {
+        let program = assemble(OPEN_MISSING);
+        let mut vm = Vm::builder().strict_syscalls(true).load(&program).unwrap();
+        let e = vm.run().unwrap_err();
+        assert_eq!(
+            e.kind,
+            ErrorKind::Io {
+                errno: Errno::NoEnt
+            }
+        );
+    }
+
+    #[test]
+    fn strict_syscalls_keep_write_void() {
+        // written against the void write, the exit status is the word pushed first
+        let program = assemble(
+            r#"
+            _start:
+                    pushi $5
+                    pushi $1
+                    pushi $!msg
+                    pushi $2
+                    int $x02
+                    int $x19
+            msg:    .string "ok"
+            "#,
+        );
+        let mut vm = Vm::builder().strict_syscalls(true).load(&program).unwrap();
+        let outcome = vm.run().unwrap();
+        assert_eq!(outcome.status, Some(5));
+        assert_eq!(outcome.stdout, b"ok");
+        let outcome = Vm::builder().load(&program).unwrap().run().unwrap();
+        assert_eq!(outcome.status, Some(2));
+    }
+
+    #[test]
+    fn short_reads_return_the_count() {
+        let program = assemble(
+            "
+            _start:
+                    pushi $0
+                    pushi $!buf
+                    pushi $16
+                    int $x03
+                    pushi $1
+                    pushi $!buf
+                    pushi $3
+                    int $x02
+                    pop r5
+                    int $x19
+            buf:    .string \"................\"
+            ",
+        );
+        let outcome = Vm::builder()
+            .stdin(&b"abc"[..])
+            .load(&program)
+            .unwrap()
+            .run()
+            .unwrap();
+        assert_eq!(outcome.status, Some(3));
+        assert_eq!(outcome.stdout, b"abc");
+    }
+
+    #[test]
+    fn directories_can_be_created_listed_and_removed() {
+        let temp = TempDir::new("fs");
+        let dir = temp.path().to_str().unwrap().to_string();
+        let outcome = run_program(&format!(
+            r#"
+            _start:
+                    pushi $!dir
+                    pushi ${}
+                    int $x2b
+                    pop r5
+                    pushi $!sub
+                    pushi $3
+                    int $x26
+                    pop r5
+                    pushi $!sub
+                    pushi $3
+                    pushi $!stat
+                    int $x23
+                    pop r5
+                    pushi $1
+                    pushi $!stat
+                    pushi $24
+                    int $x02
+                    pop r5
+                    pushi $!sub
+                    pushi $3
+                    pushi $!moved
+                    pushi $5
+                    int $x29
+                    pop r5
+                    pushi $!dot
+                    pushi $1
+                    int $x24
+                    pop r6
+            list:   push r6
+                    pushi $!name
+                    pushi $16
+                    int $x25
+                    pop r7
+                    jifz r7, $!done
+                    pushi $1
+                    pushi $!name
+                    push r7
+                    int $x02
+                    pop r5
+                    jmp $!list
+            done:   push r6
+                    int $x05
+                    pop r5
+                    pushi $!moved
+                    pushi $5
+                    int $x27
+                    pop r5
+                    pushi $!moved
+                    pushi $5
+                    pushi $!stat
+                    int $x23
+                    int $x19
+            dir:    .string "{dir}"
+            sub:    .string "sub"
+            moved:  .string "moved"
+            dot:    .string "."
+            stat:   .string "........................"
+            name:   .string "................"
+            "#,
+            dir.len()
+        ));
+        let outcome = outcome.unwrap();
+        assert_eq!(outcome.status, Some(Errno::NoEnt.result()));
+        let (stat, names) = outcome.stdout.split_at(24);
+        let word = |i: usize| u64::from_le_bytes(stat[i * 8..i * 8 + 8].try_into().unwrap());
+        assert_eq!(word(1), 1);
+        assert_ne!(word(2), 0);
+        assert_eq!(names, b"moved");
+    }
+
+    #[test]
+    fn sandboxed_guests_stay_inside_the_root() {
+        let root = TempDir::new("root");
+        std::fs::write(root.join("inside"), b"ok").unwrap();
+        let program = assemble(
+            r#"
+            _start:
+                    pushi $!inside
+                    pushi $13
+                    int $x01
+                    pop r5
+                    push r5
+                    pushi $!buf
+                    pushi $2
+                    int $x03
+                    pop r6
+                    pushi $1
+                    pushi $!buf
+                    pushi $2
+                    int $x02
+                    pop r6
+                    pushi $!new
+                    pushi $3
+                    pushi $x06
+                    int $x22
+                    int $x19
+            inside: .string "/../../inside"
+            new:    .string "new"
+            buf:    .string ".."
+            "#,
+        );
+        let sandbox = Sandbox::default().root(root.path()).unwrap().readonly(true);
+        let outcome = Vm::builder().sandbox(sandbox).load(&program).unwrap().run();
+        let outcome = outcome.unwrap();
+        assert_eq!(outcome.stdout, b"ok");
+        assert_eq!(outcome.status, Some(Errno::RoFs.result()));
+        assert!(!root.path().join("new").exists());
+    }
+
+    #[test]
+    fn file_syscalls_run_against_the_vfs() {
+        let mut vfs = Vfs::default();
+        let create = OpenMode {
+            write: true,
+            create: true,
+            ..OpenMode::default()
+        };
+        vfs.open(Path::new("/in"), create)
+            .unwrap()
+            .write_all(b"abc")
+            .unwrap();
+        let program = assemble(
+            r#"
+            _start:
+                    pushi $!in
+                    pushi $2
+                    int $x01
+                    pop r5
+                    push r5
+                    pushi $!buf
+                    pushi $8
+                    int $x03
+                    pop r6
+                    pushi $!out
+                    pushi $4
+                    pushi $x06
+                    int $x22
+                    pop r5
+                    push r5
+                    pushi $!buf
+                    push r6
+                    int $x02
+                    pop r6
+                    push r6
+                    int $x19
+            in:     .string "in"
+            out:    .string "/out"
+            buf:    .string "........"
+            "#,
+        );
+        let mut vm = Vm::builder().vfs(vfs).load(&program).unwrap();
+        assert_eq!(vm.run().unwrap().status, Some(3));
+        let out = vm.kernel().vfs().unwrap().read(Path::new("/out"));
+        assert_eq!(out.unwrap(), b"abc");
+        assert!(!Path::new("/out").exists());
+    }
+
+    #[test]
+    fn tty_syscalls_write_escape_sequences() {
+        let outcome = run_program(
+            r#"
+            _start:
+                    pushi $2
+                    pushi $3
+                    int $x09
+                    pop r5
+                    pushi $-1
+                    pushi $0
+                    int $x08
+                    pop r5
+                    pushi $2
+                    pushi $1
+                    int $x2c
+                    pop r5
+                    pushi $9
+                    pushi $0
+                    int $x2c
+                    pop r6
+                    push r6
+                    int $x19
+            "#,
+        )
+        .unwrap();
+        assert_eq!(outcome.status, Some(Errno::Inval.result()));
+        // the color is reset and the cursor shown again at exit
+        assert_eq!(
+            outcome.stdout,
+            b"\x1b[4;3H\x1b[1D\x1b[38;5;1m\x1b[0m\x1b[?25h"
+        );
+    }
+
+    #[test]
+    fn readline_reads_redirected_stdin_by_line() {
+        let program = assemble(
+            r#"
+            _start:
+                    pushi $!prompt
+                    pushi $2
+                    pushi $!buf
+                    pushi $16
+                    int $x30
+                    pop r5
+                    pushi $!prompt
+                    pushi $2
+                    pushi $!buf
+                    pushi $16
+                    int $x30
+                    pop r6
+                    pushi $1
+                    pushi $!buf
+                    push r6
+                    int $x02
+                    pop r8
+                    pushi $!prompt
+                    pushi $2
+                    pushi $!buf
+                    pushi $16
+                    int $x30
+                    pop r7
+                    add r7, r7, r5
+                    add r7, r7, r6
+                    push r7
+                    int $x19
+            prompt: .string "> "
+            buf:    .string "................"
+            "#,
+        );
+        let outcome = Vm::builder()
+            .stdin(&b"first\r\nsecond"[..])
+            .load(&program)
+            .unwrap()
+            .run()
+            .unwrap();
+        assert_eq!(outcome.status, Some(READLINE_EOF.wrapping_add(5 + 6)));
+        assert_eq!(outcome.stdout, b"> > second> ");
+    }
+
+    #[test]
+    fn headless_frames_are_recorded() {
+        let dir = TempDir::new("frames");
+        let program = assemble(
+            r#"
+            _start:
+                    pushi $!fb
+                    pushi $2
+                    pushi $1
+                    pushi $4
+                    int $x0f
+                    int $x10
+                    int $x11
+                    pop r5
+                    ldi r6, $255
+                    ldi r7, $1
+                    store r5, r7, r6
+                    int $x10
+                    int $x10
+                    pushi $0
+                    int $x19
+            fb:     .bytes $1, $2, $3, $4, $5, $6
+            "#,
+        );
+        let config = GpuConfig {
+            backend: GpuBackend::Headless,
+            frames: Some(dir.path().to_path_buf()),
+            frame_every: 2,
+            frame_format: ImageFormat::Ppm,
+        };
+        let mut vm = Vm::builder().gpu(config).load(&program).unwrap();
+        assert_eq!(vm.run().unwrap().status, Some(0));
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 2);
+        let last = std::fs::read(dir.join("frame-000002.ppm")).unwrap();
+        assert_eq!(last, b"P6\n2 1\n255\n\xff\x02\x03\x04\x05\x06");
+        let gpu = vm.kernel().gpu.as_ref().unwrap();
+        assert_eq!(gpu.frames_drawn(), 3);
+        assert_eq!(gpu.frame(), b"\xff\x02\x03\x04\x05\x06");
+    }
+
+    #[test]
+    fn vms_run_in_parallel() {
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                std::thread::spawn(|| {
+                    let mut vm = Vm::builder().load(&hello()).unwrap();
+                    vm.run().unwrap()
+                })
+            })
+            .collect();
+        for thread in threads {
+            let outcome = thread.join().unwrap();
+            assert_eq!(outcome.status, Some(7));
+            assert_eq!(outcome.stdout, b"hello\n");
+        }
+    }
+}
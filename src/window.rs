@@ -0,0 +1,138 @@
+use std::{collections::VecDeque, io::Write};
+
+use sdl2::{
+    pixels::PixelFormatEnum,
+    render::{Canvas, Texture, TextureCreator},
+    video::{Window, WindowContext},
+    EventPump,
+};
+
+use crate::{gpu::Display, logger::Logger, verbose_println, ExecutionError};
+const DEFAULT_WINDOW_NAME: &str = "nisvc-system";
+
+/// shows frames in an SDL window scaled up 4 times, the canvas and event pump keep the
+/// SDL context alive
+pub struct SdlWindow {
+    pub renderer: Canvas<Window>,
+    texture_creator: *mut TextureCreator<WindowContext>,
+    frame_buffer: *mut Texture<'static>,
+    event_pump: EventPump,
+    log: Logger,
+}
+impl SdlWindow {
+    pub fn new(fb_width: u32, fb_height: u32, log: Logger) -> Result<Self, ExecutionError> {
+        let sdl_backend = sdl2::init()
+            .map_err(|e| ExecutionError::new(format!("failed to initialize gpu backend: {e}")))?;
+        let video = sdl_backend
+            .video()
+            .map_err(|e| ExecutionError::new(format!("failed to initialize gpu backend: {e}")))?;
+        let event_pump = sdl_backend
+            .event_pump()
+            .map_err(|e| ExecutionError::new(format!("failed to initialize gpu backend: {e}")))?;
+
+        video.text_input().start();
+
+        let window = video
+            .window(DEFAULT_WINDOW_NAME, fb_width * 4, fb_height * 4)
+            // .input_grabbed()
+            .resizable()
+            .build()
+            .map_err(|e| ExecutionError::new(format!("failed to initialize gpu backend: {e}")))?;
+        let renderer = window
+            .into_canvas()
+            .build()
+            .map_err(|e| ExecutionError::new(format!("failed to initialize gpu backend: {e}")))?;
+        let texture_creator: *mut TextureCreator<WindowContext> =
+            Box::leak(Box::new(renderer.texture_creator()));
+
+        // renderer
+        //     .set_logical_size(300, 300)
+        //     .map_err(|e| ExecutionError::new(format!("failed to initialize gpu backend: {e}")))?;
+        // renderer
+        //     .set_integer_scale(true)
+        //     .map_err(|e| ExecutionError::new(format!("failed to initialize gpu backend: {e}")))?;
+
+        let frame_buffer: *mut Texture = Box::leak(Box::new(
+            unsafe {
+                texture_creator.as_ref().unwrap().create_texture_streaming(
+                    PixelFormatEnum::RGB24,
+                    fb_width,
+                    fb_height,
+                )
+            }
+            .map_err(|e| ExecutionError::new(format!("failed to initialize framebuffer: {e}")))?,
+        ));
+        verbose_println!(log, "initialized gpu");
+        Ok(Self {
+            renderer,
+            texture_creator,
+            frame_buffer,
+            event_pump,
+            log,
+        })
+    }
+}
+impl Display for SdlWindow {
+    fn draw(&mut self, fb: &[u8]) -> Result<(), ExecutionError> {
+        // let factory = self.renderer.texture_creator();
+        unsafe {
+            if let Some(gpu_fb) = self.frame_buffer.as_mut() {
+                gpu_fb
+                    .with_lock(None, |mut frame_buffer, _pitch| {
+                        match frame_buffer.write_all(fb) {
+                            Ok(()) => (),
+                            Err(e) => {
+                                panic!("error while writing to internal framebuffer (sdl2 texture) {e}")
+                            }
+                        }
+                    })
+                    .map_err(|e| {
+                        ExecutionError::new(format!("failed to write to gpu framebuffer: {e}"))
+                    })?;
+                self.renderer.copy(gpu_fb, None, None).map_err(|e| {
+                    ExecutionError::new(format!("failed to write to gpu framebuffer: {e}"))
+                })?;
+            } else {
+                // could replace with .expect() but i might propogate this if i actually ever encounter this error
+                panic!("gpu_frame_buffer dereference was null")
+            }
+        }
+        self.renderer.present();
+        Ok(())
+    }
+    fn poll_events(&mut self, keys: &mut VecDeque<u64>) -> bool {
+        for event in self.event_pump.poll_iter() {
+            match event {
+                sdl2::event::Event::Quit { .. } => return true,
+                sdl2::event::Event::KeyDown {
+                    keycode: Some(keycode),
+                    ..
+                } => keys.push_back(keycode.into_i32() as u64),
+                _ => continue,
+            }
+        }
+        false
+    }
+    // tmp, might be integrated into a real event poller
+    fn quit_loop(&mut self) -> bool {
+        for event in self.event_pump.poll_iter() {
+            match event {
+                sdl2::event::Event::Quit { .. } => {
+                    self.log.print(
+                        "GPU Framebuffer terminated (host window closed by user): shutting down.",
+                    );
+                    return true;
+                }
+                _ => continue,
+            }
+        }
+        false
+    }
+}
+impl Drop for SdlWindow {
+    /// the texture borrows from its creator, so it goes first
+    fn drop(&mut self) {
+        drop(unsafe { Box::from_raw(self.frame_buffer) });
+        drop(unsafe { Box::from_raw(self.texture_creator) });
+    }
+}